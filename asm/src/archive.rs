@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::object::Object;
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, formats::Segment, linker::Linker, parse::InstructionParser};
+
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Static library made by `asm ar`, stored as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    /// Global symbol -> index of member defining it
+    pub index: BTreeMap<String, usize>,
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub name: String,
+    pub object: Object,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveError {
+    /// Symbol, member defining it first, member defining it again
+    DuplicateSymbol(String, String, String),
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Self {
+            version: ARCHIVE_VERSION,
+            index: BTreeMap::new(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, object: Object) -> Result<(), ArchiveError> {
+        let id = self.members.len();
+        for symbol in object.globals() {
+            if let Some(prev) = self.index.get(&symbol.name) {
+                return Err(ArchiveError::DuplicateSymbol(
+                    symbol.name.clone(),
+                    self.members[*prev].name.clone(),
+                    name.into(),
+                ));
+            }
+        }
+        for symbol in object.globals() {
+            self.index.insert(symbol.name.clone(), id);
+        }
+        self.members.push(Member {
+            name: name.into(),
+            object,
+        });
+        Ok(())
+    }
+
+    /// Member exporting `symbol`
+    pub fn member(&self, symbol: &str) -> Option<(usize, &Member)> {
+        self.index.get(symbol).map(|id| (*id, &self.members[*id]))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("archive is always serializable")
+    }
+
+    pub fn from_json(input: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(input)
+    }
+}
+
+#[cfg(test)]
+fn compile(file: &str, input: &str) -> Object {
+    let parsed = InstructionParser::new().parse_lines(input).unwrap();
+    CodeGen::new().compile(file, &parsed).unwrap()
+}
+
+#[test]
+fn archive_members() {
+    let mut archive = Archive::new();
+    archive
+        .add(
+            "math.o",
+            compile(
+                "math.s",
+                "global double
+extern clear
+double:
+    add r1, r1
+    call $clear
+    ret",
+            ),
+        )
+        .unwrap();
+    archive
+        .add(
+            "screen.o",
+            compile("screen.s", "global clear\nclear:\n    ret"),
+        )
+        .unwrap();
+    archive
+        .add(
+            "unused.o",
+            compile("unused.s", "global unused\nunused:\n    hlt"),
+        )
+        .unwrap();
+    assert_eq!(
+        archive.add(
+            "again.o",
+            compile("again.s", "global clear\nclear:\n    ret")
+        ),
+        Err(ArchiveError::DuplicateSymbol(
+            "clear".into(),
+            "screen.o".into(),
+            "again.o".into()
+        ))
+    );
+    assert_eq!(archive.member("clear").map(|(id, _)| id), Some(1));
+    let archive = Archive::from_json(&archive.to_json()).unwrap();
+
+    let mut linker = Linker::new();
+    linker.add_archive(archive);
+    linker.add_object(compile(
+        "main.s",
+        "extern double
+    call $double
+    hlt",
+    ));
+    let program = linker.link().unwrap();
+
+    // double pulls in clear, unused stays out
+    let files: Vec<_> = program
+        .debug_info
+        .files
+        .iter()
+        .map(|x| x.path.as_str())
+        .collect();
+    assert_eq!(files, vec!["main.s", "math.s", "screen.s"]);
+    assert_eq!(
+        program.segments,
+        vec![
+            Segment::new(0x0000, vec![0x5Eu8, 0x00u8, 0x04u8, 0xFFu8]),
+            Segment::new(
+                0x0004,
+                vec![0x14u8, 0x02u8, 0x02u8, 0x5Eu8, 0x00u8, 0x0Bu8, 0x60u8]
+            ),
+            Segment::new(0x000B, vec![0x60u8]),
+        ]
+    );
+}
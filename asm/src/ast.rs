@@ -20,13 +20,25 @@ pub enum ExprKind {
     JmpNotEQ,
     JmpGT,
     JmpLT,
+    JmpLE,
+    JmpGE,
     Push,
     Pop,
     Call,
     Ret,
+    Int,
+    Rti,
     HLT,
 
     Label,
+    Org,
+    Constant,
+    Data8,
+    Data16,
+    Structure,
+    Section,
+    Global,
+    Extern,
     // // XXX: maybe move to typechecker
     // WrongExpr(String, u32),
     // UnknownExpr(String, u32),
@@ -40,6 +52,8 @@ pub enum ExprArgs {
     Complex(S, TokenEnum),
     Double(TokenEnum, TokenEnum),
     Triple(TokenEnum, TokenEnum, TokenEnum),
+    List(TokenEnum, Vec<TokenEnum>),
+    Fields(TokenEnum, Vec<(TokenEnum, TokenEnum)>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -1,36 +1,71 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::{
-    ast::{Expr, ExprArgs, ExprKind},
-    common::{parse_u16, TokenEnum},
+    ast::{Expr, ExprArgs, ExprKind, S},
+    common::{apply_op, parse_u16, Regs, TokenEnum},
+    debuginfo::{DebugInfo, Field, LineEntry, Structure, SymbolKind},
+    formats::{self, Segment},
     instructions::Instructions,
-    parse::{InstructionParser, ParserHelper},
+    linker::{LinkError, Linker},
+    object::{Extern, Object, ObjectSymbol, Relocation, Section, Target},
+    parse::ParserHelper,
+};
+use vm::{
+    bank::{BANK_SELECT, BANK_START},
+    interrupt::INTERRUPT_START,
+    keyboard::KEYBOARD_START,
+    screen::SCREEN_START,
+    timer::TIMER_START,
+    timing::CYCLES_START,
 };
 
+#[cfg(test)]
+use crate::parse::InstructionParser;
+
+/// Device registers of the machine `emu` wires up, usable by name unless
+/// the program defines the name itself
+pub const DEVICE_CONSTANTS: &[(&str, u16)] = &[
+    ("SCREEN", SCREEN_START),
+    ("CYCLES", CYCLES_START),
+    ("INTERRUPT_MASK", INTERRUPT_START),
+    ("INTERRUPT_VECTORS", INTERRUPT_START + 2),
+    ("INTERRUPT_PENDING", INTERRUPT_START + 4),
+    ("BANK_SELECT", BANK_SELECT),
+    ("BANK_WINDOW", BANK_START),
+    ("KEYBOARD_STATUS", KEYBOARD_START),
+    ("KEYBOARD_DATA", KEYBOARD_START + 2),
+    ("TIMER_CONTROL", TIMER_START),
+    ("TIMER_RELOAD", TIMER_START + 2),
+    ("TIMER_COUNTER", TIMER_START + 4),
+    ("TIMER_STATUS", TIMER_START + 6),
+];
+
 macro_rules! gen_hand {
     (ref $i:ident) => {
         TokenEnum::Ref($i)
     };
     (mem $i:ident) => {
-        TokenEnum::Mem($i)
+        $i @ (TokenEnum::Mem(_) | TokenEnum::MemSym(_))
     };
     (reg $i:ident) => {
         TokenEnum::Ident($i)
     };
     (lit $i:ident) => {
-        TokenEnum::Lit($i)
+        $i @ (TokenEnum::Lit(_) | TokenEnum::LitSym(_))
     };
 }
 
 macro_rules! gen_body {
-    (reg $r:ident $res:ident) => {
-        let $r = ParserHelper::parse_reg($r).unwrap();
+    ($s:ident reg $r:ident $res:ident) => {
+        let $r = $s.reg($r)?;
         $res.push($r as u8);
     };
-    (ref $($tail:tt)*) => {gen_body!(reg $($tail)*)};
-    (mem $($tail:tt)*) => {gen_body!(u16 $($tail)*)};
-    (lit $($tail:tt)*) => {gen_body!(u16 $($tail)*)};
-    (u16 $i:ident $res:ident) => {
+    ($s:ident ref $($tail:tt)*) => {gen_body!($s reg $($tail)*)};
+    ($s:ident mem $($tail:tt)*) => {gen_body!($s u16 $($tail)*)};
+    ($s:ident lit $($tail:tt)*) => {gen_body!($s u16 $($tail)*)};
+    ($s:ident u16 $i:ident $res:ident) => {
         {
-            let (h, l) = parse_u16($i);
+            let (h, l) = parse_u16(&$s.operand($i, $res.len())?);
             $res.push(h);
             $res.push(l);
         }
@@ -39,7 +74,7 @@ macro_rules! gen_body {
 
 macro_rules! gen_patt {
     (
-        $args:ident:
+        $s:ident, $args:ident:
         $(1 $i1:ident($arg11:ident);)*
         $(2 $i2:ident($arg21:ident, $arg22:ident);)*
         $(3 $i3:ident($arg31:ident, $arg32:ident, $arg33:ident);)*
@@ -48,49 +83,213 @@ macro_rules! gen_patt {
             $(
                 ExprArgs::Single(gen_hand!($arg11 arg)) => {
                     let mut res = vec![Instructions::$i1 as u8];
-                    gen_body!($arg11 arg res);
-                    res
+                    gen_body!($s $arg11 arg res);
+                    Ok(res)
                 }
             )*
             $(
                 ExprArgs::Double(gen_hand!($arg21 arg1), gen_hand!($arg22 arg2)) => {
                     let mut res = vec![Instructions::$i2 as u8];
-                    gen_body!($arg21 arg1 res);
-                    gen_body!($arg22 arg2 res);
-                    res
+                    gen_body!($s $arg21 arg1 res);
+                    gen_body!($s $arg22 arg2 res);
+                    Ok(res)
                 }
             )*
             $(
                 ExprArgs::Triple(gen_hand!($arg31 arg1), gen_hand!($arg32 arg2), gen_hand!($arg33 arg3)) => {
                     let mut res = vec![Instructions::$i3 as u8];
-                    gen_body!($arg31 arg1 res);
-                    gen_body!($arg32 arg2 res);
-                    gen_body!($arg33 arg3 res);
-                    res
+                    gen_body!($s $arg31 arg1 res);
+                    gen_body!($s $arg32 arg2 res);
+                    gen_body!($s $arg33 arg3 res);
+                    Ok(res)
                 }
             )*
-            _ => panic!("{:?} not implemented", $args),
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", $args), $s.line)),
         }
     };
 }
 
-#[derive(Debug)]
-struct CodeGen;
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodeGenError {
+    UndefinedSymbol(String, u32),
+    DuplicateSymbol(String, u32),
+    InvalidArgs(String, u32),
+    FailedToParseReg(String, u32),
+    Unimplemented(String, u32),
+    /// Relocatable symbol used where the value has to be known right away
+    NotAbsolute(String, u32),
+    Link(Vec<LinkError>),
+}
+
+type CodeGenRes<T> = Result<T, CodeGenError>;
+
+/// Assembled segments with their debug sidecar
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub debug_info: DebugInfo,
+}
+
+/// Operand value, `target` is added by the linker
+#[derive(Debug, Clone, PartialEq)]
+struct Value {
+    target: Option<Target>,
+    value: u16,
+}
+
+impl Value {
+    fn absolute(value: u16) -> Self {
+        Self {
+            target: None,
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CodeGen {
+    // name -> (section, value), constants have no section
+    symbols: HashMap<String, (Option<usize>, u16)>,
+    globals: HashMap<String, u32>,
+    externs: HashSet<String>,
+    // section addresses known after the first pass
+    layout: Vec<Option<u16>>,
+    object: Object,
+    section: usize,
+    addr: u16,
+    line: u32,
+    // second pass, every symbol has to be known by now
+    resolve: bool,
+}
 
 impl CodeGen {
-    fn new() -> Self {
-        CodeGen
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn generate(&mut self, input: &[Expr]) -> Vec<u8> {
+        let lines: Vec<_> = input.iter().cloned().map(|expr| (0, expr)).collect();
+        formats::flatten(&self.assemble("", &lines).unwrap().segments)
     }
 
-    pub fn generate(&mut self, input: &Vec<Expr>) -> Vec<u8> {
-        input
-            .iter()
-            .map(|expr| self.gen_expr(expr))
-            .flatten()
-            .collect()
+    /// Compiles and links single file on its own
+    pub fn assemble(&mut self, file: &str, input: &[(u32, Expr)]) -> CodeGenRes<Program> {
+        let mut linker = Linker::new();
+        linker.add_object(self.compile(file, input)?);
+        linker.link().map_err(CodeGenError::Link)
+    }
+
+    /// Two passes: first one collects symbols, second one emits code with them resolved
+    pub fn compile(&mut self, file: &str, input: &[(u32, Expr)]) -> CodeGenRes<Object> {
+        self.symbols.clear();
+        self.globals.clear();
+        self.externs.clear();
+        self.layout.clear();
+        self.resolve = false;
+        self.gen_pass(file, input)?;
+
+        self.layout = self.object.sections.iter().map(|x| x.address).collect();
+        self.resolve = true;
+        self.gen_pass(file, input)?;
+
+        for symbol in self.object.symbols.iter_mut() {
+            symbol.global = self.globals.contains_key(&symbol.name);
+        }
+        for (name, line) in &self.globals {
+            if !self.symbols.contains_key(name) {
+                return Err(CodeGenError::UndefinedSymbol(name.clone(), *line));
+            }
+        }
+        Ok(std::mem::take(&mut self.object))
     }
 
-    pub fn gen_expr(&mut self, expr: &Expr) -> Vec<u8> {
+    fn gen_pass(&mut self, file: &str, input: &[(u32, Expr)]) -> CodeGenRes<()> {
+        self.object = Object::new(file);
+        self.object.sections.push(Section::new("code", None));
+        self.section = 0;
+        self.addr = 0;
+        for (line, expr) in input {
+            self.line = *line;
+            match expr.kind {
+                ExprKind::Org => {
+                    let address = self.gen_org(&expr.args)?;
+                    let name = self.object.sections[self.section].name.clone();
+                    self.enter_section(&name, Some(address));
+                }
+                ExprKind::Section => {
+                    let name = self.name(&expr.args)?;
+                    self.enter_section(&name, None);
+                }
+                ExprKind::Global => {
+                    let name = self.name(&expr.args)?;
+                    self.globals.entry(name).or_insert(*line);
+                }
+                ExprKind::Extern => self.gen_extern(&expr.args)?,
+                _ => {
+                    let bytes = self.gen_expr(expr)?;
+                    let section = &mut self.object.sections[self.section];
+                    if !bytes.is_empty() {
+                        section.lines.push(LineEntry {
+                            address: self.addr,
+                            size: bytes.len() as u16,
+                            file: 0,
+                            line: *line,
+                        });
+                    }
+                    self.addr = self.addr.wrapping_add(bytes.len() as u16);
+                    section.bytes.extend(bytes);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `org` always opens new section, `section` continues the last one with that name
+    fn enter_section(&mut self, name: &str, address: Option<u16>) {
+        let sections = &mut self.object.sections;
+        let found = match address {
+            Some(_) => None,
+            None => sections.iter().rposition(|x| x.name == name),
+        };
+        self.section = found.unwrap_or_else(|| {
+            sections.push(Section::new(name, address));
+            sections.len() - 1
+        });
+        self.addr = sections[self.section].bytes.len() as u16;
+    }
+
+    fn gen_org(&self, args: &ExprArgs) -> CodeGenRes<u16> {
+        match args {
+            ExprArgs::Single(value @ (TokenEnum::Lit(_) | TokenEnum::LitSym(_))) => {
+                self.absolute(value)
+            }
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        }
+    }
+
+    fn gen_extern(&mut self, args: &ExprArgs) -> CodeGenRes<()> {
+        let name = self.name(args)?;
+        if self.symbols.contains_key(&name) {
+            return Err(CodeGenError::DuplicateSymbol(name, self.line));
+        }
+        if self.resolve && !self.object.externs.iter().any(|x| x.name == name) {
+            self.object.externs.push(Extern {
+                name: name.clone(),
+                line: self.line,
+            });
+        }
+        self.externs.insert(name);
+        Ok(())
+    }
+
+    fn name(&self, args: &ExprArgs) -> CodeGenRes<String> {
+        match args {
+            ExprArgs::Single(TokenEnum::Ident(name)) => Ok(name.clone()),
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        }
+    }
+
+    pub fn gen_expr(&mut self, expr: &Expr) -> CodeGenRes<Vec<u8>> {
         match expr.kind {
             ExprKind::Mov => self.gen_mov(&expr.args),
             ExprKind::Add => self.gen_add(&expr.args),
@@ -104,23 +303,266 @@ impl CodeGen {
             ExprKind::And => self.gen_and(&expr.args),
             ExprKind::Or => self.gen_or(&expr.args),
             ExprKind::Xor => self.gen_xor(&expr.args),
+            ExprKind::Not => self.gen_not(&expr.args),
             ExprKind::JmpEQ => self.gen_jmp_eq(&expr.args),
             ExprKind::JmpGT => self.gen_jmp_gt(&expr.args),
             ExprKind::JmpNotEQ => self.gen_jmp_not_eq(&expr.args),
             ExprKind::JmpLT => self.gen_jmp_lt(&expr.args),
+            ExprKind::JmpLE => self.gen_jmp_le(&expr.args),
+            ExprKind::JmpGE => self.gen_jmp_ge(&expr.args),
 
             ExprKind::Push => self.gen_push(&expr.args),
             ExprKind::Pop => self.gen_pop(&expr.args),
-            ExprKind::HLT => vec![Instructions::HLT as u8],
+            ExprKind::HLT => Ok(vec![Instructions::HLT as u8]),
             ExprKind::Call => self.gen_call(&expr.args),
-            ExprKind::Ret => vec![Instructions::RET as u8],
-            _ => unimplemented!("Add more instructions!"),
+            ExprKind::Ret => Ok(vec![Instructions::RET as u8]),
+            ExprKind::Int => self.gen_int(&expr.args),
+            ExprKind::Rti => Ok(vec![Instructions::RET_INT as u8]),
+
+            ExprKind::Label => self.gen_label(&expr.args),
+            ExprKind::Constant => self.gen_constant(&expr.args),
+            ExprKind::Data8 => self.gen_data(&expr.args, 1),
+            ExprKind::Data16 => self.gen_data(&expr.args, 2),
+            ExprKind::Structure => self.gen_structure(&expr.args),
+            _ => Err(CodeGenError::Unimplemented(
+                format!("{:?}", expr.kind),
+                self.line,
+            )),
         }
     }
 
-    pub fn gen_mov(&mut self, args: &ExprArgs) -> Vec<u8> {
+    fn define(&mut self, name: &str, section: Option<usize>, value: u16) -> CodeGenRes<()> {
+        let prev = self.symbols.insert(name.into(), (section, value));
+        if (prev.is_some() && !self.resolve) || self.externs.contains(name) {
+            return Err(CodeGenError::DuplicateSymbol(name.into(), self.line));
+        }
+        Ok(())
+    }
+
+    fn add_symbol(
+        &mut self,
+        name: &str,
+        kind: SymbolKind,
+        section: Option<usize>,
+        value: u16,
+        size: u16,
+    ) -> CodeGenRes<()> {
+        self.define(name, section, value)?;
+        self.object.symbols.push(ObjectSymbol {
+            name: name.into(),
+            kind,
+            section,
+            value,
+            size,
+            global: false,
+            line: self.line,
+        });
+        Ok(())
+    }
+
+    fn symbol(&self, name: &str) -> CodeGenRes<Value> {
+        match self.symbols.get(name) {
+            Some((None, value)) => Ok(Value::absolute(*value)),
+            Some((Some(section), value)) => match self.layout.get(*section) {
+                Some(Some(base)) => Ok(Value::absolute(base.wrapping_add(*value))),
+                _ if !self.resolve => Ok(Value::absolute(*value)),
+                _ => Ok(Value {
+                    target: Some(Target::Section(*section)),
+                    value: *value,
+                }),
+            },
+            None if self.externs.contains(name) => Ok(Value {
+                target: Some(Target::Symbol(name.into())),
+                value: 0,
+            }),
+            None => match DEVICE_CONSTANTS.iter().find(|x| x.0 == name) {
+                Some((_, value)) => Ok(Value::absolute(*value)),
+                None if !self.resolve => Ok(Value::absolute(0)),
+                None => Err(CodeGenError::UndefinedSymbol(name.into(), self.line)),
+            },
+        }
+    }
+
+    fn reg(&self, name: &str) -> CodeGenRes<Regs> {
+        ParserHelper::parse_reg(name)
+            .map_err(|_| CodeGenError::FailedToParseReg(name.into(), self.line))
+    }
+
+    fn value(&self, token: &TokenEnum) -> CodeGenRes<Value> {
+        match token {
+            TokenEnum::Lit(x) | TokenEnum::Mem(x) => Ok(Value::absolute(*x)),
+            TokenEnum::LitSym(name) | TokenEnum::MemSym(name) | TokenEnum::Ident(name) => {
+                self.symbol(name)
+            }
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", token), self.line)),
+        }
+    }
+
+    /// Value that can not be left to the linker
+    fn absolute(&self, token: &TokenEnum) -> CodeGenRes<u16> {
+        match self.value(token)? {
+            Value {
+                target: None,
+                value,
+            } => Ok(value),
+            _ => Err(CodeGenError::NotAbsolute(token.to_string(), self.line)),
+        }
+    }
+
+    /// 16-bit operand written `pos` bytes into the current instruction
+    fn operand(&mut self, token: &TokenEnum, pos: usize) -> CodeGenRes<u16> {
+        let value = self.value(token)?;
+        Ok(self.relocate(value, pos))
+    }
+
+    fn relocate(&mut self, value: Value, pos: usize) -> u16 {
+        match value.target {
+            Some(target) => {
+                let offset = self.addr.wrapping_add(pos as u16);
+                self.object.sections[self.section]
+                    .relocations
+                    .push(Relocation {
+                        offset,
+                        target,
+                        addend: value.value,
+                        line: self.line,
+                    });
+                0
+            }
+            None => value.value,
+        }
+    }
+
+    fn eval(&self, expr: &S) -> CodeGenRes<Value> {
+        match expr {
+            S::Atom(token) => self.value(token),
+            S::Cons(op, args) => {
+                let args = args
+                    .iter()
+                    .map(|x| self.eval(x))
+                    .collect::<CodeGenRes<Vec<_>>>()?;
+                let not_absolute = || CodeGenError::NotAbsolute(expr.to_string(), self.line);
+                // relocatable value may only be moved by an absolute one
+                match (op, args.as_slice()) {
+                    (TokenEnum::Plus, [lhs, rhs]) => match (&lhs.target, &rhs.target) {
+                        (Some(_), Some(_)) => Err(not_absolute()),
+                        (target, None) | (None, target) => Ok(Value {
+                            target: target.clone(),
+                            value: lhs.value.wrapping_add(rhs.value),
+                        }),
+                    },
+                    (TokenEnum::Minus, [lhs, rhs]) if rhs.target.is_none() => Ok(Value {
+                        target: lhs.target.clone(),
+                        value: lhs.value.wrapping_sub(rhs.value),
+                    }),
+                    (TokenEnum::Plus, [val]) => Ok(val.clone()),
+                    _ if args.iter().any(|x| x.target.is_some()) => Err(not_absolute()),
+                    _ => {
+                        let values: Vec<u16> = args.iter().map(|x| x.value).collect();
+                        apply_op(op, &values)
+                            .map(Value::absolute)
+                            .ok_or_else(|| CodeGenError::InvalidArgs(expr.to_string(), self.line))
+                    }
+                }
+            }
+        }
+    }
+
+    fn gen_label(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        match args {
+            ExprArgs::Single(TokenEnum::Ident(name)) => {
+                self.add_symbol(name, SymbolKind::Label, Some(self.section), self.addr, 0)?;
+                Ok(vec![])
+            }
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        }
+    }
+
+    fn gen_constant(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        match args {
+            ExprArgs::Double(TokenEnum::Ident(name), value) => {
+                let value = self.absolute(value)?;
+                self.add_symbol(name, SymbolKind::Constant, None, value, 0)?;
+                Ok(vec![])
+            }
+            _ => Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        }
+    }
+
+    fn gen_data(&mut self, args: &ExprArgs, width: u16) -> CodeGenRes<Vec<u8>> {
+        let (name, values) = match args {
+            ExprArgs::List(TokenEnum::Ident(name), values) => (name, values),
+            _ => return Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        };
+
+        let mut res = Vec::new();
+        for value in values {
+            if width == 1 {
+                let value = self.absolute(value)?;
+                let byte = u8::try_from(value).map_err(|_| {
+                    CodeGenError::InvalidArgs(
+                        format!("{:#06x} does not fit in byte", value),
+                        self.line,
+                    )
+                })?;
+                res.push(byte);
+            } else {
+                let (h, l) = parse_u16(&self.operand(value, res.len())?);
+                res.push(h);
+                res.push(l);
+            }
+        }
+
+        let size = res.len() as u16;
+        self.add_symbol(name, SymbolKind::Data, Some(self.section), self.addr, size)?;
+        Ok(res)
+    }
+
+    fn gen_structure(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        let (name, fields) = match args {
+            ExprArgs::Fields(TokenEnum::Ident(name), fields) => (name, fields),
+            _ => return Err(CodeGenError::InvalidArgs(format!("{:?}", args), self.line)),
+        };
+
+        let mut offset = 0u16;
+        let mut layout = Vec::new();
+        for (field, size) in fields {
+            let field = match field {
+                TokenEnum::Ident(field) => field,
+                _ => return Err(CodeGenError::InvalidArgs(format!("{:?}", field), self.line)),
+            };
+            let size = self.absolute(size)?;
+            self.define(&format!("{}.{}", name, field), None, offset)?;
+            layout.push(Field {
+                name: field.clone(),
+                offset,
+                size,
+            });
+            offset = offset.wrapping_add(size);
+        }
+        // structure name itself is its size
+        self.define(name, None, offset)?;
+
+        self.object.structures.push(Structure {
+            name: name.clone(),
+            size: offset,
+            fields: layout,
+            file: 0,
+            line: self.line,
+        });
+        Ok(vec![])
+    }
+
+    pub fn gen_mov(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        if let ExprArgs::Complex(expr, rhs) = args {
+            // literal is the first operand of both MOV_LIT_REG and MOV_LIT_MEM
+            let value = self.eval(expr)?;
+            let lit = TokenEnum::Lit(self.relocate(value, 1));
+            return self.gen_mov(&ExprArgs::Double(lit, rhs.clone()));
+        }
+
         gen_patt!(
-            args:
+            self, args:
             2 MOV_LIT_REG(lit, reg);
             2 MOV_REG_REG(reg, reg);
             2 MOV_REG_MEM(reg, mem);
@@ -131,136 +573,165 @@ impl CodeGen {
         )
     }
 
-    fn gen_add(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_add(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 ADD_REG_REG(reg, reg);
             2 ADD_LIT_REG(lit, reg);
         )
     }
 
-    fn gen_sub(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_sub(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 SUB_REG_REG(reg, reg);
             2 SUB_LIT_REG(lit, reg);
             2 SUB_REG_LIT(reg, lit);
         )
     }
 
-    fn gen_mul(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_mul(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 MUL_REG_REG(reg, reg);
             2 MUL_LIT_REG(lit, reg);
         )
     }
 
-    fn gen_push(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_push(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             1 PSH_REG(reg);
             1 PSH_LIT(lit);
         )
     }
 
-    fn gen_pop(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_pop(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             1 POP(reg);
         )
     }
 
-    fn gen_call(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_call(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             1 CALL_REG(reg);
             1 CALL_LIT(lit);
         )
     }
 
-    fn gen_inc(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_int(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        gen_patt!(
+            self, args:
+            1 INT_LIT(lit);
+        )
+    }
+
+    fn gen_inc(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             1 INC_REG(reg);
         )
     }
 
-    fn gen_dec(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_dec(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             1 DEC_REG(reg);
         )
     }
 
-    fn gen_and(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_and(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 AND_REG_REG(reg, reg);
             2 AND_REG_LIT(reg, lit);
         )
     }
 
-    fn gen_or(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_or(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 OR_REG_REG(reg, reg);
             2 OR_REG_LIT(reg, lit);
         )
     }
 
-    fn gen_xor(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_xor(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 XOR_REG_REG(reg, reg);
             2 XOR_REG_LIT(reg, lit);
         )
     }
 
-    fn gen_jmp_eq(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_jmp_eq(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
-            1 JEQ_REG(reg);
-            1 JEQ_LIT(lit);
+            self, args:
+            2 JEQ_REG(reg, mem);
+            2 JEQ_LIT(lit, mem);
         )
     }
 
-    fn gen_jmp_gt(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_jmp_gt(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
-            1 JGT_REG(reg);
-            1 JGT_LIT(lit);
+            self, args:
+            2 JGT_REG(reg, mem);
+            2 JGT_LIT(lit, mem);
         )
     }
 
-    fn gen_jmp_not_eq(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_jmp_not_eq(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
-            1 JNE_REG(reg);
-            // XXX: fuked up
-            // 1 JNE_LIT(lit);
+            self, args:
+            2 JNE_REG(reg, mem);
+            2 JMP_NOT_EQ(lit, mem);
         )
     }
 
-    fn gen_jmp_lt(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_jmp_lt(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
-            1 JLT_REG(reg);
-            1 JLT_LIT(lit);
+            self, args:
+            2 JLT_REG(reg, mem);
+            2 JLT_LIT(lit, mem);
         )
     }
 
-    fn gen_left_shift(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_jmp_le(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
+            2 JLE_REG(reg, mem);
+            2 JLE_LIT(lit, mem);
+        )
+    }
+
+    fn gen_jmp_ge(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        gen_patt!(
+            self, args:
+            2 JGE_REG(reg, mem);
+            2 JGE_LIT(lit, mem);
+        )
+    }
+
+    fn gen_not(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        gen_patt!(
+            self, args:
+            1 NOT(reg);
+        )
+    }
+
+    fn gen_left_shift(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
+        gen_patt!(
+            self, args:
             2 LSF_REG_LIT(reg, lit);
             2 LSF_REG_REG(reg, reg);
         )
     }
 
-    fn gen_rigth_shift(&self, args: &ExprArgs) -> Vec<u8> {
+    fn gen_rigth_shift(&mut self, args: &ExprArgs) -> CodeGenRes<Vec<u8>> {
         gen_patt!(
-            args:
+            self, args:
             2 RSF_REG_LIT(reg, lit);
             2 RSF_REG_REG(reg, reg);
         )
@@ -378,6 +849,20 @@ fn codegen_subroutine() {
     assert_eq!(generated, vec![0x60u8]);
 }
 
+#[test]
+fn codegen_interrupts() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser.parse("int $3").unwrap();
+    let generated = codegen.generate(&parsed);
+    assert_eq!(generated, vec![0xFDu8, 0x00u8, 0x03u8]);
+
+    let parsed = parser.parse("rti").unwrap();
+    let generated = codegen.generate(&parsed);
+    assert_eq!(generated, vec![0xFCu8]);
+}
+
 #[test]
 fn codegen_hlt() {
     let mut parser = InstructionParser::new();
@@ -401,3 +886,248 @@ fn codegen_inc_dec() {
     let generated = codegen.generate(&parsed);
     assert_eq!(generated, vec![0x36u8, 0x02u8]);
 }
+
+#[test]
+fn codegen_device_constants() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser.parse("mov $2, &BANK_SELECT").unwrap();
+    let generated = codegen.generate(&parsed);
+    assert_eq!(generated, vec![0x1Bu8, 0x00u8, 0x02u8, 0x31u8, 0x0Au8]);
+
+    // programs may reuse the names for their own symbols
+    let parsed = parser
+        .parse_lines("constant SCREEN = $0x10\nmov $SCREEN, r1")
+        .unwrap();
+    let program = codegen.assemble("main.s", &parsed).unwrap();
+    assert_eq!(
+        formats::flatten(&program.segments),
+        vec![0x10u8, 0x00u8, 0x10u8, 0x02u8]
+    );
+}
+
+#[test]
+fn codegen_symbols() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser
+        .parse_lines(
+            "constant screen = $0x3000
+structure Point {
+    x: $2,
+    y: $2
+}
+start:
+    mov $screen, r1
+    mov $Point.y, r2
+    call $done
+done:
+    mov &value, acc
+    hlt
+data16 value = { $0x1234 }",
+        )
+        .unwrap();
+    let program = codegen.assemble("main.s", &parsed).unwrap();
+    assert_eq!(
+        formats::flatten(&program.segments),
+        vec![
+            0x10u8, 0x30u8, 0x00u8, 0x02u8, 0x10u8, 0x00u8, 0x02u8, 0x03u8, 0x5Eu8, 0x00u8, 0x0Bu8,
+            0x13u8, 0x00u8, 0x10u8, 0x01u8, 0xFFu8, 0x12u8, 0x34u8
+        ]
+    );
+
+    let info = program.debug_info;
+    assert_eq!(info.file_path(0), Some("main.s"));
+
+    let symbols: Vec<_> = info
+        .symbols
+        .iter()
+        .map(|x| (x.name.as_str(), x.kind, x.value, x.size, x.line))
+        .collect();
+    assert_eq!(
+        symbols,
+        vec![
+            ("screen", SymbolKind::Constant, 0x3000, 0, 1),
+            ("start", SymbolKind::Label, 0x0000, 0, 6),
+            ("done", SymbolKind::Label, 0x000B, 0, 10),
+            ("value", SymbolKind::Data, 0x0010, 2, 13),
+        ]
+    );
+
+    assert_eq!(
+        info.structures,
+        vec![Structure {
+            name: "Point".into(),
+            size: 4,
+            fields: vec![
+                Field {
+                    name: "x".into(),
+                    offset: 0,
+                    size: 2
+                },
+                Field {
+                    name: "y".into(),
+                    offset: 2,
+                    size: 2
+                },
+            ],
+            file: 0,
+            line: 2,
+        }]
+    );
+
+    let lines: Vec<_> = info
+        .lines
+        .iter()
+        .map(|x| (x.address, x.size, x.line))
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            (0x0000, 4, 7),
+            (0x0004, 4, 8),
+            (0x0008, 3, 9),
+            (0x000B, 4, 11),
+            (0x000F, 1, 12),
+            (0x0010, 2, 13)
+        ]
+    );
+}
+
+#[test]
+fn codegen_symbol_errors() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser.parse_lines("call $nowhere").unwrap();
+    assert_eq!(
+        codegen.assemble("main.s", &parsed),
+        Err(CodeGenError::UndefinedSymbol("nowhere".into(), 1))
+    );
+
+    let parsed = parser.parse_lines("a:\nhlt\na:").unwrap();
+    assert_eq!(
+        codegen.assemble("main.s", &parsed),
+        Err(CodeGenError::DuplicateSymbol("a".into(), 3))
+    );
+}
+
+#[test]
+fn codegen_org() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser
+        .parse_lines(
+            "call $func
+hlt
+org $0x0100
+func:
+    ret",
+        )
+        .unwrap();
+    let program = codegen.assemble("main.s", &parsed).unwrap();
+    assert_eq!(
+        program.segments,
+        vec![
+            Segment::new(0x0000, vec![0x5Eu8, 0x01u8, 0x00u8, 0xFFu8]),
+            Segment::new(0x0100, vec![0x60u8]),
+        ]
+    );
+    assert_eq!(program.debug_info.line_at(0x0100).map(|x| x.line), Some(5));
+
+    let parsed = parser
+        .parse_lines(
+            "org $2
+hlt
+hlt
+org $3
+hlt",
+        )
+        .unwrap();
+    assert_eq!(
+        codegen.assemble("main.s", &parsed),
+        Err(CodeGenError::Link(vec![LinkError::SectionOverlap(
+            "code".into(),
+            3
+        )]))
+    );
+}
+
+#[test]
+fn codegen_relocations() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser
+        .parse_lines(
+            "extern print
+start:
+    mov [$table + $2], r1
+    call $print
+section data
+data16 table = { $start, $0x0010 }",
+        )
+        .unwrap();
+    let object = codegen.compile("main.s", &parsed).unwrap();
+    assert_eq!(object.sections.len(), 2);
+
+    let relocs: Vec<_> = object
+        .sections
+        .iter()
+        .flat_map(|x| x.relocations.iter())
+        .map(|x| (x.offset, x.target.clone(), x.addend, x.line))
+        .collect();
+    assert_eq!(
+        relocs,
+        vec![
+            (0x0001, Target::Section(1), 2, 3),
+            (0x0005, Target::Symbol("print".into()), 0, 4),
+            (0x0000, Target::Section(0), 0, 6),
+        ]
+    );
+    assert_eq!(
+        object.sections[1].bytes,
+        vec![0x00u8, 0x00u8, 0x00u8, 0x10u8]
+    );
+
+    let parsed = parser.parse_lines("a:\nconstant b = $a").unwrap();
+    assert_eq!(
+        codegen.compile("main.s", &parsed),
+        Err(CodeGenError::NotAbsolute("$a".into(), 2))
+    );
+
+    // comparisons fold like the rest of absolute arithmetic
+    let parsed = parser
+        .parse_lines("constant n = $3\nmov [$n >= $2 && !$0 ? $5 : $6], r1")
+        .unwrap();
+    let object = codegen.compile("main.s", &parsed).unwrap();
+    assert_eq!(object.sections[0].bytes, vec![0x10u8, 0x00u8, 0x05u8, 0x02u8]);
+}
+
+#[test]
+fn codegen_jumps() {
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let parsed = parser
+        .parse_lines(
+            "loop:
+    jne $3, &loop
+    jeq r1, &loop
+    jle $0x10, &0x0100
+    jge r2, &loop
+    not r1",
+        )
+        .unwrap();
+    let program = codegen.assemble("main.s", &parsed).unwrap();
+    assert_eq!(
+        formats::flatten(&program.segments),
+        vec![
+            0x15u8, 0x00u8, 0x03u8, 0x00u8, 0x00u8, 0x3Eu8, 0x02u8, 0x00u8, 0x00u8, 0x47u8, 0x00u8,
+            0x10u8, 0x01u8, 0x00u8, 0x48u8, 0x03u8, 0x00u8, 0x00u8, 0x34u8, 0x02u8
+        ]
+    );
+}
@@ -24,19 +24,30 @@ pub enum TokenEnum {
     Or,
     And,
     Neg,
+    DoubleEqual,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
     // arithm
     Plus,
     Star,
     Minus,
+    Equal,
     // vars
     Lit(u16),
     Mem(u16),
     Reg(Regs),
+    LitSym(String),
+    MemSym(String),
     // params
     OpenParen,
     CloseParen,
     OpenBracket,
     CloseBracket,
+    OpenBrace,
+    CloseBrace,
     Question,
     // ident
     Ident(String),
@@ -55,17 +66,58 @@ impl fmt::Display for TokenEnum {
             TokenEnum::Mem(mem) => write!(f, "&{}", mem),
             TokenEnum::Reg(reg) => write!(f, "{:?}", reg),
             TokenEnum::Ref(ident) => write!(f, "&{}", ident),
+            TokenEnum::LitSym(ident) => write!(f, "${}", ident),
+            TokenEnum::MemSym(ident) => write!(f, "&{}", ident),
             TokenEnum::Comma => write!(f, ","),
             TokenEnum::Plus => write!(f, "+"),
             TokenEnum::Minus => write!(f, "-"),
             TokenEnum::Star => write!(f, "*"),
             TokenEnum::Question => write!(f, "?"),
             TokenEnum::Colon => write!(f, ":"),
-            _ => return Err(fmt::Error),
+            TokenEnum::Equal => write!(f, "="),
+            TokenEnum::Or => write!(f, "||"),
+            TokenEnum::And => write!(f, "&&"),
+            TokenEnum::Neg => write!(f, "!"),
+            TokenEnum::DoubleEqual => write!(f, "=="),
+            TokenEnum::NotEqual => write!(f, "!="),
+            TokenEnum::Less => write!(f, "<"),
+            TokenEnum::Greater => write!(f, ">"),
+            TokenEnum::LessEqual => write!(f, "<="),
+            TokenEnum::GreaterEqual => write!(f, ">="),
+            _ => Err(fmt::Error),
         }
     }
 }
 
+/// Operator applied to absolute values, comparisons and logic give 0 or 1
+pub fn apply_op(op: &TokenEnum, args: &[u16]) -> Option<u16> {
+    let value = match (op, args) {
+        (TokenEnum::Plus, [val]) => *val,
+        (TokenEnum::Minus, [val]) => val.wrapping_neg(),
+        (TokenEnum::Neg, [val]) => (*val == 0) as u16,
+        (TokenEnum::Plus, [lhs, rhs]) => lhs.wrapping_add(*rhs),
+        (TokenEnum::Minus, [lhs, rhs]) => lhs.wrapping_sub(*rhs),
+        (TokenEnum::Star, [lhs, rhs]) => lhs.wrapping_mul(*rhs),
+        (TokenEnum::DoubleEqual, [lhs, rhs]) => (lhs == rhs) as u16,
+        (TokenEnum::NotEqual, [lhs, rhs]) => (lhs != rhs) as u16,
+        (TokenEnum::Less, [lhs, rhs]) => (lhs < rhs) as u16,
+        (TokenEnum::Greater, [lhs, rhs]) => (lhs > rhs) as u16,
+        (TokenEnum::LessEqual, [lhs, rhs]) => (lhs <= rhs) as u16,
+        (TokenEnum::GreaterEqual, [lhs, rhs]) => (lhs >= rhs) as u16,
+        (TokenEnum::And, [lhs, rhs]) => (*lhs != 0 && *rhs != 0) as u16,
+        (TokenEnum::Or, [lhs, rhs]) => (*lhs != 0 || *rhs != 0) as u16,
+        (TokenEnum::Question, [cond, lhs, rhs]) => {
+            if *cond != 0 {
+                *lhs
+            } else {
+                *rhs
+            }
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
 pub fn parse_u16(val: &u16) -> (u8, u8) {
     ((val >> 8) as u8, (*val & 0x00ff) as u8)
 }
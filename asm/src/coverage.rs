@@ -0,0 +1,196 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use vm::{coverage::Coverage, cpu::Cpu};
+
+use crate::debuginfo::{DebugInfo, SymbolKind};
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+#[cfg(test)]
+use vm::registers::Register;
+
+/// Source line holding instructions, keyed by file and line
+struct Line {
+    count: u64,
+    /// Conditional jumps with taken and not taken counts, `None` never ran
+    jumps: Vec<Option<(u64, u64)>>,
+}
+
+/// Lines with instructions, data declarations are left out. Jumps are
+/// decoded from `cpu` memory, so they are found even if they never ran.
+fn code_lines(
+    coverage: &Coverage,
+    debug_info: &DebugInfo,
+    cpu: &Cpu,
+) -> BTreeMap<(u32, u32), Line> {
+    let mut lines: BTreeMap<(u32, u32), Line> = BTreeMap::new();
+    for entry in &debug_info.lines {
+        let data = debug_info.symbols.iter().any(|x| {
+            x.kind == SymbolKind::Data
+                && entry.address >= x.value
+                && (entry.address as u32) < x.value as u32 + x.size as u32
+        });
+        if data {
+            continue;
+        }
+        let line = lines.entry((entry.file, entry.line)).or_insert(Line {
+            count: 0,
+            jumps: Vec::new(),
+        });
+        line.count = line.count.max(coverage.hits(entry.address));
+        if cpu
+            .decode(entry.address)
+            .kind
+            .is_some_and(|x| x.is_conditional_jump())
+        {
+            let branch = coverage.branch(entry.address);
+            line.jumps.push(branch.map(|x| (x.taken, x.not_taken)));
+        }
+    }
+    lines
+}
+
+/// LCOV tracefile, each conditional jump is a block with taken and not taken branches
+pub fn lcov(coverage: &Coverage, debug_info: &DebugInfo, cpu: &Cpu) -> String {
+    let mut files: BTreeMap<u32, Vec<(u32, Line)>> = BTreeMap::new();
+    for ((file, number), line) in code_lines(coverage, debug_info, cpu) {
+        files.entry(file).or_default().push((number, line));
+    }
+
+    let mut out = String::new();
+    for (file, lines) in files {
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", debug_info.file_path(file).unwrap_or("?")).unwrap();
+        let (mut found, mut hit) = (0, 0);
+        for (number, line) in &lines {
+            for (block, jump) in line.jumps.iter().enumerate() {
+                let (taken, not_taken) = match jump {
+                    Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                writeln!(out, "BRDA:{},{},0,{}", number, block, taken).unwrap();
+                writeln!(out, "BRDA:{},{},1,{}", number, block, not_taken).unwrap();
+                found += 2;
+                hit += jump.map_or(0, |(taken, not_taken)| {
+                    (taken > 0) as u32 + (not_taken > 0) as u32
+                });
+            }
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", found, hit).unwrap();
+        for (number, line) in &lines {
+            writeln!(out, "DA:{},{}", number, line.count).unwrap();
+        }
+        let executed = lines.iter().filter(|(_, line)| line.count > 0).count();
+        writeln!(out, "LF:{}\nLH:{}", lines.len(), executed).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+    }
+    out
+}
+
+/// gcov style listing of `source` for `file`: execution count, line number
+/// and text, `-` for lines without code and `#####` for code that never ran
+pub fn listing(
+    coverage: &Coverage,
+    debug_info: &DebugInfo,
+    cpu: &Cpu,
+    file: u32,
+    source: &str,
+) -> String {
+    let lines = code_lines(coverage, debug_info, cpu);
+    let path = debug_info.file_path(file).unwrap_or("?");
+    let mut out = format!("{:>9}:{:>5}:Source:{}\n", "-", 0, path);
+    for (number, text) in (1..).zip(source.lines()) {
+        let Some(line) = lines.get(&(file, number)) else {
+            writeln!(out, "{:>9}:{:>5}:{}", "-", number, text).unwrap();
+            continue;
+        };
+        let count = match line.count {
+            0 => "#####".to_string(),
+            count => count.to_string(),
+        };
+        writeln!(out, "{:>9}:{:>5}:{}", count, number, text).unwrap();
+        for jump in &line.jumps {
+            match jump {
+                Some((taken, not_taken)) => writeln!(
+                    out,
+                    "{:16}branch taken {}, not taken {}",
+                    "", taken, not_taken
+                ),
+                None => writeln!(out, "{:16}branch never executed", ""),
+            }
+            .unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn coverage_reports() {
+    let source = "start:
+    mov $2, acc
+loop:
+    inc r1
+    jne r1, &loop
+    jeq $0, &never
+    hlt
+never:
+    jgt $1, &start
+    hlt
+data16 slot = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut cpu = machine(&program.segments).cpu;
+    let mut coverage = Coverage::new();
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let halted = cpu.step();
+        coverage.record(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(
+        lcov(&coverage, &program.debug_info, &cpu),
+        "TN:
+SF:main.s
+BRDA:5,0,0,1
+BRDA:5,0,1,1
+BRDA:6,0,0,0
+BRDA:6,0,1,1
+BRDA:9,0,0,-
+BRDA:9,0,1,-
+BRF:6
+BRH:3
+DA:2,1
+DA:4,2
+DA:5,2
+DA:6,1
+DA:7,1
+DA:9,0
+DA:10,0
+LF:7
+LH:5
+end_of_record
+"
+    );
+    assert_eq!(
+        listing(&coverage, &program.debug_info, &cpu, 0, source),
+        "        -:    0:Source:main.s
+        -:    1:start:
+        1:    2:    mov $2, acc
+        -:    3:loop:
+        2:    4:    inc r1
+        2:    5:    jne r1, &loop
+                branch taken 1, not taken 1
+        1:    6:    jeq $0, &never
+                branch taken 0, not taken 1
+        1:    7:    hlt
+        -:    8:never:
+    #####:    9:    jgt $1, &start
+                branch never executed
+    #####:   10:    hlt
+        -:   11:data16 slot = { $0 }
+"
+    );
+}
@@ -0,0 +1,582 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use serde_json::{json, Value};
+use vm::{
+    device::MemoryMappedDevice,
+    fault::Fault,
+    registers::Register,
+    unwind::{backtrace, FRAME_BYTES},
+};
+
+use crate::{debuginfo::DebugInfo, emu::Machine};
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+
+const THREAD_ID: u64 = 1;
+
+type DapRes<T> = Result<T, String>;
+
+/// Why execution stopped, `reason` of the `stopped` event
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Entry,
+    Step,
+    Breakpoint,
+    Halted,
+    Fault(Fault),
+}
+
+/// Debug adapter protocol server for editors, single thread with id 1.
+/// Frame `n` has register scope `n + 1`, outer frames show what the callee
+/// frame saved. Runs block until a stop, there is no `pause`.
+pub struct DapServer {
+    machine: Machine,
+    debug_info: DebugInfo,
+    // source path from the client -> breakpoint addresses
+    breakpoints: HashMap<String, Vec<u16>>,
+    next_breakpoint: usize,
+    seq: u64,
+    stop_on_entry: bool,
+    halted: bool,
+    done: bool,
+}
+
+impl DapServer {
+    pub fn new(machine: Machine, debug_info: DebugInfo) -> Self {
+        Self {
+            machine,
+            debug_info,
+            breakpoints: HashMap::new(),
+            next_breakpoint: 1,
+            seq: 1,
+            stop_on_entry: false,
+            halted: false,
+            done: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Answers `Content-Length` framed messages until `disconnect` or end of input
+    pub fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while !self.done {
+            let Some(request) = read_message(&mut input)? else {
+                break;
+            };
+            for mut message in self.handle(&request) {
+                message["seq"] = json!(self.seq);
+                self.seq += 1;
+                write_message(&mut output, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Response to `request` followed by events it caused, `seq` is left unset
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let mut events = Vec::new();
+        let body = match command {
+            "initialize" => {
+                events.push(event("initialized", Value::Null));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                }))
+            }
+            "launch" => {
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                match self.stop_on_entry {
+                    true => events.extend(stop_events(Stop::Entry)),
+                    false => events.extend(self.resume(|_, _| false)),
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => Ok(self.stack_trace(args)),
+            "scopes" => Ok(self.scopes(args)),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "continue" | "next" | "stepIn" | "stepOut" if self.halted => {
+                Err("program has halted".into())
+            }
+            "continue" => {
+                events.extend(self.resume(|_, _| false));
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                let (line, fp) = (self.line(), self.register(Register::Fp));
+                events.extend(self.resume(|this, fp_now| fp_now >= fp && this.line() != line));
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                let line = self.line();
+                events.extend(self.resume(|this, _| this.line() != line));
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                let fp = self.register(Register::Fp);
+                events.extend(self.resume(|_, fp_now| fp_now > fp));
+                Ok(Value::Null)
+            }
+            "disconnect" => {
+                self.done = true;
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request `{}`", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": body.is_ok(),
+            "command": command,
+        });
+        match body {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        std::iter::once(response).chain(events).collect()
+    }
+
+    fn register(&self, reg: Register) -> u16 {
+        self.machine.cpu.register(reg)
+    }
+
+    fn line(&self) -> Option<(u32, u32)> {
+        let entry = self.debug_info.line_at(self.register(Register::Ip))?;
+        Some((entry.file, entry.line))
+    }
+
+    /// Steps until `done(self, fp)`, a breakpoint or `hlt`, returns stop events
+    fn resume(&mut self, mut done: impl FnMut(&Self, u16) -> bool) -> Vec<Value> {
+        let stop = loop {
+            if self.machine.cpu.step() {
+                self.halted = true;
+                break self.machine.cpu.fault().map_or(Stop::Halted, Stop::Fault);
+            }
+            let ip = self.register(Register::Ip);
+            if self.breakpoints.values().flatten().any(|x| *x == ip) {
+                break Stop::Breakpoint;
+            }
+            if done(self, self.register(Register::Fp)) {
+                break Stop::Step;
+            }
+        };
+        stop_events(stop)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> DapRes<Value> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("missing source path")?;
+        let file = self
+            .debug_info
+            .files
+            .iter()
+            .find(|x| Path::new(path).ends_with(&x.path))
+            .map(|x| x.id);
+
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+        let lines = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        for line in lines.iter().filter_map(|x| x["line"].as_u64()) {
+            let address = file.and_then(|file| {
+                let found = self.debug_info.addresses_for_line(file, line as u32);
+                found.into_iter().min()
+            });
+            let mut breakpoint = json!({
+                "id": self.next_breakpoint,
+                "verified": address.is_some(),
+                "line": line,
+            });
+            self.next_breakpoint += 1;
+            match address {
+                Some(address) => {
+                    breakpoint["instructionReference"] = json!(format!("{:#06x}", address));
+                    addresses.push(address);
+                }
+                None => breakpoint["message"] = json!("no code at this line"),
+            }
+            breakpoints.push(breakpoint);
+        }
+        self.breakpoints.insert(path.to_string(), addresses);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Corrupted chain ends with a label frame carrying the unwinder error
+    fn stack_trace(&self, args: &Value) -> Value {
+        let trace = backtrace(&self.machine.cpu);
+        let frames = trace.frames;
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64().unwrap_or(0) as usize {
+            0 => usize::MAX,
+            levels => levels,
+        };
+        let total = frames.len() + trace.error.is_some() as usize;
+        let mut stack_frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(id, frame)| {
+                let ip = frame.ip;
+                let name = match self.debug_info.symbol_at(ip) {
+                    Some((symbol, _)) => symbol.name.clone(),
+                    None => format!("{:#06x}", ip),
+                };
+                // return address belongs to the instruction after the call
+                let at = if id == 0 { ip } else { ip.wrapping_sub(1) };
+                let mut json = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#06x}", ip),
+                });
+                if let Some(entry) = self.debug_info.line_at(at) {
+                    if let Some(path) = self.debug_info.file_path(entry.file) {
+                        let name = Path::new(path).file_name().unwrap_or_default();
+                        json["source"] = json!({
+                            "name": name.to_string_lossy(),
+                            "path": path,
+                        });
+                        json["line"] = json!(entry.line);
+                        json["column"] = json!(1);
+                    }
+                }
+                json
+            })
+            .collect();
+        let paged = start <= frames.len() && frames.len() - start < levels;
+        if let Some(error) = trace.error.filter(|_| paged) {
+            stack_frames.push(json!({
+                "id": frames.len(),
+                "name": format!("corrupted stack: {}", error),
+                "line": 0,
+                "column": 0,
+                "presentationHint": "label",
+            }));
+        }
+        json!({ "stackFrames": stack_frames, "totalFrames": total })
+    }
+
+    fn scopes(&self, args: &Value) -> Value {
+        let frame = args["frameId"].as_u64().unwrap_or(0);
+        json!({ "scopes": [{
+            "name": "Registers",
+            "presentationHint": "registers",
+            "variablesReference": frame + 1,
+            "expensive": false,
+        }] })
+    }
+
+    /// Registers of frame `reference - 1`, outer frames lack `acc`
+    fn frame_registers(&self, reference: u64) -> DapRes<Vec<(Register, u16)>> {
+        let frames = backtrace(&self.machine.cpu).frames;
+        let index = (reference as usize)
+            .checked_sub(1)
+            .filter(|x| *x < frames.len())
+            .ok_or(format!("no frame for reference {}", reference))?;
+        let (frame, callee) = match index {
+            0 => return Ok(self.machine.cpu.registers().collect()),
+            _ => (&frames[index], &frames[index - 1]),
+        };
+
+        let mut registers = vec![(Register::Ip, frame.ip)];
+        let saved = frame.registers.unwrap_or_default();
+        for (i, value) in saved.into_iter().enumerate() {
+            registers.push((Register::from_index(Register::R1 as u8 + i as u8), value));
+        }
+        // sp before the call pushed its frame
+        registers.push((Register::Sp, callee.fp.wrapping_add(FRAME_BYTES)));
+        registers.push((Register::Fp, frame.fp));
+        Ok(registers)
+    }
+
+    fn variables(&self, args: &Value) -> DapRes<Value> {
+        let reference = args["variablesReference"].as_u64().unwrap_or(0);
+        let variables: Vec<Value> = self
+            .frame_registers(reference)?
+            .into_iter()
+            .map(|(reg, value)| {
+                json!({
+                    "name": reg.name(),
+                    "value": format!("{:#06x}", value),
+                    "type": "u16",
+                    "variablesReference": 0,
+                    "memoryReference": format!("{:#06x}", value),
+                })
+            })
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> DapRes<Value> {
+        if args["variablesReference"].as_u64() != Some(1) {
+            return Err("only registers of the current frame can be set".into());
+        }
+        let name = args["name"].as_str().unwrap_or_default();
+        let reg = Register::from_name(name).ok_or(format!("unknown register `{}`", name))?;
+        let text = args["value"].as_str().unwrap_or_default();
+        let value = parse_number(text).ok_or(format!("invalid value `{}`", text))?;
+        self.machine.cpu.set_register(reg, value);
+        Ok(json!({ "value": format!("{:#06x}", value) }))
+    }
+
+    /// Start of the request range inside the 64 KiB address space
+    fn memory_range(&self, args: &Value) -> DapRes<i64> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let base = parse_number(reference).ok_or(format!("invalid reference `{}`", reference))?;
+        Ok(base as i64 + args["offset"].as_i64().unwrap_or(0))
+    }
+
+    fn read_memory(&self, args: &Value) -> DapRes<Value> {
+        let start = self.memory_range(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as i64;
+        let readable = match start {
+            0..=0xffff => count.min(0x10000 - start),
+            _ => 0,
+        };
+        let memory = self.machine.cpu.memory();
+        let bytes: Vec<u8> = (0..readable)
+            .map(|i| memory.peek_u8((start + i) as u16))
+            .collect();
+        Ok(json!({
+            "address": format!("{:#06x}", start),
+            "data": base64_encode(&bytes),
+            "unreadableBytes": count - readable,
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> DapRes<Value> {
+        let start = self.memory_range(args)?;
+        let data = args["data"].as_str().unwrap_or_default();
+        let bytes = base64_decode(data).ok_or("invalid base64 data")?;
+        if !(0..=0xffff).contains(&start) {
+            return Err(format!("address {:#x} is outside of memory", start));
+        }
+        let written = bytes.len().min((0x10000 - start) as usize);
+        self.machine
+            .cpu
+            .memory_mut()
+            .load(start as u16, &bytes[..written]);
+        Ok(json!({ "bytesWritten": written }))
+    }
+}
+
+fn event(name: &str, body: Value) -> Value {
+    let mut event = json!({ "type": "event", "event": name });
+    if !body.is_null() {
+        event["body"] = body;
+    }
+    event
+}
+
+fn stop_events(stop: Stop) -> Vec<Value> {
+    let reason = match stop {
+        Stop::Entry => "entry",
+        Stop::Step => "step",
+        Stop::Breakpoint => "breakpoint",
+        Stop::Halted => {
+            return vec![
+                event("exited", json!({ "exitCode": 0 })),
+                event("terminated", Value::Null),
+            ]
+        }
+        Stop::Fault(fault) => {
+            return vec![event(
+                "stopped",
+                json!({
+                    "reason": "exception",
+                    "description": "fault",
+                    "text": fault.to_string(),
+                    "threadId": THREAD_ID,
+                    "allThreadsStopped": true,
+                }),
+            )]
+        }
+    };
+    vec![event(
+        "stopped",
+        json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+    )]
+}
+
+fn parse_number(input: &str) -> Option<u16> {
+    match input.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => input.parse().ok(),
+    }
+}
+
+/// Next message body, `None` once input is over
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, x)| acc | (*x as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(match i <= chunk.len() {
+                true => BASE64[(word >> (18 - 6 * i) & 0x3f) as usize] as char,
+                false => '=',
+            });
+        }
+    }
+    out
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut word, mut bits) = (0u32, 0);
+    for ch in input.bytes().filter(|x| *x != b'=') {
+        let value = BASE64.iter().position(|x| *x == ch)? as u32;
+        word = word << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((word >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[test]
+fn dap_base64() {
+    for input in [&b""[..], b"a", b"ab", b"abc", b"\x00\xff\x10\x80"] {
+        assert_eq!(base64_decode(&base64_encode(input)).unwrap(), input);
+    }
+    assert_eq!(base64_encode(b"\x12\x34"), "EjQ=");
+    assert_eq!(base64_decode("!"), None);
+}
+
+#[test]
+fn dap_fault_stop() {
+    let events = stop_events(Stop::Fault(Fault::NoFrame(0x0012)));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["event"], "stopped");
+    assert_eq!(events[0]["body"]["reason"], "exception");
+    assert_eq!(
+        events[0]["body"]["text"],
+        "ret without call frame at 0x0012"
+    );
+}
+
+/// Replays client requests from a recorded transcript and expects the
+/// server side of it, messages are one per line in order
+#[test]
+fn dap_transcript() {
+    let source = "start:
+    mov $3, r1
+    push $0
+    call $square
+    mov acc, &result
+    hlt
+square:
+    mul r1, r1
+    ret
+data16 result = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut server = DapServer::new(machine(&program.segments), program.debug_info);
+
+    let transcript: Vec<Value> = include_str!("../transcripts/dap_session.jsonl")
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    let (requests, expected): (Vec<_>, Vec<_>) =
+        transcript.into_iter().partition(|x| x["type"] == "request");
+
+    let mut input = Vec::new();
+    for request in &requests {
+        write_message(&mut input, request).unwrap();
+    }
+    let mut output = Vec::new();
+    server.serve(input.as_slice(), &mut output).unwrap();
+
+    let mut output = output.as_slice();
+    let mut replies = Vec::new();
+    while let Some(message) = read_message(&mut output).unwrap() {
+        replies.push(message);
+    }
+    for (reply, expected) in replies.iter().zip(&expected) {
+        assert_eq!(reply, expected);
+    }
+    assert_eq!(replies.len(), expected.len());
+
+    let result = server.debug_info.symbol("result").unwrap().value;
+    assert_eq!(server.machine().cpu.memory().get_u16(result), 0x1234);
+}
+
+#[test]
+fn dap_stack_pages() {
+    let source = "start:
+    push $0
+    call $square
+    hlt
+square:
+    ret";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut machine = machine(&program.segments);
+    machine.cpu.step();
+    machine.cpu.step();
+    let server = DapServer::new(machine, program.debug_info);
+
+    let page = server.stack_trace(&json!({ "startFrame": 1, "levels": 1 }));
+    assert_eq!(page["totalFrames"], 2);
+    assert_eq!(page["stackFrames"][0]["name"], "start");
+    let page = server.stack_trace(&json!({ "startFrame": 0, "levels": 1 }));
+    assert_eq!(page["totalFrames"], 2);
+    assert_eq!(page["stackFrames"][0]["name"], "square");
+}
@@ -0,0 +1,1356 @@
+use std::{
+    cell::Ref,
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::{self, BufRead, Write},
+};
+
+use vm::{
+    bank::{BankedMemory, BANK_START},
+    cpu::Cpu,
+    device::MemoryMappedDevice,
+    history::Record,
+    instructions::{Instruction, Instructions, Operand},
+    mapper::{Access, WatchHit, WatchKind, Watchpoint},
+    registers::Register,
+    snapshot::Snapshot,
+    unwind::backtrace,
+};
+
+use crate::{
+    ast::S,
+    codegen::DEVICE_CONSTANTS,
+    common::{apply_op, TokenEnum},
+    debuginfo::DebugInfo,
+    emu::Machine,
+    parse::InstructionParser,
+};
+
+#[cfg(test)]
+use crate::{
+    codegen::CodeGen,
+    emu::{machine, machine_with_banks, DEFAULT_BANKS},
+};
+#[cfg(test)]
+use std::collections::VecDeque;
+#[cfg(test)]
+use vm::bank::BANK_SIZE;
+
+/// Instructions kept for reverse execution unless the machine already records
+pub const DEFAULT_HISTORY: usize = 10_000;
+
+const HELP: &str = "\
+break|b <addr|label>     stop when ip reaches address
+break <loc> if <expr>    stop only if asm expression is non-zero, `acc == $10 && r1 > $3`
+watch <addr> [len]       stop after write to range, 2 bytes by default
+rwatch <addr> [len]      stop after read
+awatch <addr> [len]      stop after read or write
+delete [id]              remove breakpoint or watchpoint, all without id
+info breakpoints         list breakpoints and watchpoints
+step|s [count]           execute instructions
+next|n                   step over call
+finish                   run until current function returns
+backtrace|bt             show call frames with arguments and saved registers
+continue|c|run|r         run until breakpoint or hlt
+reverse-step|rs [count]  undo instructions
+reverse-continue|rc      run backwards to previous breakpoint or watchpoint hit
+goto <count>             move to instruction count through history or forward
+info history             show instruction count and recorded depth
+save <file>              write machine snapshot to file
+restore <file>           load machine snapshot, reverse history starts over
+registers|regs           dump registers
+print|p <addr> [len]     view memory, 8 bytes by default
+info banks               show selected bank, bank count and size
+bank <n> <addr> [len]    view memory of any bank at window address
+set <reg> <value>        write register
+set &<addr> <value>      write 16-bit value to memory
+list|l                   show source around ip
+history                  list commands, `!n` repeats n-th one
+quit|q                   leave debugger
+empty line repeats last command";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugError {
+    UnknownCommand(String),
+    InvalidArgs(String),
+    InvalidExpr(String),
+    UnknownSymbol(String),
+    NoBreakpoint(usize),
+    NoHistory(String),
+    OutOfHistory(u64),
+    NotRunning,
+    Snapshot(String),
+    /// Bank and the bank count
+    NoBank(usize, usize),
+    /// Machine runs without banked memory
+    NoBanks,
+    /// Range does not fit the bank window
+    OutsideBank(u16, u16),
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownCommand(x) => write!(f, "unknown command `{}`, try `help`", x),
+            Self::InvalidArgs(x) => write!(f, "invalid arguments `{}`", x),
+            Self::InvalidExpr(x) => write!(f, "invalid expression `{}`", x),
+            Self::UnknownSymbol(x) => write!(f, "no symbol `{}`", x),
+            Self::NoBreakpoint(x) => write!(f, "no breakpoint {}", x),
+            Self::NoHistory(x) => write!(f, "no command `{}` in history", x),
+            Self::OutOfHistory(x) => write!(f, "instruction {} is out of recorded history", x),
+            Self::NotRunning => write!(f, "program has halted"),
+            Self::Snapshot(x) => write!(f, "snapshot: {}", x),
+            Self::NoBank(x, count) => write!(f, "no bank {}, there are {}", x, count),
+            Self::NoBanks => write!(f, "no banked memory, run with `--banks`"),
+            Self::OutsideBank(addr, len) => {
+                write!(
+                    f,
+                    "{} bytes at {:#06x} are outside the bank window",
+                    len, addr
+                )
+            }
+        }
+    }
+}
+
+type DebugRes<T> = Result<T, DebugError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Break(u16, Option<Condition>),
+    Watch(WatchKind, u16, u16),
+    Delete(Option<usize>),
+    Breakpoints,
+    Step(u32),
+    Next,
+    Finish,
+    Backtrace,
+    Continue,
+    ReverseStep(u32),
+    ReverseContinue,
+    Goto(u64),
+    InfoHistory,
+    Save(String),
+    Restore(String),
+    Registers,
+    Print(u16, u16),
+    Banks,
+    PrintBank(usize, u16, u16),
+    SetRegister(Register, u16),
+    SetMemory(u16, u16),
+    List,
+    History,
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+/// Breakpoint condition as typed and parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub text: String,
+    pub expr: S,
+}
+
+/// Why execution stopped
+#[derive(Debug, Clone, PartialEq)]
+enum Stop {
+    Done,
+    Breakpoint(usize),
+    Watchpoint(WatchHit),
+    Halted,
+    StartOfHistory,
+}
+
+/// Interactive debugger over [`Machine`], richer take on `run_debug` from core
+pub struct Debugger {
+    machine: Machine,
+    debug_info: DebugInfo,
+    // source lines by file id, `None` once reading failed
+    sources: HashMap<u32, Option<Vec<String>>>,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: usize,
+    history: Vec<String>,
+    halted: bool,
+}
+
+impl Debugger {
+    pub fn new(mut machine: Machine, debug_info: DebugInfo) -> Self {
+        if machine.cpu.history().is_none() {
+            machine.cpu.record(DEFAULT_HISTORY);
+        }
+        Self {
+            machine,
+            debug_info,
+            sources: HashMap::new(),
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+            history: Vec::new(),
+            halted: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.machine.cpu
+    }
+
+    /// Source text for `file`, otherwise it is read from the path in debug info
+    pub fn add_source(&mut self, file: u32, text: &str) {
+        let lines = text.lines().map(String::from).collect();
+        self.sources.insert(file, Some(lines));
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Reads commands until `quit` or end of input
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{}(dbg) ", self.location())?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut out)? {
+                break;
+            }
+            write!(out, "(dbg) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs one input line, returns `false` once the user quits
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let line = match self.expand_history(line.trim()) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(true),
+            Err(err) => {
+                writeln!(out, "error: {}", err)?;
+                return Ok(true);
+            }
+        };
+        self.history.push(line.clone());
+
+        let mut text = String::new();
+        let res = self
+            .parse(&line)
+            .and_then(|cmd| self.execute(cmd, &mut text));
+        write!(out, "{}", text)?;
+        match res {
+            Ok(running) => Ok(running),
+            Err(err) => {
+                writeln!(out, "error: {}", err)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Empty line repeats the last command, `!n` picks one from history
+    fn expand_history(&self, line: &str) -> DebugRes<Option<String>> {
+        if line.is_empty() {
+            return Ok(self.history.last().cloned());
+        }
+        match line.strip_prefix('!') {
+            Some(n) => n
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.history.get(n.wrapping_sub(1)))
+                .map(|x| Some(x.clone()))
+                .ok_or_else(|| DebugError::NoHistory(line.into())),
+            None => Ok(Some(line.into())),
+        }
+    }
+
+    pub fn parse(&self, line: &str) -> DebugRes<Command> {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, text)) => (line, Some(self.condition(text)?)),
+            None => (line, None),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let invalid = || DebugError::InvalidArgs(line.into());
+        let count = |x: &str| x.parse::<u32>().map_err(|_| invalid());
+        if condition.is_some() && !matches!(words.first(), Some(&"break" | &"b")) {
+            return Err(invalid());
+        }
+
+        Ok(match words.as_slice() {
+            ["break" | "b", loc] => Command::Break(self.value(loc)?, condition),
+            [cmd @ ("watch" | "rwatch" | "awatch"), addr, rest @ ..] => {
+                let kind = match *cmd {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let len = match rest {
+                    [] => 2,
+                    [len] => self.value(len)?,
+                    _ => return Err(invalid()),
+                };
+                if len == 0 {
+                    return Err(invalid());
+                }
+                Command::Watch(kind, self.value(addr)?, len)
+            }
+            ["delete"] => Command::Delete(None),
+            ["delete", id] => Command::Delete(Some(count(id)? as usize)),
+            ["info", "breakpoints"] => Command::Breakpoints,
+            ["step" | "s"] => Command::Step(1),
+            ["step" | "s", n] => Command::Step(count(n)?),
+            ["next" | "n"] => Command::Next,
+            ["finish"] => Command::Finish,
+            ["backtrace" | "bt"] => Command::Backtrace,
+            ["continue" | "c" | "run" | "r"] => Command::Continue,
+            ["reverse-step" | "rs"] => Command::ReverseStep(1),
+            ["reverse-step" | "rs", n] => Command::ReverseStep(count(n)?),
+            ["reverse-continue" | "rc"] => Command::ReverseContinue,
+            ["goto", n] => Command::Goto(n.parse().map_err(|_| invalid())?),
+            ["info", "history"] => Command::InfoHistory,
+            ["save", path] => Command::Save(path.to_string()),
+            ["restore", path] => Command::Restore(path.to_string()),
+            ["registers" | "regs"] => Command::Registers,
+            ["print" | "p", addr] => Command::Print(self.value(addr)?, 8),
+            ["print" | "p", addr, len] => Command::Print(self.value(addr)?, self.value(len)?),
+            ["info", "banks"] => Command::Banks,
+            ["bank", n, addr] => Command::PrintBank(count(n)? as usize, self.value(addr)?, 8),
+            ["bank", n, addr, len] => {
+                Command::PrintBank(count(n)? as usize, self.value(addr)?, self.value(len)?)
+            }
+            ["set", target, value] if target.starts_with('&') => {
+                Command::SetMemory(self.value(target)?, self.value(value)?)
+            }
+            ["set", reg, value] => {
+                let reg = Register::from_name(reg).ok_or_else(invalid)?;
+                Command::SetRegister(reg, self.value(value)?)
+            }
+            ["list" | "l"] => Command::List,
+            ["history"] => Command::History,
+            ["help" | "h"] => Command::Help,
+            ["quit" | "q"] => Command::Quit,
+            [name, ..] if is_command(name) => return Err(invalid()),
+            _ => return Err(DebugError::UnknownCommand(line.into())),
+        })
+    }
+
+    /// Number in asm notation (`$`/`&` prefix, `0x` for hex), symbol or
+    /// device constant
+    fn value(&self, input: &str) -> DebugRes<u16> {
+        let input = input.trim_start_matches(['$', '&']);
+        if let Some(hex) = input.strip_prefix("0x") {
+            return u16::from_str_radix(hex, 16).map_err(|_| DebugError::InvalidArgs(input.into()));
+        }
+        if input.starts_with(|x: char| x.is_ascii_digit()) {
+            return input
+                .parse()
+                .map_err(|_| DebugError::InvalidArgs(input.into()));
+        }
+        self.debug_info
+            .symbol(input)
+            .map(|x| x.value)
+            .or_else(|| DEVICE_CONSTANTS.iter().find(|x| x.0 == input).map(|x| x.1))
+            .ok_or_else(|| DebugError::UnknownSymbol(input.into()))
+    }
+
+    /// Parsed and checked once so typos fail here rather than on every hit
+    fn condition(&self, text: &str) -> DebugRes<Condition> {
+        let expr = InstructionParser::parse_expr(text)
+            .map_err(|_| DebugError::InvalidExpr(text.into()))?;
+        self.eval(&expr)?;
+        Ok(Condition {
+            text: text.trim().into(),
+            expr,
+        })
+    }
+
+    /// Registers by name, `&reg` reads memory at register, other names are symbols
+    fn eval(&self, expr: &S) -> DebugRes<u16> {
+        match expr {
+            S::Atom(token) => self.atom(token),
+            S::Cons(op, args) => {
+                let values = args
+                    .iter()
+                    .map(|x| self.eval(x))
+                    .collect::<DebugRes<Vec<_>>>()?;
+                apply_op(op, &values).ok_or_else(|| DebugError::InvalidExpr(expr.to_string()))
+            }
+        }
+    }
+
+    fn atom(&self, token: &TokenEnum) -> DebugRes<u16> {
+        let symbol = |name: &str| {
+            self.debug_info
+                .symbol(name)
+                .map(|x| x.value)
+                .ok_or_else(|| DebugError::UnknownSymbol(name.into()))
+        };
+        match token {
+            TokenEnum::Lit(value) | TokenEnum::Mem(value) => Ok(*value),
+            TokenEnum::Ident(name) => match Register::from_name(name) {
+                Some(reg) => Ok(self.cpu().register(reg)),
+                None => symbol(name),
+            },
+            TokenEnum::Ref(name) | TokenEnum::MemSym(name) => match Register::from_name(name) {
+                Some(reg) => Ok(self.cpu().memory().peek_u16(self.cpu().register(reg))),
+                None => symbol(name),
+            },
+            TokenEnum::LitSym(name) => symbol(name),
+            _ => Err(DebugError::InvalidExpr(format!("{:?}", token))),
+        }
+    }
+
+    fn next_id(&mut self) -> usize {
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        id
+    }
+
+    pub fn execute(&mut self, cmd: Command, out: &mut String) -> DebugRes<bool> {
+        match cmd {
+            Command::Break(address, condition) => {
+                let id = self.next_id();
+                writeln!(out, "breakpoint {} at {}", id, self.describe(address)).unwrap();
+                self.breakpoints.push(Breakpoint {
+                    id,
+                    address,
+                    condition,
+                });
+            }
+            Command::Watch(kind, start, len) => {
+                let id = self.next_id();
+                let end = start.saturating_add(len - 1);
+                self.machine.cpu.memory_mut().add_watchpoint(Watchpoint {
+                    id,
+                    start,
+                    end,
+                    kind,
+                });
+                writeln!(
+                    out,
+                    "watchpoint {}: {}",
+                    id,
+                    self.describe_watch(kind, start, end)
+                )
+                .unwrap();
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                let memory = self.machine.cpu.memory_mut();
+                let ids: Vec<usize> = memory.watchpoints().iter().map(|x| x.id).collect();
+                for id in ids {
+                    memory.remove_watchpoint(id);
+                }
+            }
+            Command::Delete(Some(id)) => {
+                let len = self.breakpoints.len();
+                self.breakpoints.retain(|x| x.id != id);
+                let removed = self.breakpoints.len() != len
+                    || self.machine.cpu.memory_mut().remove_watchpoint(id);
+                if !removed {
+                    return Err(DebugError::NoBreakpoint(id));
+                }
+            }
+            Command::Breakpoints => {
+                for bp in &self.breakpoints {
+                    write!(out, "{}: {}", bp.id, self.describe(bp.address)).unwrap();
+                    if let Some(condition) = &bp.condition {
+                        write!(out, " if {}", condition.text).unwrap();
+                    }
+                    writeln!(out).unwrap();
+                }
+                for wp in self.cpu().memory().watchpoints() {
+                    let text = self.describe_watch(wp.kind, wp.start, wp.end);
+                    writeln!(out, "{}: {}", wp.id, text).unwrap();
+                }
+            }
+            Command::Step(count) => {
+                let mut left = count;
+                let stop = self.resume(|_, _| {
+                    left = left.saturating_sub(1);
+                    left == 0
+                })?;
+                self.report(stop, out);
+            }
+            Command::Next => {
+                let instruction = self.cpu().decode(self.cpu().register(Register::Ip));
+                let stop = if is_call(&instruction) {
+                    // same address and stack depth means the call returned
+                    let ret = instruction.address.wrapping_add(instruction.size);
+                    let sp = self.cpu().register(Register::Sp);
+                    self.resume(|cpu, _| {
+                        cpu.register(Register::Ip) == ret && cpu.register(Register::Sp) == sp
+                    })?
+                } else {
+                    // handlers entered on the way run to their `rti`
+                    let mut depth = 0u32;
+                    self.resume(|cpu, instruction| {
+                        if depth > 0 {
+                            depth = nesting(depth, instruction);
+                        }
+                        if cpu.interrupted().is_some() {
+                            depth += 1;
+                        }
+                        depth == 0
+                    })?
+                };
+                self.report(stop, out);
+            }
+            Command::Finish => {
+                let (mut depth, mut returned) = (0u32, false);
+                let stop = self.resume(|cpu, instruction| {
+                    if depth == 0 && is_return(instruction) {
+                        returned = true;
+                    } else {
+                        depth = nesting(depth, instruction);
+                    }
+                    // hardware interrupts enter handlers without a call
+                    if cpu.interrupted().is_some() {
+                        depth += 1;
+                    }
+                    returned && depth == 0
+                })?;
+                self.report(stop, out);
+            }
+            Command::Continue => {
+                let stop = self.resume(|_, _| false)?;
+                self.report(stop, out);
+            }
+            Command::ReverseStep(count) => {
+                let mut left = count;
+                let stop = self.reverse(|_| {
+                    left = left.saturating_sub(1);
+                    left == 0
+                });
+                self.report(stop, out);
+            }
+            Command::ReverseContinue => {
+                let stop = self.reverse(|_| false);
+                self.report(stop, out);
+            }
+            Command::Goto(target) => {
+                let executed = self.cpu().executed();
+                let recorded = self.cpu().history().map_or(0, |x| x.len()) as u64;
+                if target < executed.saturating_sub(recorded) {
+                    return Err(DebugError::OutOfHistory(target));
+                }
+                let mut stop = Stop::Done;
+                if target < executed {
+                    for _ in target..executed {
+                        self.machine.cpu.step_back();
+                    }
+                    self.halted = false;
+                } else if target > executed {
+                    if self.halted {
+                        return Err(DebugError::NotRunning);
+                    }
+                    for _ in executed..target {
+                        if self.machine.cpu.step() {
+                            self.halted = true;
+                            stop = Stop::Halted;
+                            break;
+                        }
+                    }
+                }
+                self.report(stop, out);
+            }
+            Command::InfoHistory => {
+                let (recorded, depth) = self
+                    .cpu()
+                    .history()
+                    .map_or((0, 0), |x| (x.len(), x.depth()));
+                writeln!(
+                    out,
+                    "instruction {}, {} of {} recorded",
+                    self.cpu().executed(),
+                    recorded,
+                    depth
+                )
+                .unwrap();
+            }
+            Command::Save(path) => {
+                let snapshot = self.cpu().snapshot();
+                std::fs::write(&path, snapshot.encode())
+                    .map_err(|x| DebugError::Snapshot(x.to_string()))?;
+                writeln!(out, "saved instruction {} to {}", snapshot.executed, path).unwrap();
+            }
+            Command::Restore(path) => {
+                let error = |x: &dyn fmt::Display| DebugError::Snapshot(x.to_string());
+                let bytes = std::fs::read(&path).map_err(|x| error(&x))?;
+                let snapshot = Snapshot::decode(&bytes).map_err(|x| error(&x))?;
+                self.machine.cpu.restore(&snapshot).map_err(|x| error(&x))?;
+                self.halted = false;
+                self.report(Stop::Done, out);
+            }
+            Command::Backtrace => {
+                let trace = backtrace(self.cpu());
+                for (i, frame) in trace.frames.iter().enumerate() {
+                    write!(out, "#{:<3} {}", i, self.describe(frame.ip)).unwrap();
+                    // return address belongs to the instruction after the call
+                    let at = if i == 0 {
+                        frame.ip
+                    } else {
+                        frame.ip.wrapping_sub(1)
+                    };
+                    if let Some(entry) = self.debug_info.line_at(at) {
+                        let path = self.debug_info.file_path(entry.file).unwrap_or("?");
+                        write!(out, " at {}:{}", path, entry.line).unwrap();
+                    }
+                    writeln!(out).unwrap();
+                    if let Some(args) = frame.args.as_ref().filter(|x| !x.is_empty()) {
+                        let args: Vec<String> =
+                            args.iter().map(|x| format!("{:#06x}", x)).collect();
+                        writeln!(out, "     args: {}", args.join(", ")).unwrap();
+                    }
+                    if let Some(registers) = frame.registers {
+                        let saved: Vec<String> = registers
+                            .iter()
+                            .enumerate()
+                            .map(|(i, x)| format!("r{}={:#06x}", i + 1, x))
+                            .collect();
+                        writeln!(out, "     saved: {}", saved.join(" ")).unwrap();
+                    }
+                }
+                if let Some(error) = trace.error {
+                    writeln!(out, "corrupted stack: {}", error).unwrap();
+                }
+            }
+            Command::Registers => {
+                for (reg, value) in self.cpu().registers() {
+                    writeln!(out, "[{}]: {:#06x}", reg.name(), value).unwrap();
+                }
+            }
+            Command::Print(addr, len) => {
+                let memory = self.cpu().memory();
+                dump(out, addr, len, |x| memory.peek_u8(x));
+            }
+            Command::Banks => {
+                let banks = self.banks()?;
+                writeln!(
+                    out,
+                    "bank {} of {}, {} bytes each at {:#06x}",
+                    banks.selected(),
+                    banks.count(),
+                    banks.size(),
+                    BANK_START
+                )
+                .unwrap();
+            }
+            Command::PrintBank(bank, addr, len) => {
+                let banks = self.banks()?;
+                let bytes = banks
+                    .bank(bank)
+                    .ok_or_else(|| DebugError::NoBank(bank, banks.count()))?;
+                let offset = |x: u16| x.wrapping_sub(BANK_START) as usize;
+                if offset(addr) >= bytes.len() || offset(addr) + len as usize > bytes.len() {
+                    return Err(DebugError::OutsideBank(addr, len));
+                }
+                dump(out, addr, len, |x| bytes[offset(x)]);
+            }
+            Command::SetRegister(reg, value) => self.machine.cpu.set_register(reg, value),
+            Command::SetMemory(addr, value) => self.machine.cpu.memory_mut().set_u16(addr, value),
+            Command::List => {
+                let ip = self.cpu().register(Register::Ip);
+                let Some(entry) = self.debug_info.line_at(ip).cloned() else {
+                    writeln!(out, "no source for {:#06x}", ip).unwrap();
+                    return Ok(true);
+                };
+                let first = entry.line.saturating_sub(3).max(1);
+                for line in first..entry.line + 3 {
+                    let Some(text) = self.source_line(entry.file, line) else {
+                        break;
+                    };
+                    let marker = if line == entry.line { "=>" } else { "  " };
+                    writeln!(out, "{} {:4} {}", marker, line, text).unwrap();
+                }
+            }
+            Command::History => {
+                for (i, line) in self.history.iter().enumerate() {
+                    writeln!(out, "{:4} {}", i + 1, line).unwrap();
+                }
+            }
+            Command::Help => writeln!(out, "{}", HELP).unwrap(),
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Steps until `done` says so after an instruction, a breakpoint is hit or CPU halts
+    fn resume(&mut self, mut done: impl FnMut(&Cpu, &Instruction) -> bool) -> DebugRes<Stop> {
+        if self.halted {
+            return Err(DebugError::NotRunning);
+        }
+        loop {
+            let instruction = self.cpu().decode(self.cpu().register(Register::Ip));
+            if self.machine.cpu.step() {
+                self.halted = true;
+                return Ok(Stop::Halted);
+            }
+            if let Some(hit) = self.cpu().memory().take_hits().into_iter().next() {
+                return Ok(Stop::Watchpoint(hit));
+            }
+            if done(self.cpu(), &instruction) {
+                return Ok(Stop::Done);
+            }
+            if let Some(id) = self.breakpoint_hit() {
+                return Ok(Stop::Breakpoint(id));
+            }
+        }
+    }
+
+    /// Banked memory, an error if the machine has none
+    fn banks(&self) -> Result<Ref<'_, BankedMemory>, DebugError> {
+        Ok(self
+            .machine
+            .banks
+            .as_ref()
+            .ok_or(DebugError::NoBanks)?
+            .borrow())
+    }
+
+    /// Undoes instructions until `done`, a breakpoint or a watchpoint hit
+    fn reverse(&mut self, mut done: impl FnMut(&Record) -> bool) -> Stop {
+        loop {
+            // writes are matched against current watchpoints, so ones set
+            // after the fact work too
+            let write = self
+                .cpu()
+                .history()
+                .and_then(|x| x.last())
+                .and_then(|x| self.write_hit(x));
+            let Some(record) = self.machine.cpu.step_back() else {
+                return Stop::StartOfHistory;
+            };
+            self.halted = false;
+            let watchpoints = self.cpu().memory().watchpoints();
+            let read = record.hits.iter().find(|hit| {
+                hit.access == Access::Read && watchpoints.iter().any(|x| x.id == hit.id)
+            });
+            if let Some(hit) = write.or_else(|| read.cloned()) {
+                return Stop::Watchpoint(hit);
+            }
+            if done(&record) {
+                return Stop::Done;
+            }
+            if let Some(id) = self.breakpoint_hit() {
+                return Stop::Breakpoint(id);
+            }
+        }
+    }
+
+    /// Write or access watchpoint covering bytes `record` overwrote, value is the new one
+    fn write_hit(&self, record: &Record) -> Option<WatchHit> {
+        let start = record.writes.first()?.addr;
+        let memory = self.cpu().memory();
+        memory
+            .watchpoints()
+            .iter()
+            .filter(|x| x.kind != WatchKind::Read)
+            .find(|wp| {
+                record
+                    .writes
+                    .iter()
+                    .any(|x| wp.start <= x.addr && x.addr <= wp.end)
+            })
+            .map(|wp| WatchHit {
+                id: wp.id,
+                access: Access::Write,
+                addr: start,
+                value: memory.peek_u16(start),
+            })
+    }
+
+    /// Breakpoint at `ip` whose condition holds
+    fn breakpoint_hit(&self) -> Option<usize> {
+        let ip = self.cpu().register(Register::Ip);
+        self.breakpoints
+            .iter()
+            .find(|x| {
+                // broken condition stops too, better than running past it
+                x.address == ip
+                    && x.condition
+                        .as_ref()
+                        .is_none_or(|x| self.eval(&x.expr) != Ok(0))
+            })
+            .map(|x| x.id)
+    }
+
+    fn report(&mut self, stop: Stop, out: &mut String) {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(id) => write!(out, "breakpoint {}, ", id).unwrap(),
+            Stop::Watchpoint(hit) => {
+                let access = match hit.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                let addr = self.data_address(hit.addr);
+                writeln!(
+                    out,
+                    "watchpoint {}, {} {} = {:#06x}",
+                    hit.id, access, addr, hit.value
+                )
+                .unwrap();
+            }
+            Stop::Halted => {
+                if let Some(fault) = self.cpu().fault() {
+                    writeln!(out, "fault: {}", fault).unwrap();
+                    return;
+                }
+                let ip = self.cpu().register(Register::Ip);
+                writeln!(out, "halted at {}", self.describe(ip.wrapping_sub(1))).unwrap();
+                return;
+            }
+            Stop::StartOfHistory => write!(out, "no more reverse history, ").unwrap(),
+        }
+        *out += &self.location();
+    }
+
+    /// Current instruction followed by its source line
+    fn location(&mut self) -> String {
+        let ip = self.cpu().register(Register::Ip);
+        let instruction = self.cpu().decode(ip);
+        let mut res = format!(
+            "{}: {}\n",
+            self.describe(ip),
+            disassemble(&instruction, &self.debug_info)
+        );
+        if let Some(entry) = self.debug_info.line_at(ip).cloned() {
+            let path = self.debug_info.file_path(entry.file).unwrap_or("?");
+            write!(res, "{}:{}", path, entry.line).unwrap();
+            if let Some(text) = self.source_line(entry.file, entry.line) {
+                write!(res, ": {}", text.trim()).unwrap();
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    /// `0x0004 <loop+1>` style address
+    fn describe(&self, addr: u16) -> String {
+        match self.debug_info.symbol_at(addr) {
+            Some((symbol, 0)) => format!("{:#06x} <{}>", addr, symbol.name),
+            Some((symbol, offset)) => format!("{:#06x} <{}+{}>", addr, symbol.name, offset),
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    fn describe_watch(&self, kind: WatchKind, start: u16, end: u16) -> String {
+        let kind = match kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        format!("{} {}..={:#06x}", kind, self.data_address(start), end)
+    }
+
+    /// Like [`Self::describe`] but only exact symbols, data rarely sits next to a label
+    fn data_address(&self, addr: u16) -> String {
+        match self.debug_info.symbol_at(addr) {
+            Some((symbol, 0)) => format!("{:#06x} <{}>", addr, symbol.name),
+            _ => format!("{:#06x}", addr),
+        }
+    }
+
+    fn source_line(&mut self, file: u32, line: u32) -> Option<String> {
+        let debug_info = &self.debug_info;
+        let lines = self.sources.entry(file).or_insert_with(|| {
+            let path = debug_info.file_path(file)?;
+            let text = std::fs::read_to_string(path).ok()?;
+            Some(text.lines().map(String::from).collect())
+        });
+        lines.as_ref()?.get(line.checked_sub(1)? as usize).cloned()
+    }
+}
+
+/// Known command name or alias, taken from the help text
+fn is_command(name: &str) -> bool {
+    HELP.lines()
+        .filter_map(|x| x.split(' ').next())
+        .any(|x| x.split('|').any(|x| x == name))
+}
+
+/// `len` bytes from `addr` on through `read`, 8 per row
+fn dump(out: &mut String, addr: u16, len: u16, read: impl Fn(u16) -> u8) {
+    for row in (0..len).step_by(8) {
+        let start = addr.wrapping_add(row);
+        write!(out, "{:#06x}:", start).unwrap();
+        for i in 0..(len - row).min(8) {
+            write!(out, " {:02x}", read(start.wrapping_add(i))).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+}
+
+fn is_return(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.kind,
+        Some(Instructions::RET | Instructions::RET_INT)
+    )
+}
+
+/// Call depth after `instruction` ran at `depth`
+fn nesting(depth: u32, instruction: &Instruction) -> u32 {
+    if is_call(instruction) {
+        depth + 1
+    } else if is_return(instruction) {
+        depth.saturating_sub(1)
+    } else {
+        depth
+    }
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.kind,
+        Some(Instructions::CALL_LIT | Instructions::CALL_REG | Instructions::INT_LIT)
+    )
+}
+
+/// Instruction in asm syntax, addresses matching a symbol are shown by name
+pub fn disassemble(instruction: &Instruction, debug_info: &DebugInfo) -> String {
+    let Some(kind) = instruction.kind else {
+        return format!("(bad) {:#04x}", instruction.opcode);
+    };
+
+    let address = |prefix: &str, addr: u16| match debug_info.symbol_at(addr) {
+        Some((symbol, 0)) => format!("{}{}", prefix, symbol.name),
+        _ => format!("{}{:#06x}", prefix, addr),
+    };
+    let operands: Vec<String> = instruction
+        .operands
+        .iter()
+        .enumerate()
+        .map(|(i, operand)| match (kind, operand) {
+            (Instructions::MOV_REG_PTR_REG, Operand::Reg(reg)) if i == 0 => {
+                format!("&{}", reg.name())
+            }
+            (Instructions::CALL_LIT, Operand::Addr(addr)) => address("$", *addr),
+            (_, Operand::Addr(addr)) => address("&", *addr),
+            (_, Operand::Reg(reg)) => reg.name().into(),
+            (_, Operand::Lit(lit)) => format!("${:#06x}", lit),
+        })
+        .collect();
+
+    if operands.is_empty() {
+        kind.mnemonic().into()
+    } else {
+        format!("{} {}", kind.mnemonic(), operands.join(", "))
+    }
+}
+
+#[test]
+fn debugger_session() {
+    let source = "start:
+    mov $2, r1
+    push $0
+    call $square
+    mov acc, &result
+    hlt
+square:
+    mul r1, r1
+    ret
+data16 result = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut debugger = Debugger::new(machine(&program.segments), program.debug_info);
+    debugger.add_source(0, source);
+
+    let script = "b square
+c
+set r1 3
+finish
+n
+p result 2
+set &result 0x1234
+!6
+
+bogus
+step x
+s
+s
+q
+s";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0x0000 <start>: mov $0x0002, r1
+main.s:2: mov $2, r1
+(dbg) breakpoint 1 at 0x000f <square>
+(dbg) breakpoint 1, 0x000f <square>: mul r1, r1
+main.s:8: mul r1, r1
+(dbg) (dbg) 0x000a <start+10>: mov acc, &result
+main.s:5: mov acc, &result
+(dbg) 0x000e <start+14>: hlt
+main.s:6: hlt
+(dbg) 0x0013: 00 09
+(dbg) (dbg) 0x0013: 12 34
+(dbg) 0x0013: 12 34
+(dbg) error: unknown command `bogus`, try `help`
+(dbg) error: invalid arguments `step x`
+(dbg) halted at 0x000e <start+14>
+(dbg) error: program has halted
+(dbg) "
+    );
+
+    // r1 comes back from the frame, acc keeps the product
+    assert_eq!(debugger.cpu().register(Register::Acc), 9);
+    assert_eq!(debugger.cpu().register(Register::R1), 2);
+    assert_eq!(debugger.history[8], "p result 2");
+}
+
+#[test]
+fn debugger_watchpoints() {
+    let source = "start:
+    mov $4, acc
+loop:
+    inc r1
+    mov r1, &0x3000
+    mov r1, &slot
+    jne r1, &loop
+    hlt
+data16 slot = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut debugger = Debugger::new(machine(&program.segments), program.debug_info);
+    debugger.add_source(0, source);
+
+    let script = "watch &0x3000
+c
+delete 1
+b loop if r1 == $3 && acc > $1
+c
+info breakpoints
+awatch slot
+c
+b loop if bogus == $1
+b loop if (r1
+q";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0x0000 <start>: mov $0x0004, acc
+main.s:2: mov $4, acc
+(dbg) watchpoint 1: write 0x3000..=0x3001
+(dbg) watchpoint 1, write 0x3000 = 0x0001
+0x000a <loop+6>: mov r1, &slot
+main.s:6: mov r1, &slot
+(dbg) (dbg) breakpoint 2 at 0x0004 <loop>
+(dbg) breakpoint 2, 0x0004 <loop>: inc r1
+main.s:4: inc r1
+(dbg) 2: 0x0004 <loop> if r1 == $3 && acc > $1
+(dbg) watchpoint 3: access 0x0013 <slot>..=0x0014
+(dbg) watchpoint 3, write 0x0013 <slot> = 0x0004
+0x000e <loop+10>: jne r1, &loop
+main.s:7: jne r1, &loop
+(dbg) error: no symbol `bogus`
+(dbg) error: invalid expression `(r1`
+(dbg) "
+    );
+    assert_eq!(debugger.cpu().register(Register::R1), 4);
+}
+
+#[test]
+fn debugger_reverse() {
+    let source = "start:
+    mov $4, acc
+loop:
+    inc r1
+    mov r1, &0x3000
+    mov r1, &slot
+    jne r1, &loop
+    hlt
+data16 slot = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut debugger = Debugger::new(machine(&program.segments), program.debug_info.clone());
+    debugger.add_source(0, source);
+
+    let script = "c
+info history
+rs
+b loop if r1 == $2
+rc
+p slot 2
+watch slot
+delete 1
+rc
+p slot 2
+goto 0
+goto 18
+goto 30";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0x0000 <start>: mov $0x0004, acc
+main.s:2: mov $4, acc
+(dbg) halted at 0x0012 <loop+14>
+(dbg) instruction 18, 18 of 10000 recorded
+(dbg) 0x0012 <loop+14>: hlt
+main.s:8: hlt
+(dbg) breakpoint 1 at 0x0004 <loop>
+(dbg) breakpoint 1, 0x0004 <loop>: inc r1
+main.s:4: inc r1
+(dbg) 0x0013: 00 02
+(dbg) watchpoint 2: write 0x0013 <slot>..=0x0014
+(dbg) (dbg) watchpoint 2, write 0x0013 <slot> = 0x0002
+0x000a <loop+6>: mov r1, &slot
+main.s:6: mov r1, &slot
+(dbg) 0x0013: 00 01
+(dbg) 0x0000 <start>: mov $0x0004, acc
+main.s:2: mov $4, acc
+(dbg) halted at 0x0012 <loop+14>
+(dbg) error: program has halted
+(dbg) "
+    );
+
+    // bounded history
+    let mut machine = machine(&program.segments);
+    machine.cpu.record(4);
+    let mut debugger = Debugger::new(machine, program.debug_info);
+    debugger.add_source(0, source);
+    let mut out = Vec::new();
+    debugger
+        .repl("c\ngoto 10\nrc".as_bytes(), &mut out)
+        .unwrap();
+    assert!(String::from_utf8(out).unwrap().ends_with(
+        "(dbg) error: instruction 10 is out of recorded history
+(dbg) no more reverse history, 0x0006 <loop+2>: mov r1, &0x3000
+main.s:5: mov r1, &0x3000
+(dbg) "
+    ));
+    assert_eq!(debugger.cpu().executed(), 14);
+}
+
+#[test]
+fn debugger_backtrace() {
+    let source = "start:
+    mov $7, r2
+    push $0x11
+    push $1
+    call $outer
+    hlt
+outer:
+    push $0
+    call $inner
+    ret
+inner:
+    hlt";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut debugger = Debugger::new(machine(&program.segments), program.debug_info);
+    debugger.add_source(0, source);
+
+    // saved size of the `outer` frame is smashed at the end
+    let script = "b inner
+c
+bt
+set &0xffe8 2
+bt
+q";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let saved =
+        "saved: r1=0x0000 r2=0x0007 r3=0x0000 r4=0x0000 r5=0x0000 r6=0x0000 r7=0x0000 r8=0x0000";
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!(
+            "0x0000 <start>: mov $0x0007, r2
+main.s:2: mov $7, r2
+(dbg) breakpoint 1 at 0x0015 <inner>
+(dbg) breakpoint 1, 0x0015 <inner>: hlt
+main.s:12: hlt
+(dbg) #0   0x0015 <inner> at main.s:12
+#1   0x0014 <outer+6> at main.s:9
+     args: 0x0011
+     {saved}
+#2   0x000d <start+13> at main.s:5
+     {saved}
+(dbg) (dbg) #0   0x0015 <inner> at main.s:12
+#1   0x0014 <outer+6> at main.s:9
+     {saved}
+corrupted stack: frame at 0xffe6 has saved size 0x0002, too small
+(dbg) "
+        )
+    );
+}
+
+#[test]
+fn debugger_snapshot() {
+    let source = "start:
+    mov $3, acc
+loop:
+    inc r1
+    mov r1, &0x3000
+    jne r1, &loop
+    hlt";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut debugger = Debugger::new(machine(&program.segments), program.debug_info);
+    debugger.add_source(0, source);
+
+    let path = std::env::temp_dir().join(format!("debugger_snapshot_{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    let script = format!(
+        "s 4
+save {path}
+c
+restore {path}
+info history
+restore {path}.missing
+q"
+    );
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.split("(dbg) ").collect::<Vec<_>>()[2..5],
+        [
+            format!("saved instruction 4 to {}\n", path),
+            "halted at 0x000e <loop+10>\n".to_string(),
+            "0x0004 <loop>: inc r1\nmain.s:4: inc r1\n".to_string(),
+        ]
+    );
+    assert!(out.contains("(dbg) instruction 4, 0 of 10000 recorded\n(dbg) error: snapshot: "));
+    assert_eq!(debugger.cpu().register(Register::R1), 1);
+    // screen is restored along with RAM
+    assert_eq!(debugger.machine().screen.borrow().cell(0, 0).ch, '\u{1}');
+}
+
+#[test]
+fn debugger_banks() {
+    let source = "start:
+    mov $0x1234, &BANK_WINDOW
+    mov $3, &BANK_SELECT
+    mov $0x5678, &BANK_WINDOW
+    hlt";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let info = program.debug_info.clone();
+    let mut debugger = Debugger::new(machine(&program.segments), info);
+    let mut out = Vec::new();
+    debugger.repl("info banks".as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.split("(dbg) ").nth(1),
+        Some("error: no banked memory, run with `--banks`\n")
+    );
+
+    let machine = machine_with_banks(&program.segments, DEFAULT_BANKS, BANK_SIZE);
+    let mut debugger = Debugger::new(machine, program.debug_info);
+    let script = "c
+info banks
+bank 0 BANK_WINDOW 4
+bank 3 0xd000 2
+bank 8 0xd000
+bank 0 0xeffe 4
+q";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.split("(dbg) ").collect::<Vec<_>>()[2..7],
+        [
+            "bank 3 of 8, 8192 bytes each at 0xd000\n",
+            "0xd000: 12 34 00 00\n",
+            "0xd000: 56 78\n",
+            "error: no bank 8, there are 8\n",
+            "error: 4 bytes at 0xeffe are outside the bank window\n",
+        ]
+    );
+}
+
+#[test]
+fn debugger_peeks_devices() {
+    let source = "start:
+    mov $1, r1
+    mov &KEYBOARD_DATA, r2
+    hlt";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let machine = machine(&program.segments);
+    machine
+        .keyboard
+        .borrow_mut()
+        .set_input(VecDeque::from(*b"k"));
+    let mut debugger = Debugger::new(machine, program.debug_info);
+    let script = "s
+p KEYBOARD_STATUS 4
+p KEYBOARD_DATA 2
+c
+q";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.split("(dbg) ").collect::<Vec<_>>()[2..4],
+        ["0x310c: 00 05 00 6b\n", "0x310e: 00 6b\n"]
+    );
+    // the program still gets the key
+    assert_eq!(debugger.cpu().register(Register::R2), b'k' as u16);
+}
+
+#[test]
+fn debugger_interrupted() {
+    let source = "start:
+    mov $vectors, &INTERRUPT_VECTORS
+    mov $2, &TIMER_RELOAD
+    push $0
+    call $work
+    hlt
+work:
+    mov $5, &TIMER_CONTROL
+    inc r1
+    inc r1
+    ret
+tick:
+    mov $1, &fired
+    rti
+data16 vectors = { $tick }
+data16 fired = { $0 }";
+    let parsed = InstructionParser::new().parse_lines(source).unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    // the timer expires on the first `inc`, `next` and `finish` run the
+    // handler it enters
+    for (script, stops) in [
+        (
+            "b work\nc\nn\nn\nq",
+            [
+                "0x0016 <work+5>: inc r1\nmain.s:9\n",
+                "0x0018 <work+7>: inc r1\nmain.s:10\n",
+            ],
+        ),
+        (
+            "b work\nc\nfinish\nq",
+            ["0x0010 <start+16>: hlt\nmain.s:6\n", ""],
+        ),
+    ] {
+        let machine = machine(&program.segments);
+        let mut debugger = Debugger::new(machine, program.debug_info.clone());
+        let mut out = Vec::new();
+        debugger.repl(script.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let out: Vec<_> = out.split("(dbg) ").skip(3).collect();
+        assert_eq!(out[..2], stops);
+        let fired = debugger.debug_info.symbol("fired").unwrap().value;
+        assert_eq!(debugger.cpu().memory().get_u16(fired), 1);
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+
+pub const DEBUG_INFO_VERSION: u32 = 1;
+
+/// Sidecar written next to the binary, describes symbols and source lines
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    pub version: u32,
+    pub files: Vec<SourceFile>,
+    pub symbols: Vec<Symbol>,
+    pub structures: Vec<Structure>,
+    pub lines: Vec<LineEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub id: u32,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    Label,
+    Constant,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: u16,
+    /// Size in bytes, zero for labels and constants
+    #[serde(default)]
+    pub size: u16,
+    pub file: u32,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Structure {
+    pub name: String,
+    pub size: u16,
+    pub fields: Vec<Field>,
+    pub file: u32,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub offset: u16,
+    pub size: u16,
+}
+
+/// Bytes `address..address + size` were generated from `line` of `file`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineEntry {
+    pub address: u16,
+    pub size: u16,
+    pub file: u32,
+    pub line: u32,
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self {
+            version: DEBUG_INFO_VERSION,
+            files: Vec::new(),
+            symbols: Vec::new(),
+            structures: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("debug info is always serializable")
+    }
+
+    pub fn from_json(input: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(input)
+    }
+
+    pub fn file_path(&self, id: u32) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|x| x.id == id)
+            .map(|x| x.path.as_str())
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|x| x.name == name)
+    }
+
+    /// Closest label or data symbol at or before `addr` with offset from it
+    pub fn symbol_at(&self, addr: u16) -> Option<(&Symbol, u16)> {
+        self.symbols
+            .iter()
+            .filter(|x| x.kind != SymbolKind::Constant && x.value <= addr)
+            .max_by_key(|x| x.value)
+            .map(|x| (x, addr - x.value))
+    }
+
+    pub fn line_at(&self, addr: u16) -> Option<&LineEntry> {
+        self.lines
+            .iter()
+            .find(|x| addr >= x.address && (addr as u32) < x.address as u32 + x.size as u32)
+    }
+
+    pub fn addresses_for_line(&self, file: u32, line: u32) -> Vec<u16> {
+        self.lines
+            .iter()
+            .filter(|x| x.file == file && x.line == line)
+            .map(|x| x.address)
+            .collect()
+    }
+}
+
+#[test]
+fn debug_info_json() {
+    let mut info = DebugInfo::new();
+    info.files.push(SourceFile {
+        id: 0,
+        path: "main.s".into(),
+    });
+    info.symbols.push(Symbol {
+        name: "start".into(),
+        kind: SymbolKind::Label,
+        value: 0x0004,
+        size: 0,
+        file: 0,
+        line: 2,
+    });
+    info.lines.push(LineEntry {
+        address: 0x0004,
+        size: 4,
+        file: 0,
+        line: 3,
+    });
+
+    let parsed = DebugInfo::from_json(&info.to_json()).unwrap();
+    assert_eq!(parsed, info);
+
+    assert_eq!(
+        parsed
+            .symbol_at(0x0006)
+            .map(|(s, off)| (s.name.as_str(), off)),
+        Some(("start", 2))
+    );
+    assert_eq!(parsed.symbol_at(0x0002), None);
+    assert_eq!(parsed.line_at(0x0007).map(|x| x.line), Some(3));
+    assert_eq!(parsed.line_at(0x0008), None);
+    assert_eq!(parsed.addresses_for_line(0, 3), vec![0x0004]);
+}
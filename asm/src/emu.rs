@@ -0,0 +1,386 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, IsTerminal, Read},
+    process::Command,
+    rc::Rc,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use vm::{
+    bank::{BankSelect, BankedMemory, BANK_SELECT, BANK_SIZE, BANK_START},
+    cpu::Cpu,
+    device::MemoryMappedDevice,
+    interrupt::{INTERRUPT_END, INTERRUPT_START},
+    keyboard::{KeyInput, Keyboard, KEYBOARD_END, KEYBOARD_LINE, KEYBOARD_START},
+    mapper::{MemoryMapper, Region},
+    memory::Memory,
+    screen::{Screen, SCREEN_END, SCREEN_START},
+    timer::{Timer, TIMER_END, TIMER_LINE, TIMER_START},
+    timing::{CYCLES_END, CYCLES_START},
+};
+
+use crate::formats::Segment;
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, debugger::disassemble, parse::InstructionParser};
+#[cfg(test)]
+use vm::{registers::Register, screen::Backend};
+
+/// Banks behind the window when only `--bank-size` is given
+pub const DEFAULT_BANKS: usize = 8;
+
+/// CPU wired up like `core/src/main.cpp`, devices stay reachable after mapping
+pub struct Machine {
+    pub cpu: Cpu,
+    pub screen: Rc<RefCell<Screen>>,
+    /// Only with [`machine_with_banks`], RAM stays at the window otherwise
+    pub banks: Option<Rc<RefCell<BankedMemory>>>,
+    pub keyboard: Rc<RefCell<Keyboard>>,
+    pub timer: Rc<RefCell<Timer>>,
+}
+
+/// Fresh VM with `segments` loaded, execution starts at 0x0000 like in core.
+/// Cycle counter is mapped at [`CYCLES_START`], interrupt controller at
+/// [`INTERRUPT_START`], keyboard without input at [`KEYBOARD_START`] and timer
+/// at [`TIMER_START`] on top of core devices.
+pub fn machine(segments: &[Segment]) -> Machine {
+    let mut memory = Memory::default();
+    for segment in segments {
+        memory.load(segment.address, &segment.bytes);
+    }
+
+    let screen = Rc::new(RefCell::new(Screen::new()));
+    let mut mapper = MemoryMapper::from(memory);
+    mapper.map(Region::new(screen.clone(), SCREEN_START, SCREEN_END, true));
+    let mut cpu = Cpu::new(mapper);
+    let counter = cpu.cycle_counter();
+    cpu.memory_mut()
+        .map(Region::new(counter, CYCLES_START, CYCLES_END, true));
+    let controller = cpu.interrupt_controller();
+    cpu.memory_mut().map(Region::new(
+        controller,
+        INTERRUPT_START,
+        INTERRUPT_END,
+        true,
+    ));
+    let keyboard = Rc::new(RefCell::new(Keyboard::new(
+        VecDeque::new(),
+        cpu.interrupt_line(KEYBOARD_LINE),
+    )));
+    cpu.memory_mut().map(Region::new(
+        keyboard.clone(),
+        KEYBOARD_START,
+        KEYBOARD_END,
+        true,
+    ));
+    let timer = Rc::new(RefCell::new(Timer::new(cpu.interrupt_line(TIMER_LINE))));
+    cpu.memory_mut()
+        .map(Region::new(timer.clone(), TIMER_START, TIMER_END, true));
+    Machine {
+        cpu,
+        screen,
+        banks: None,
+        keyboard,
+        timer,
+    }
+}
+
+/// Like [`machine`] with `count` banks of `size` bytes, at most
+/// [`BANK_SIZE`], mapped from [`BANK_START`] and their select register at
+/// [`BANK_SELECT`]. Segment bytes under the window go to bank 0.
+pub fn machine_with_banks(segments: &[Segment], count: usize, size: usize) -> Machine {
+    let mut machine = machine(segments);
+    let mut memory = BankedMemory::new(count, size);
+    let bank = memory.bank_mut(0).unwrap();
+    for segment in segments {
+        for (i, byte) in segment.bytes.iter().enumerate() {
+            let addr = segment.address.wrapping_add(i as u16);
+            if let Some(x) = bank.get_mut(addr.wrapping_sub(BANK_START) as usize) {
+                *x = *byte;
+            }
+        }
+    }
+    let banks = Rc::new(RefCell::new(memory));
+    let end = BANK_START + (size.min(BANK_SIZE) - 1) as u16;
+    let cpu = &mut machine.cpu;
+    cpu.memory_mut()
+        .map(Region::new(banks.clone(), BANK_START, end, true));
+    cpu.memory_mut().map(Region::new(
+        BankSelect::new(banks.clone()),
+        BANK_SELECT,
+        BANK_SELECT + 1,
+        true,
+    ));
+    machine.banks = Some(banks);
+    machine
+}
+
+/// Host terminal in raw mode through `stty`, previous mode comes back on drop
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(Self {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Keys from host stdin, read by a thread so the CPU never waits for them.
+/// Raw mode turns Ctrl-C into a key, `raw` makes it restore the terminal and
+/// exit instead.
+pub struct HostInput {
+    keys: Receiver<u8>,
+    closed: bool,
+}
+
+impl HostInput {
+    pub fn spawn(raw: Option<&RawMode>) -> Self {
+        let saved = raw.map(|x| x.saved.clone());
+        let (sender, keys) = mpsc::channel();
+        thread::spawn(move || {
+            for key in io::stdin().lock().bytes() {
+                let Ok(key) = key else { break };
+                if key == 0x03 {
+                    if let Some(saved) = &saved {
+                        let _ = stty(&[saved]);
+                        std::process::exit(130);
+                    }
+                }
+                if sender.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            keys,
+            closed: false,
+        }
+    }
+
+    /// Raw mode when stdin is a terminal, keep the guard until the program
+    /// stopped
+    pub fn terminal() -> io::Result<(Self, Option<RawMode>)> {
+        let raw = if io::stdin().is_terminal() {
+            Some(RawMode::enable()?)
+        } else {
+            None
+        };
+        Ok((Self::spawn(raw.as_ref()), raw))
+    }
+}
+
+impl KeyInput for HostInput {
+    fn poll(&mut self) -> Option<u8> {
+        match self.keys.try_recv() {
+            Ok(key) => Some(key),
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            }
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[test]
+fn emu_runs_asm_output() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $0, r1
+    mov $5, acc
+loop:
+    inc r1
+    jne r1, &loop
+    push $0
+    call $square
+    hlt
+square:
+    mul r1, r1
+    mov acc, &result
+    mov $0, r1
+    ret
+data16 result = { $0 }",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let Machine { mut cpu, .. } = machine(&program.segments);
+    cpu.run();
+
+    let result = program.debug_info.symbol("result").unwrap().value;
+    assert_eq!(cpu.memory().get_u16(result), 25);
+    // r1 is restored from the frame on return
+    assert_eq!(cpu.register(Register::R1), 5);
+    assert_eq!(cpu.register(Register::Sp), 0xfffe);
+}
+
+#[test]
+fn emu_screen_output() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "mov $0xff48, &0x3000
+    mov $0x0169, &0x3001
+    mov $0x0221, &0x3012
+    hlt",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("screen.s", &parsed).unwrap();
+
+    let mut machine = machine(&program.segments);
+    machine.cpu.run();
+    assert_eq!(
+        machine.screen.borrow().render(Backend::Text),
+        format!("Hi\n  !\n{}", "\n".repeat(14))
+    );
+    // screen is a device, RAM under it is untouched
+    assert_eq!(machine.cpu.memory().get_u16(0x3000), 0);
+}
+
+#[test]
+fn emu_interrupts() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $vectors, &0x3106
+    int $1
+loop:
+    mov &ticks, acc
+    jne $3, &loop
+    hlt
+tick:
+    mov &ticks, r1
+    inc r1
+    mov r1, &ticks
+    rti
+data16 vectors = { $0, $tick }
+data16 ticks = { $0 }",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    // line 1 fires every 20 instructions like a timer would, `int $1` counts too
+    let Machine { mut cpu, .. } = machine(&program.segments);
+    let timer = cpu.interrupt_line(1);
+    for step in 1.. {
+        if step % 20 == 0 {
+            timer.raise();
+        }
+        if cpu.step() {
+            break;
+        }
+    }
+    assert_eq!(cpu.fault(), None);
+    let ticks = program.debug_info.symbol("ticks").unwrap().value;
+    assert_eq!(cpu.memory().get_u16(ticks), 3);
+    assert_eq!(cpu.register(Register::Sp), 0xfffe);
+
+    let int = cpu.decode(0x0005);
+    assert_eq!(disassemble(&int, &program.debug_info), "int $0x0001");
+    let tick = program.debug_info.symbol("tick").unwrap().value;
+    let rti = cpu.decode(tick + 10);
+    assert_eq!(disassemble(&rti, &program.debug_info), "rti");
+}
+
+#[test]
+fn emu_keyboard() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov &KEYBOARD_STATUS, r1
+    and r1, $1
+    jne $0, &key
+    mov &KEYBOARD_STATUS, r1
+    and r1, $4
+    jne $0, &done
+    jeq $0, &start
+key:
+    mov &KEYBOARD_DATA, r2
+    add r2, r3
+    mov acc, r3
+    inc r4
+    mov $0, acc
+    jeq $0, &start
+done:
+    hlt",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    let mut machine = machine(&program.segments);
+    machine
+        .keyboard
+        .borrow_mut()
+        .set_input(VecDeque::from(*b"ok"));
+    assert_eq!(machine.cpu.run_for(1000), vm::cpu::Exit::Halted);
+    assert_eq!(machine.cpu.register(Register::R3), (b'o' + b'k') as u16);
+    assert_eq!(machine.cpu.register(Register::R4), 2);
+}
+
+#[test]
+fn emu_timer() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $100, &TIMER_RELOAD
+    mov $3, &TIMER_CONTROL
+wait:
+    mov &TIMER_STATUS, acc
+    jeq $0, &wait
+    hlt",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+
+    // one-shot on cycles expires during the 10th pass and stops, every run
+    let mut machine = machine(&program.segments);
+    machine.cpu.run();
+    assert_eq!((machine.cpu.executed(), machine.cpu.cycles()), (23, 125));
+    assert_eq!(machine.timer.borrow().counter(), 0);
+    assert_eq!(machine.cpu.memory().get_u16(TIMER_START), 2);
+}
+
+#[test]
+fn emu_banks() {
+    let segments = [Segment::new(0xd000, vec![0x12, 0x34, 0x56])];
+    // RAM stays under the window unless banks are asked for
+    let machine = machine(&segments);
+    assert!(machine.banks.is_none());
+    assert_eq!(machine.cpu.memory().get_u16(0xd001), 0x3456);
+    assert_eq!(machine.cpu.memory().get_u16(BANK_SELECT), 0);
+
+    let mut machine = machine_with_banks(&segments, 2, 2);
+    let banks = machine.banks.clone().unwrap();
+    assert_eq!(banks.borrow().bank(0), Some(&[0x12, 0x34][..]));
+    let memory = machine.cpu.memory_mut();
+    memory.set_u16(BANK_SELECT, 1);
+    assert_eq!(memory.get_u16(0xd000), 0);
+    // past the bank size the window is RAM again
+    assert_eq!(memory.get_u8(0xd002), 0x56);
+    assert_eq!(banks.borrow().selected(), 1);
+}
@@ -0,0 +1,399 @@
+use std::fmt::Write;
+
+/// Bytes loaded at `address`, one per placed section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    pub fn new(address: u16, bytes: Vec<u8>) -> Self {
+        Self { address, bytes }
+    }
+
+    pub fn end(&self) -> u32 {
+        self.address as u32 + self.bytes.len() as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// Flat memory image starting at 0x0000, gaps are zero filled
+    Raw,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// Hex dump, `addr: bytes` per line
+    Hex,
+    /// C source snippet
+    CArray,
+    /// Rust source snippet
+    RustArray,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    InvalidRecord(String, usize),
+    WrongChecksum(usize),
+    AddressOverflow(u32),
+}
+
+type FormatRes<T> = Result<T, FormatError>;
+
+const RECORD_LEN: usize = 16;
+
+/// Flattens segments into image loaded from address 0
+pub fn flatten(segments: &[Segment]) -> Vec<u8> {
+    let end = segments.iter().map(Segment::end).max().unwrap_or(0);
+    let mut image = vec![0u8; end as usize];
+    for segment in segments {
+        let start = segment.address as usize;
+        image[start..start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+    }
+    image
+}
+
+pub fn write(format: Format, segments: &[Segment]) -> Vec<u8> {
+    match format {
+        Format::Raw => flatten(segments),
+        Format::Ihex => write_ihex(segments).into_bytes(),
+        Format::Srec => write_srec(segments).into_bytes(),
+        Format::Hex => write_hex(segments).into_bytes(),
+        Format::CArray => write_c_array(segments).into_bytes(),
+        Format::RustArray => write_rust_array(segments).into_bytes(),
+    }
+}
+
+pub fn read(format: Format, input: &[u8]) -> FormatRes<Vec<Segment>> {
+    match format {
+        Format::Raw => {
+            let mut segments = Vec::new();
+            push_bytes(&mut segments, 0, input)?;
+            Ok(segments)
+        }
+        Format::Ihex => read_ihex(&String::from_utf8_lossy(input)),
+        Format::Srec => read_srec(&String::from_utf8_lossy(input)),
+        Format::Hex => read_hex(&String::from_utf8_lossy(input)),
+        Format::CArray | Format::RustArray => read_array(&String::from_utf8_lossy(input)),
+    }
+}
+
+/// Guesses format by file contents, anything unknown is a raw image
+pub fn detect(input: &[u8]) -> Format {
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => text.trim_start(),
+        Err(_) => return Format::Raw,
+    };
+
+    if text.starts_with(':') {
+        Format::Ihex
+    } else if text.starts_with("S0") || text.starts_with("S1") {
+        Format::Srec
+    } else if text.contains("unsigned char segment_") {
+        Format::CArray
+    } else if text.contains("const SEGMENT_") {
+        Format::RustArray
+    } else if text
+        .lines()
+        .next()
+        .and_then(|x| x.split_once(':'))
+        .is_some_and(|(addr, _)| addr.len() == 4 && u16::from_str_radix(addr, 16).is_ok())
+    {
+        Format::Hex
+    } else {
+        Format::Raw
+    }
+}
+
+pub fn load(input: &[u8]) -> FormatRes<Vec<Segment>> {
+    read(detect(input), input)
+}
+
+fn records(segments: &[Segment]) -> impl Iterator<Item = (u16, &[u8])> {
+    segments.iter().flat_map(|segment| {
+        segment
+            .bytes
+            .chunks(RECORD_LEN)
+            .enumerate()
+            .map(|(i, chunk)| (segment.address.wrapping_add((i * RECORD_LEN) as u16), chunk))
+    })
+}
+
+fn write_ihex(segments: &[Segment]) -> String {
+    let mut res = String::new();
+    for (addr, chunk) in records(segments) {
+        let mut record = vec![chunk.len() as u8, (addr >> 8) as u8, addr as u8, 0x00];
+        record.extend_from_slice(chunk);
+        let checksum = record
+            .iter()
+            .fold(0u8, |acc, x| acc.wrapping_add(*x))
+            .wrapping_neg();
+        record.push(checksum);
+        writeln!(res, ":{}", to_hex(&record)).unwrap();
+    }
+    res.push_str(":00000001FF\n");
+    res
+}
+
+fn read_ihex(input: &str) -> FormatRes<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .and_then(from_hex)
+            .ok_or_else(|| FormatError::InvalidRecord(line.into(), i + 1))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(FormatError::InvalidRecord(line.into(), i + 1));
+        }
+        if record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) != 0 {
+            return Err(FormatError::WrongChecksum(i + 1));
+        }
+
+        let addr = u16::from_be_bytes([record[1], record[2]]);
+        match record[3] {
+            0x00 => push_bytes(&mut segments, addr, &record[4..record.len() - 1])?,
+            0x01 => break,
+            _ => return Err(FormatError::InvalidRecord(line.into(), i + 1)),
+        }
+    }
+    Ok(segments)
+}
+
+fn write_srec(segments: &[Segment]) -> String {
+    let mut res = String::new();
+    let srec_line = |kind: &str, addr: u16, data: &[u8]| {
+        let mut record = vec![(data.len() + 3) as u8, (addr >> 8) as u8, addr as u8];
+        record.extend_from_slice(data);
+        let checksum = !record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        record.push(checksum);
+        format!("{}{}\n", kind, to_hex(&record))
+    };
+
+    res.push_str(&srec_line("S0", 0, b"16bit"));
+    for (addr, chunk) in records(segments) {
+        res.push_str(&srec_line("S1", addr, chunk));
+    }
+    res.push_str(&srec_line("S9", 0, &[]));
+    res
+}
+
+fn read_srec(input: &str) -> FormatRes<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || FormatError::InvalidRecord(line.into(), i + 1);
+        if line.len() < 2 || !line.starts_with('S') {
+            return Err(invalid());
+        }
+        let (kind, record) = line.split_at(2);
+        let record = from_hex(record).ok_or_else(invalid)?;
+        if record.len() < 4 || record.len() != record[0] as usize + 1 {
+            return Err(invalid());
+        }
+        if record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) != 0xff {
+            return Err(FormatError::WrongChecksum(i + 1));
+        }
+
+        let addr = u16::from_be_bytes([record[1], record[2]]);
+        match kind {
+            "S0" | "S5" => continue,
+            "S1" => push_bytes(&mut segments, addr, &record[3..record.len() - 1])?,
+            "S9" => break,
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(segments)
+}
+
+fn write_hex(segments: &[Segment]) -> String {
+    let mut res = String::new();
+    for (addr, chunk) in records(segments) {
+        let bytes: Vec<_> = chunk.iter().map(|x| format!("{:02x}", x)).collect();
+        writeln!(res, "{:04x}: {}", addr, bytes.join(" ")).unwrap();
+    }
+    res
+}
+
+fn read_hex(input: &str) -> FormatRes<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || FormatError::InvalidRecord(line.into(), i + 1);
+        let (addr, bytes) = line.split_once(':').ok_or_else(invalid)?;
+        let addr = u16::from_str_radix(addr.trim(), 16).map_err(|_| invalid())?;
+        let bytes = bytes
+            .split_whitespace()
+            .map(|x| u8::from_str_radix(x, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        push_bytes(&mut segments, addr, &bytes)?;
+    }
+    Ok(segments)
+}
+
+fn array_body(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    for chunk in bytes.chunks(RECORD_LEN) {
+        let line: Vec<_> = chunk.iter().map(|x| format!("0x{:02x}", x)).collect();
+        writeln!(res, "    {},", line.join(", ")).unwrap();
+    }
+    res
+}
+
+fn write_c_array(segments: &[Segment]) -> String {
+    let mut res = String::from("/* generated by asm, one array per segment */\n");
+    for segment in segments {
+        writeln!(
+            res,
+            "\n/* loaded at {:#06x} */\nconst unsigned char segment_{:04x}[{}] = {{\n{}}};",
+            segment.address,
+            segment.address,
+            segment.bytes.len(),
+            array_body(&segment.bytes)
+        )
+        .unwrap();
+    }
+    res
+}
+
+fn write_rust_array(segments: &[Segment]) -> String {
+    let mut res = String::from("// generated by asm, one array per segment\n");
+    for segment in segments {
+        writeln!(
+            res,
+            "\n/// Loaded at {:#06x}\npub const SEGMENT_{:04X}: [u8; {}] = [\n{}];",
+            segment.address,
+            segment.address,
+            segment.bytes.len(),
+            array_body(&segment.bytes)
+        )
+        .unwrap();
+    }
+    res
+}
+
+/// Reads both C and Rust snippets, address is taken from array name
+fn read_array(input: &str) -> FormatRes<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut current: Option<(u16, Vec<u8>)> = None;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        let invalid = || FormatError::InvalidRecord(line.into(), i + 1);
+
+        if let Some(pos) = line.find("segment_").or_else(|| line.find("SEGMENT_")) {
+            let name = &line[pos + "segment_".len()..];
+            let addr = name.get(..4).ok_or_else(invalid)?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| invalid())?;
+            current = Some((addr, Vec::new()));
+            continue;
+        }
+
+        let Some((addr, bytes)) = current.as_mut() else {
+            continue;
+        };
+        if line.starts_with('}') || line.starts_with(']') {
+            push_bytes(&mut segments, *addr, bytes)?;
+            current = None;
+            continue;
+        }
+        for value in line.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let value = value.strip_prefix("0x").ok_or_else(invalid)?;
+            bytes.push(u8::from_str_radix(value, 16).map_err(|_| invalid())?);
+        }
+    }
+    Ok(segments)
+}
+
+/// Appends bytes to the last segment when contiguous, otherwise starts new one
+fn push_bytes(segments: &mut Vec<Segment>, addr: u16, bytes: &[u8]) -> FormatRes<()> {
+    let end = addr as u32 + bytes.len() as u32;
+    if end > 0x10000 {
+        return Err(FormatError::AddressOverflow(end));
+    }
+    match segments.last_mut() {
+        Some(last) if last.end() == addr as u32 => last.bytes.extend_from_slice(bytes),
+        _ => segments.push(Segment::new(addr, bytes.to_vec())),
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+fn from_hex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[test]
+fn formats_roundtrip() {
+    let bytes: Vec<u8> = (0..20).collect();
+    let segments = vec![
+        Segment::new(0x0000, bytes.clone()),
+        Segment::new(0x3000, vec![0xff, 0x41]),
+    ];
+
+    for format in [
+        Format::Ihex,
+        Format::Srec,
+        Format::Hex,
+        Format::CArray,
+        Format::RustArray,
+    ] {
+        let written = write(format, &segments);
+        assert_eq!(detect(&written), format);
+        assert_eq!(read(format, &written).unwrap(), segments, "{:?}", format);
+    }
+
+    let raw = write(Format::Raw, &segments);
+    assert_eq!(raw.len(), 0x3002);
+    assert_eq!(&raw[..20], bytes.as_slice());
+    assert_eq!(&raw[0x3000..], &[0xff, 0x41]);
+    assert_eq!(load(&raw).unwrap(), vec![Segment::new(0, raw.clone())]);
+}
+
+#[test]
+fn formats_records() {
+    let segments = vec![Segment::new(0x0100, vec![0x10, 0x00, 0x05, 0x02, 0xff])];
+
+    let ihex = String::from_utf8(write(Format::Ihex, &segments)).unwrap();
+    assert_eq!(ihex, ":0501000010000502FFE4\n:00000001FF\n");
+
+    let srec = String::from_utf8(write(Format::Srec, &segments)).unwrap();
+    assert_eq!(
+        srec,
+        "S0080000313662697451\nS108010010000502FFE0\nS9030000FC\n"
+    );
+
+    let broken = ihex.replace("E4", "E5");
+    assert_eq!(
+        read(Format::Ihex, broken.as_bytes()),
+        Err(FormatError::WrongChecksum(1))
+    );
+
+    // raw images have to fit the address space like records do
+    assert_eq!(read(Format::Raw, &[0; 0x10000]).unwrap()[0].end(), 0x10000);
+    assert_eq!(
+        read(Format::Raw, &[0; 0x10001]),
+        Err(FormatError::AddressOverflow(0x10001))
+    );
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    io::{self, BufReader, Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use vm::{
+    device::MemoryMappedDevice,
+    fault::Fault,
+    mapper::{WatchKind, Watchpoint},
+    registers::Register,
+};
+
+use crate::emu::Machine;
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+#[cfg(test)]
+use std::io::Cursor;
+
+/// Instructions `c` runs between looks for an interrupt from the client
+const POLL_STEPS: u64 = 1024;
+
+/// GDB remote serial protocol server over any byte stream, registers are
+/// numbered like [`Register::ALL`] and sent big-endian like the VM stores them.
+/// `c` runs until a breakpoint, a watchpoint, `hlt`, a fault or the client
+/// sends an interrupt (0x03) while serving.
+pub struct GdbStub {
+    machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    // (kind, addr, len) -> mapper watchpoint id
+    watchpoints: HashMap<(WatchKind, u16, u16), usize>,
+    next_watchpoint: usize,
+    halted: bool,
+    done: bool,
+    // bytes from the client while serving, read on another thread so `c`
+    // can look for interrupts
+    input: Option<Receiver<u8>>,
+    // bytes that came in while running, ahead of `input`
+    pending: VecDeque<u8>,
+}
+
+impl GdbStub {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: HashMap::new(),
+            next_watchpoint: 1,
+            halted: false,
+            done: false,
+            input: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Answers packets until the client detaches, kills or closes the stream
+    pub fn serve(
+        &mut self,
+        input: impl Read + Send + 'static,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in BufReader::new(input).bytes() {
+                let Ok(byte) = byte else { break };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        self.input = Some(receiver);
+        while !self.done {
+            let (pending, input) = (&mut self.pending, &self.input);
+            let mut bytes = std::iter::from_fn(|| {
+                let byte = pending.pop_front();
+                byte.or_else(|| input.as_ref()?.recv().ok()).map(Ok)
+            });
+            let Some(packet) = read_packet(&mut bytes, &mut output)? else {
+                break;
+            };
+            if let Some(reply) = self.handle(&packet) {
+                write_packet(&mut output, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to a single packet body, `None` when protocol expects no answer
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        let reply = match cmd {
+            "?" => self.stop_reply(None),
+            "g" => Register::ALL
+                .iter()
+                .map(|x| format!("{:04x}", self.machine.cpu.register(*x)))
+                .collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == Register::ALL.len() * 2 => {
+                    for (reg, value) in Register::ALL.iter().zip(bytes.chunks(2)) {
+                        let value = u16::from_be_bytes([value[0], value[1]]);
+                        self.machine.cpu.set_register(*reg, value);
+                    }
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            "p" => match register(args) {
+                Some(reg) => format!("{:04x}", self.machine.cpu.register(reg)),
+                None => "E01".into(),
+            },
+            "P" => {
+                let value = args.split_once('=').and_then(|(reg, value)| {
+                    let bytes = decode_hex(value).filter(|x| x.len() == 2)?;
+                    Some((register(reg)?, u16::from_be_bytes([bytes[0], bytes[1]])))
+                });
+                match value {
+                    Some((reg, value)) => {
+                        self.machine.cpu.set_register(reg, value);
+                        "OK".into()
+                    }
+                    None => "E01".into(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let memory = self.machine.cpu.memory();
+                    (0..len)
+                        .map(|i| format!("{:02x}", memory.peek_u8(addr.wrapping_add(i))))
+                        .collect()
+                }
+                None => "E01".into(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = decode_hex(data).filter(|x| x.len() == len as usize)?;
+                    // no wrapping around to 0x0000
+                    (addr as usize + bytes.len() <= 0x10000).then_some((addr, bytes))
+                });
+                match write {
+                    Some((addr, bytes)) => {
+                        self.machine.cpu.memory_mut().load(addr, &bytes);
+                        "OK".into()
+                    }
+                    None => "E01".into(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(cmd == "Z", args),
+            "s" => self.resume(true),
+            "c" => self.resume(false),
+            "H" => "OK".into(),
+            "q" => self.query(args),
+            "D" => {
+                self.done = true;
+                "OK".into()
+            }
+            "k" => {
+                self.done = true;
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=1000;qXfer:features:read+".into();
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',').and_then(|(offset, len)| {
+                let offset = usize::from_str_radix(offset, 16).ok()?;
+                Some((offset, usize::from_str_radix(len, 16).ok()?))
+            }) else {
+                return "E01".into();
+            };
+            let xml = target_xml();
+            let chunk = xml.get(offset..).unwrap_or_default();
+            return match chunk.len() > len {
+                true => format!("m{}", &chunk[..len]),
+                false => format!("l{}", chunk),
+            };
+        }
+        match query {
+            "Attached" => "1".into(),
+            "C" => "QC1".into(),
+            "fThreadInfo" => "m1".into(),
+            "sThreadInfo" => "l".into(),
+            _ => String::new(),
+        }
+    }
+
+    /// `Z0`/`Z1` code breakpoints, `Z2`..`Z4` write, read and access watchpoints
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return "E01".into();
+        };
+        let (Ok(addr), Ok(len)) = (u16::from_str_radix(addr, 16), u16::from_str_radix(len, 16))
+        else {
+            return "E01".into();
+        };
+
+        let kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".into();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let key = (kind, addr, len.max(1));
+        let memory = self.machine.cpu.memory_mut();
+        if insert {
+            let id = self.next_watchpoint;
+            self.next_watchpoint += 1;
+            memory.add_watchpoint(Watchpoint {
+                id,
+                start: addr,
+                end: addr.saturating_add(key.2 - 1),
+                kind,
+            });
+            self.watchpoints.insert(key, id);
+        } else if let Some(id) = self.watchpoints.remove(&key) {
+            memory.remove_watchpoint(id);
+        }
+        "OK".into()
+    }
+
+    fn resume(&mut self, single: bool) -> String {
+        if self.halted {
+            return self.exit_reply();
+        }
+        loop {
+            if self.machine.cpu.step() {
+                self.halted = true;
+                return self.exit_reply();
+            }
+            if let Some(hit) = self.machine.cpu.memory().take_hits().into_iter().next() {
+                let kind = self
+                    .machine
+                    .cpu
+                    .memory()
+                    .watchpoints()
+                    .iter()
+                    .find(|x| x.id == hit.id)
+                    .map_or(WatchKind::Access, |x| x.kind);
+                return self.stop_reply(Some((kind, hit.addr)));
+            }
+            let ip = self.machine.cpu.register(Register::Ip);
+            if single || self.breakpoints.contains(&ip) {
+                return self.stop_reply(None);
+            }
+            if self.machine.cpu.executed().is_multiple_of(POLL_STEPS) && self.interrupted() {
+                return "S02".into();
+            }
+        }
+    }
+
+    /// Whether the client sent an interrupt, keeps other bytes for later
+    fn interrupted(&mut self) -> bool {
+        let Some(input) = &self.input else {
+            return false;
+        };
+        while let Ok(byte) = input.try_recv() {
+            if byte == 0x03 {
+                return true;
+            }
+            self.pending.push_back(byte);
+        }
+        false
+    }
+
+    /// Exit after `hlt`, faults stop with SIGILL for bad instructions and
+    /// SIGSEGV for the rest, so the program can still be inspected
+    fn exit_reply(&self) -> String {
+        match self.machine.cpu.fault() {
+            Some(Fault::InvalidOpcode(..) | Fault::InvalidRegister(..)) => "S04".into(),
+            Some(_) => "S0B".into(),
+            None => "W00".into(),
+        }
+    }
+
+    fn stop_reply(&self, watch: Option<(WatchKind, u16)>) -> String {
+        if self.halted {
+            return self.exit_reply();
+        }
+        match watch {
+            Some((kind, addr)) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:04x};", name, addr)
+            }
+            None => "S05".into(),
+        }
+    }
+}
+
+fn target_xml() -> String {
+    let regs: String = Register::ALL
+        .iter()
+        .map(|reg| {
+            let kind = match reg {
+                Register::Ip => "code_ptr",
+                Register::Sp | Register::Fp => "data_ptr",
+                _ => "uint16",
+            };
+            format!(
+                "<reg name=\"{}\" bitsize=\"16\" type=\"{}\"/>",
+                reg.name(),
+                kind
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.16bit.core\">{}</feature></target>",
+        regs
+    )
+}
+
+fn register(index: &str) -> Option<Register> {
+    let index = usize::from_str_radix(index, 16).ok()?;
+    Register::ALL.get(index).copied()
+}
+
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn decode_hex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |acc, x| acc.wrapping_add(x))
+}
+
+/// Next `$data#cs` packet body, acks it. Stray acks and interrupts are skipped.
+fn read_packet(
+    input: &mut impl Iterator<Item = io::Result<u8>>,
+    output: &mut impl Write,
+) -> io::Result<Option<String>> {
+    loop {
+        match input.next().transpose()? {
+            None => return Ok(None),
+            Some(b'$') => {}
+            Some(_) => continue,
+        }
+        let mut data = Vec::new();
+        loop {
+            match input.next().transpose()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+        let mut sum = [0u8; 2];
+        for byte in &mut sum {
+            match input.next().transpose()? {
+                None => return Ok(None),
+                Some(x) => *byte = x,
+            }
+        }
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        if expected == Some(checksum(&data)) {
+            output.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        output.write_all(b"-")?;
+        output.flush()?;
+    }
+}
+
+fn write_packet(output: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(output, "${}#{:02x}", data, checksum(data))?;
+    output.flush()
+}
+
+#[cfg(test)]
+fn packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data))
+}
+
+#[test]
+fn gdbstub_session() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $4, acc
+loop:
+    inc r1
+    mov r1, &slot
+    jne r1, &loop
+    hlt
+data16 slot = { $0 }",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut stub = GdbStub::new(machine(&program.segments));
+
+    // client acks every reply, the first packet has a broken checksum and is nacked
+    let requests = [
+        "qSupported:multiprocess+",
+        "?",
+        "s",
+        "g",
+        "p2",
+        "P2=0002",
+        "Z0,4,1",
+        "c",
+        "p2",
+        "z0,4,1",
+        "Z2,f,2",
+        "c",
+        "m0f,2",
+        "M0f,2:0100",
+        "m0f,2",
+        "Mffff,2:0102",
+        "z2,f,2",
+        "qXfer:features:read:target.xml:0,10",
+        "vMustReplyEmpty",
+        "c",
+        "s",
+        "k",
+        "?",
+    ];
+    let mut input = String::from("$?#00");
+    for request in requests {
+        input += &packet(request);
+        input += "+";
+    }
+    let mut output = Vec::new();
+    stub.serve(Cursor::new(input), &mut output).unwrap();
+
+    let replies = [
+        "PacketSize=1000;qXfer:features:read+",
+        "S05",
+        "S05",
+        "0004000400000000000000000000000000000000fffefffe",
+        "0000",
+        "OK",
+        "OK",
+        "S05",
+        "0003",
+        "OK",
+        "OK",
+        "T05watch:000f;",
+        "0004",
+        "OK",
+        "0100",
+        "E01",
+        "OK",
+        "m<?xml version=\"1",
+        "",
+        "W00",
+        "W00",
+    ];
+    let mut expected = String::from("-");
+    for reply in replies {
+        expected += "+";
+        expected += &packet(reply);
+    }
+    // `k` is acked but gets no reply, `?` after it is never read
+    expected += "+";
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert_eq!(stub.machine().cpu.memory().get_u16(0x000f), 0x0100);
+}
+
+#[test]
+fn gdbstub_faults() {
+    let reply = |source: &str| {
+        let parsed = InstructionParser::new().parse_lines(source).unwrap();
+        let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+        let mut stub = GdbStub::new(machine(&program.segments));
+        let mut output = Vec::new();
+        let input = packet("c") + "+" + &packet("?") + "+";
+        stub.serve(Cursor::new(input), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    // faulted programs stay inspectable, so they stop instead of exiting
+    let expected = format!("+{}+{}", packet("S0B"), packet("S0B"));
+    assert_eq!(reply("inc r1\nret"), expected);
+    let expected = format!("+{}+{}", packet("S04"), packet("S04"));
+    assert_eq!(reply("inc r1"), expected);
+}
+
+#[test]
+fn gdbstub_interrupt() {
+    let parsed = InstructionParser::new()
+        .parse_lines("start:\n    mov $0, acc\nloop:\n    jne $1, &loop")
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut stub = GdbStub::new(machine(&program.segments));
+    let mut output = Vec::new();
+    let input = packet("c") + "\x03+" + &packet("?") + "+" + &packet("k");
+    stub.serve(Cursor::new(input), &mut output).unwrap();
+    let expected = format!("+{}+{}+", packet("S02"), packet("S05"));
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert!(stub.machine().cpu.executed().is_multiple_of(POLL_STEPS));
+}
@@ -0,0 +1,145 @@
+/// Address space of the VM as [`crate::emu::machine`] maps it, `bank` is a
+/// device window with [`crate::emu::machine_with_banks`]
+pub const VM_LAYOUT: &str = include_str!("../layouts/vm.layout");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    /// Sections with the same name are placed here
+    Memory,
+    /// Memory mapped device window, nothing can be loaded into it
+    Device,
+    /// Reserved for the stack growing down from 0xfffe
+    Stack,
+}
+
+/// Inclusive `start..=end` address range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutError {
+    InvalidLine(String, u32),
+    InvalidNumber(String, u32),
+    EmptyRegion(String, u32),
+    RegionOverlap(String, u32),
+}
+
+type LayoutRes<T> = Result<T, LayoutError>;
+
+/// Memory layout description used by the linker
+///
+/// One region per line: `<name> <start> <end> [device|stack]`, `#` starts comment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub regions: Vec<Region>,
+}
+
+impl Region {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+
+    /// Does `start..end` (end exclusive) touch this region
+    pub fn intersects(&self, start: u16, end: u32) -> bool {
+        (start as u32) <= self.end as u32 && end > self.start as u32
+    }
+}
+
+impl Layout {
+    pub fn vm() -> Self {
+        Self::parse(VM_LAYOUT).expect("builtin layout is valid")
+    }
+
+    pub fn parse(input: &str) -> LayoutRes<Self> {
+        let mut regions: Vec<Region> = Vec::new();
+        for (line, text) in input.lines().enumerate() {
+            let line = line as u32 + 1;
+            let text = text.split('#').next().unwrap_or_default();
+            let words: Vec<_> = text.split_whitespace().collect();
+            let (name, start, end, kind) = match words.as_slice() {
+                [] => continue,
+                [name, start, end] => (name, start, end, RegionKind::Memory),
+                [name, start, end, "device"] => (name, start, end, RegionKind::Device),
+                [name, start, end, "stack"] => (name, start, end, RegionKind::Stack),
+                _ => return Err(LayoutError::InvalidLine(text.trim().into(), line)),
+            };
+
+            let region = Region {
+                name: name.to_string(),
+                start: Self::number(start, line)?,
+                end: Self::number(end, line)?,
+                kind,
+            };
+            if region.start > region.end {
+                return Err(LayoutError::EmptyRegion(region.name, line));
+            }
+            if regions
+                .iter()
+                .any(|x| x.intersects(region.start, region.end as u32 + 1))
+            {
+                return Err(LayoutError::RegionOverlap(region.name, line));
+            }
+            regions.push(region);
+        }
+        Ok(Self { regions })
+    }
+
+    fn number(word: &str, line: u32) -> LayoutRes<u16> {
+        let res = match word.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+        res.map_err(|_| LayoutError::InvalidNumber(word.into(), line))
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|x| x.kind == RegionKind::Memory && x.name == name)
+    }
+
+    /// Device and stack windows, nothing may be loaded there
+    pub fn reserved(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(|x| x.kind != RegionKind::Memory)
+    }
+}
+
+#[test]
+fn layout_parse() {
+    let layout = Layout::vm();
+    assert_eq!(layout.regions.len(), 6);
+    assert_eq!(layout.region("data").map(|x| x.start), Some(0x3200));
+    assert_eq!(layout.region("screen"), None);
+    let reserved: Vec<_> = layout
+        .reserved()
+        .map(|x| (x.name.as_str(), x.kind))
+        .collect();
+    assert_eq!(
+        reserved,
+        vec![
+            ("screen", RegionKind::Device),
+            ("io", RegionKind::Device),
+            ("bank", RegionKind::Device),
+            ("stack", RegionKind::Stack)
+        ]
+    );
+    assert!(layout.regions[5].contains(0xfffe));
+
+    assert_eq!(
+        Layout::parse("code 0 0x10\n\ndata 0x10 0x20"),
+        Err(LayoutError::RegionOverlap("data".into(), 3))
+    );
+    assert_eq!(
+        Layout::parse("code 0 0x10 rom"),
+        Err(LayoutError::InvalidLine("code 0 0x10 rom".into(), 1))
+    );
+    assert_eq!(
+        Layout::parse("code 0 0xfffff"),
+        Err(LayoutError::InvalidNumber("0xfffff".into(), 1))
+    );
+}
@@ -9,7 +9,7 @@ pub struct Token {
     pub len: u32,
 }
 
-impl<'a> fmt::Display for Token {
+impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.kind.fmt(f)
     }
@@ -34,8 +34,12 @@ pub enum LexerError {
 
 type Result<T> = std::result::Result<T, LexerError>;
 
+pub fn is_valid_id_start(c: &char) -> bool {
+    c.is_alphabetic() || *c == '_'
+}
+
 pub fn is_valid_id_continue(c: &char) -> bool {
-    c.is_alphabetic() || c.is_numeric()
+    c.is_alphabetic() || c.is_numeric() || *c == '_' || *c == '.'
 }
 
 pub fn tokenize(input: &str) -> impl Iterator<Item = TokenEnum> + '_ {
@@ -85,29 +89,44 @@ impl<'a> Cursor<'a> {
         // println!("to parse: {:?}", first_char);
 
         match first_char {
+            Some('&') if self.follows('&') => Token::new(TokenEnum::And, 2),
             Some('&') => self.parse_mem(),
+            Some('|') if self.follows('|') => Token::new(TokenEnum::Or, 2),
             Some('$') => self.parse_lit(),
             Some(',') => Token::new(TokenEnum::Comma, 1),
             Some('(') => Token::new(TokenEnum::OpenParen, 1),
             Some(')') => Token::new(TokenEnum::CloseParen, 1),
             Some('[') => Token::new(TokenEnum::OpenBracket, 1),
             Some(']') => Token::new(TokenEnum::CloseBracket, 1),
+            Some('!') if self.follows('=') => Token::new(TokenEnum::NotEqual, 2),
             Some('!') => Token::new(TokenEnum::Neg, 1),
             Some(':') => Token::new(TokenEnum::Colon, 1),
             Some('?') => Token::new(TokenEnum::Question, 1),
-            Some(';') => Token::new(TokenEnum::Semicolon, 1),
+            Some(';') => self.eat_comment(),
             Some('+') => Token::new(TokenEnum::Plus, 1),
             Some('*') => Token::new(TokenEnum::Star, 1),
             Some('-') => Token::new(TokenEnum::Minus, 1),
+            Some('=') if self.follows('=') => Token::new(TokenEnum::DoubleEqual, 2),
+            Some('=') => Token::new(TokenEnum::Equal, 1),
+            Some('<') if self.follows('=') => Token::new(TokenEnum::LessEqual, 2),
+            Some('<') => Token::new(TokenEnum::Less, 1),
+            Some('>') if self.follows('=') => Token::new(TokenEnum::GreaterEqual, 2),
+            Some('>') => Token::new(TokenEnum::Greater, 1),
+            Some('{') => Token::new(TokenEnum::OpenBrace, 1),
+            Some('}') => Token::new(TokenEnum::CloseBrace, 1),
             Some('\n') => self.parse_newline(),
             Some(x) if x.is_whitespace() => self.eat_whitespace(),
-            Some(c) if c.is_alphabetic() => self.parse_ident(c),
+            Some(c) if is_valid_id_start(&c) => self.parse_ident(c),
             None => Token::new(TokenEnum::EOF, 0),
-            // XXX: dunno what can happen
-            _ => unimplemented!(),
+            _ => Token::new(TokenEnum::InvalidIdent, 1),
         }
     }
 
+    /// Consumes `c` if it is next, for two char operators
+    fn follows(&mut self, c: char) -> bool {
+        self.cursor.next_if_eq(&c).is_some()
+    }
+
     pub fn parse_newline(&mut self) -> Token {
         let mut len = 1;
         if self.cursor.peek() == Some(&'\t') {
@@ -120,21 +139,21 @@ impl<'a> Cursor<'a> {
     pub fn parse_hex(&mut self) -> Result<(u16, u32)> {
         let mut hex = String::new();
         while let Some(x) = self.cursor.peek() {
-            if x.is_digit(16) || *x == 'x' {
+            if x.is_ascii_hexdigit() || *x == 'x' {
                 hex.push(self.cursor.next().unwrap());
             } else {
                 break;
             }
         }
-        if hex.starts_with("0x") {
+        let value = if hex.starts_with("0x") {
             let without_prefix = hex.trim_start_matches("0x");
-            Ok((
-                u16::from_str_radix(without_prefix, 16).unwrap(),
-                hex.len() as u32,
-            ))
+            u16::from_str_radix(without_prefix, 16)
         } else {
-            Ok((hex.parse::<u16>().unwrap(), hex.len() as u32))
-        }
+            hex.parse::<u16>()
+        };
+        value
+            .map(|x| (x, hex.len() as u32))
+            .map_err(|_| LexerError::WrongHexVal)
     }
 
     fn parse_mem(&mut self) -> Token {
@@ -152,16 +171,34 @@ impl<'a> Cursor<'a> {
     }
 
     fn parse_lit(&mut self) -> Token {
+        if let Some(ident) = self.try_parse_ident() {
+            let ident_inner = ident.kind.to_string();
+            let len = (ident_inner.len() + 1) as u32;
+            return Token::new(TokenEnum::LitSym(ident_inner), len);
+        }
+
         match self.parse_hex() {
             Ok((x, size)) => Token::new(TokenEnum::Lit(x), size + 1),
             Err(_) => Token::new(TokenEnum::InvalidIdent, 0),
         }
     }
 
+    fn eat_comment(&mut self) -> Token {
+        let mut len = 1;
+        while let Some(c) = self.cursor.peek() {
+            if *c == '\n' {
+                break;
+            }
+            self.cursor.next();
+            len += 1;
+        }
+        Token::new(TokenEnum::Semicolon, len)
+    }
+
     fn eat_whitespace(&mut self) -> Token {
         let mut len = 1;
         while let Some(c) = self.cursor.peek() {
-            if c.is_whitespace() {
+            if c.is_whitespace() && *c != '\n' {
                 self.cursor.next();
                 len += 1;
             } else {
@@ -187,7 +224,7 @@ impl<'a> Cursor<'a> {
 
     fn try_parse_ident(&mut self) -> Option<Token> {
         match self.cursor.peek() {
-            Some(c) if !c.is_numeric() => {
+            Some(c) if is_valid_id_start(c) => {
                 let first_char = self.cursor.next().unwrap();
                 Some(self.parse_ident(first_char))
             }
@@ -290,3 +327,37 @@ fn parse_reg_ref() {
         ]
     )
 }
+
+#[test]
+fn parse_operators() {
+    let tokens: Vec<_> = tokenize_expr("acc == $10 && r1 >= &0x3 || !r2 != $1 < $2 > $3 <= $4")
+        .map(|x| x.kind)
+        .collect();
+    assert_eq!(
+        tokens,
+        vec![
+            TokenEnum::Ident("acc".into()),
+            TokenEnum::DoubleEqual,
+            TokenEnum::Lit(10),
+            TokenEnum::And,
+            TokenEnum::Ident("r1".into()),
+            TokenEnum::GreaterEqual,
+            TokenEnum::Mem(3),
+            TokenEnum::Or,
+            TokenEnum::Neg,
+            TokenEnum::Ident("r2".into()),
+            TokenEnum::NotEqual,
+            TokenEnum::Lit(1),
+            TokenEnum::Less,
+            TokenEnum::Lit(2),
+            TokenEnum::Greater,
+            TokenEnum::Lit(3),
+            TokenEnum::LessEqual,
+            TokenEnum::Lit(4),
+        ]
+    );
+
+    // stray chars and empty numbers do not panic
+    let tokens: Vec<_> = tokenize_expr("% &").map(|x| x.kind).collect();
+    assert_eq!(tokens, vec![TokenEnum::InvalidIdent, TokenEnum::InvalidIdent]);
+}
@@ -0,0 +1,20 @@
+pub mod archive;
+pub mod ast;
+pub mod codegen;
+pub mod common;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod debuginfo;
+pub mod emu;
+pub mod formats;
+pub mod gdbstub;
+pub mod instructions;
+pub mod layout;
+pub mod lexer;
+pub mod linker;
+pub mod object;
+pub mod parse;
+pub mod profile;
+pub mod run;
+pub mod trace;
@@ -0,0 +1,585 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    archive::Archive,
+    codegen::Program,
+    debuginfo::{DebugInfo, LineEntry, SourceFile, Structure, Symbol},
+    formats::Segment,
+    layout::{Layout, RegionKind},
+    object::{Object, ObjectSymbol, Section, Target},
+};
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, parse::InstructionParser};
+
+/// Source position a link error points at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    /// Name, first definition, second definition
+    DuplicateSymbol(String, Location, Location),
+    /// Name, where it was referenced
+    UndefinedSymbol(String, Location),
+    /// Section name and address where it runs into another one
+    SectionOverlap(String, u16),
+    /// Relocation points outside of its section
+    InvalidRelocation(String, u16),
+    /// File and section name the layout has no region for
+    NoRegion(String, String),
+    /// Region and section name that does not fit in it anymore
+    RegionOverflow(String, String),
+    /// Section, device window and address where section is loaded
+    DeviceOverlap(String, String, u16),
+    /// Section and address where it runs into stack reserve
+    StackCollision(String, u16),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol(name, first, second) => write!(
+                f,
+                "{}: duplicate symbol `{}`, first defined at {}",
+                second, name, first
+            ),
+            LinkError::UndefinedSymbol(name, at) => {
+                write!(f, "{}: undefined symbol `{}`", at, name)
+            }
+            LinkError::SectionOverlap(name, address) => write!(
+                f,
+                "section `{}` at {:#06x} overlaps another section",
+                name, address
+            ),
+            LinkError::InvalidRelocation(file, offset) => {
+                write!(
+                    f,
+                    "{}: relocation at {:#06x} is out of bounds",
+                    file, offset
+                )
+            }
+            LinkError::NoRegion(file, name) => {
+                write!(f, "{}: no region for section `{}` in layout", file, name)
+            }
+            LinkError::RegionOverflow(region, name) => {
+                write!(f, "section `{}` does not fit in region `{}`", name, region)
+            }
+            LinkError::DeviceOverlap(name, region, address) => write!(
+                f,
+                "section `{}` at {:#06x} overlaps device window `{}`",
+                name, address, region
+            ),
+            LinkError::StackCollision(name, address) => write!(
+                f,
+                "section `{}` at {:#06x} collides with stack reserve",
+                name, address
+            ),
+        }
+    }
+}
+
+pub type LinkRes<T> = Result<T, Vec<LinkError>>;
+
+/// Places sections of all objects, resolves globals and patches relocations
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<Object>,
+    archives: Vec<Archive>,
+    layout: Option<Layout>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_object(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    /// Members are linked only when they define a symbol someone needs
+    pub fn add_archive(&mut self, archive: Archive) {
+        self.archives.push(archive);
+    }
+
+    /// Without layout sections are laid out from 0x0000 grouped by name
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = Some(layout);
+    }
+
+    pub fn link(&self) -> LinkRes<Program> {
+        if self.archives.is_empty() {
+            return self.link_objects();
+        }
+
+        let mut linker = Self {
+            objects: self.objects.clone(),
+            archives: Vec::new(),
+            layout: self.layout.clone(),
+        };
+        for (archive, member) in self.needed_members() {
+            let object = &self.archives[archive].members[member].object;
+            linker.add_object(object.clone());
+        }
+        linker.link_objects()
+    }
+
+    /// Archive members pulled in, transitively, by undefined symbols of objects
+    fn needed_members(&self) -> Vec<(usize, usize)> {
+        let mut defined: HashSet<&str> = HashSet::new();
+        let mut wanted: Vec<&str> = Vec::new();
+        let mut pulled = Vec::new();
+        let mut queue: Vec<&Object> = self.objects.iter().collect();
+        loop {
+            for object in queue.drain(..) {
+                defined.extend(object.globals().map(|x| x.name.as_str()));
+                wanted.extend(object.undefined());
+            }
+            let Some(name) = wanted.pop() else {
+                break;
+            };
+            if defined.contains(name) {
+                continue;
+            }
+            let found = self.archives.iter().enumerate().find_map(|(id, archive)| {
+                archive
+                    .member(name)
+                    .map(|(member, x)| ((id, member), &x.object))
+            });
+            if let Some((id, object)) = found {
+                pulled.push(id);
+                queue.push(object);
+            }
+        }
+        pulled.sort();
+        pulled
+    }
+
+    fn link_objects(&self) -> LinkRes<Program> {
+        let globals = self.globals()?;
+        let bases = self.place()?;
+
+        let mut errors = Vec::new();
+        let mut segments = Vec::new();
+        for (id, object) in self.objects.iter().enumerate() {
+            for (index, section) in object.sections.iter().enumerate() {
+                let mut bytes = section.bytes.clone();
+                for reloc in &section.relocations {
+                    let target = match &reloc.target {
+                        Target::Section(section) => bases[id][*section],
+                        Target::Symbol(name) => match globals.get(name.as_str()) {
+                            Some((id, symbol)) => Self::address(&bases, *id, symbol),
+                            None => {
+                                errors.push(LinkError::UndefinedSymbol(
+                                    name.clone(),
+                                    Location {
+                                        file: object.file.clone(),
+                                        line: reloc.line,
+                                    },
+                                ));
+                                continue;
+                            }
+                        },
+                    };
+                    let offset = reloc.offset as usize;
+                    if offset + 2 > bytes.len() {
+                        errors.push(LinkError::InvalidRelocation(
+                            object.file.clone(),
+                            reloc.offset,
+                        ));
+                        continue;
+                    }
+                    let value = target.wrapping_add(reloc.addend);
+                    bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+                }
+                if !bytes.is_empty() {
+                    segments.push(Segment::new(bases[id][index], bytes));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        segments.sort_by_key(|x| x.address);
+
+        Ok(Program {
+            segments,
+            debug_info: self.debug_info(&bases),
+        })
+    }
+
+    fn globals(&self) -> LinkRes<HashMap<&str, (usize, &ObjectSymbol)>> {
+        let mut errors = Vec::new();
+        let mut globals: HashMap<&str, (usize, &ObjectSymbol)> = HashMap::new();
+        for (id, object) in self.objects.iter().enumerate() {
+            for symbol in object.globals() {
+                if let Some((first, prev)) = globals.get(symbol.name.as_str()) {
+                    errors.push(LinkError::DuplicateSymbol(
+                        symbol.name.clone(),
+                        self.location(*first, prev.line),
+                        self.location(id, symbol.line),
+                    ));
+                    continue;
+                }
+                globals.insert(&symbol.name, (id, symbol));
+            }
+        }
+        if errors.is_empty() {
+            Ok(globals)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Sections with `org` stay put, the rest goes to regions named after them
+    fn place(&self) -> LinkRes<Vec<Vec<u16>>> {
+        let mut bases: Vec<Vec<u16>> = self
+            .objects
+            .iter()
+            .map(|x| x.sections.iter().map(|x| x.address.unwrap_or(0)).collect())
+            .collect();
+
+        let mut errors = Vec::new();
+        match &self.layout {
+            Some(layout) => self.place_regions(layout, &mut bases, &mut errors),
+            None => self.place_flat(&mut bases, &mut errors),
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut placed: Vec<(u16, u32, &str)> = Vec::new();
+        for (id, object) in self.objects.iter().enumerate() {
+            for (index, section) in object.sections.iter().enumerate() {
+                if !section.bytes.is_empty() {
+                    let base = bases[id][index];
+                    let end = base as u32 + section.bytes.len() as u32;
+                    placed.push((base, end, &section.name));
+                }
+            }
+        }
+        placed.sort();
+        for pair in placed.windows(2) {
+            if pair[0].1 > pair[1].0 as u32 {
+                errors.push(LinkError::SectionOverlap(pair[1].2.into(), pair[1].0));
+            }
+        }
+        if let Some((base, end, name)) = placed.last() {
+            if *end > 0x10000 {
+                errors.push(LinkError::SectionOverlap(name.to_string(), *base));
+            }
+        }
+
+        for (base, end, name) in &placed {
+            for region in self.layout.iter().flat_map(|x| x.reserved()) {
+                if !region.intersects(*base, *end) {
+                    continue;
+                }
+                errors.push(match region.kind {
+                    RegionKind::Stack => LinkError::StackCollision(name.to_string(), *base),
+                    _ => LinkError::DeviceOverlap(name.to_string(), region.name.clone(), *base),
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(bases)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn place_flat(&self, bases: &mut [Vec<u16>], errors: &mut Vec<LinkError>) {
+        let mut names: Vec<&str> = Vec::new();
+        for (_, _, section) in self.floating() {
+            if !names.contains(&section.name.as_str()) {
+                names.push(&section.name);
+            }
+        }
+
+        let mut cursor = 0u32;
+        for name in names {
+            for (id, index, section) in self.floating() {
+                if section.name == name {
+                    bases[id][index] = cursor as u16;
+                    cursor += section.bytes.len() as u32;
+                    if cursor > 0x10000 {
+                        errors.push(LinkError::SectionOverlap(name.into(), bases[id][index]));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn place_regions(&self, layout: &Layout, bases: &mut [Vec<u16>], errors: &mut Vec<LinkError>) {
+        for region in &layout.regions {
+            let mut cursor = region.start as u32;
+            for (id, index, section) in self.floating() {
+                if region.kind != RegionKind::Memory || section.name != region.name {
+                    continue;
+                }
+                bases[id][index] = cursor as u16;
+                cursor += section.bytes.len() as u32;
+                if cursor > region.end as u32 + 1 {
+                    errors.push(LinkError::RegionOverflow(
+                        region.name.clone(),
+                        section.name.clone(),
+                    ));
+                }
+            }
+        }
+
+        for (id, _, section) in self.floating() {
+            if !section.bytes.is_empty() && layout.region(&section.name).is_none() {
+                errors.push(LinkError::NoRegion(
+                    self.objects[id].file.clone(),
+                    section.name.clone(),
+                ));
+            }
+        }
+    }
+
+    /// Sections without fixed address as (object, section index, section)
+    fn floating(&self) -> impl Iterator<Item = (usize, usize, &Section)> {
+        self.objects.iter().enumerate().flat_map(|(id, object)| {
+            object
+                .sections
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.address.is_none())
+                .map(move |(index, x)| (id, index, x))
+        })
+    }
+
+    fn address(bases: &[Vec<u16>], id: usize, symbol: &ObjectSymbol) -> u16 {
+        match symbol.section {
+            Some(section) => bases[id][section].wrapping_add(symbol.value),
+            None => symbol.value,
+        }
+    }
+
+    fn location(&self, id: usize, line: u32) -> Location {
+        Location {
+            file: self.objects[id].file.clone(),
+            line,
+        }
+    }
+
+    /// Every object gets its own file id, addresses are made absolute
+    fn debug_info(&self, bases: &[Vec<u16>]) -> DebugInfo {
+        let mut info = DebugInfo::new();
+        for (id, object) in self.objects.iter().enumerate() {
+            let file = id as u32;
+            info.files.push(SourceFile {
+                id: file,
+                path: object.file.clone(),
+            });
+            info.symbols.extend(object.symbols.iter().map(|x| Symbol {
+                name: x.name.clone(),
+                kind: x.kind,
+                value: Self::address(bases, id, x),
+                size: x.size,
+                file,
+                line: x.line,
+            }));
+            info.structures.extend(
+                object
+                    .structures
+                    .iter()
+                    .map(|x| Structure { file, ..x.clone() }),
+            );
+            for (index, section) in object.sections.iter().enumerate() {
+                info.lines.extend(section.lines.iter().map(|x| LineEntry {
+                    address: bases[id][index].wrapping_add(x.address),
+                    file,
+                    ..x.clone()
+                }));
+            }
+        }
+        info.lines.sort_by_key(|x| x.address);
+        info
+    }
+}
+
+#[cfg(test)]
+fn compile(file: &str, input: &str) -> Object {
+    let parsed = InstructionParser::new().parse_lines(input).unwrap();
+    CodeGen::new().compile(file, &parsed).unwrap()
+}
+
+#[test]
+fn link_objects() {
+    let main = compile(
+        "main.s",
+        "extern print
+global start
+start:
+    call $print
+    hlt",
+    );
+    let lib = compile(
+        "lib.s",
+        "global print
+section data
+data8 text = { $0x41, $0x42 }
+section code
+print:
+    mov &text, r1
+    ret",
+    );
+    assert_eq!(main.externs.len(), 1);
+    assert_eq!(main.sections[0].relocations.len(), 1);
+    assert_eq!(
+        main.sections[0].relocations[0].target,
+        Target::Symbol("print".into())
+    );
+
+    let mut linker = Linker::new();
+    linker.add_object(main);
+    linker.add_object(Object::from_json(&lib.to_json()).unwrap());
+    let program = linker.link().unwrap();
+
+    // code of main, code of lib, then data of lib
+    assert_eq!(
+        program.segments,
+        vec![
+            Segment::new(0x0000, vec![0x5Eu8, 0x00u8, 0x04u8, 0xFFu8]),
+            Segment::new(0x0004, vec![0x13u8, 0x00u8, 0x09u8, 0x02u8, 0x60u8]),
+            Segment::new(0x0009, vec![0x41u8, 0x42u8]),
+        ]
+    );
+
+    let info = program.debug_info;
+    assert_eq!(info.file_path(1), Some("lib.s"));
+    assert_eq!(
+        info.symbol("print").map(|x| (x.value, x.file)),
+        Some((0x0004, 1))
+    );
+    assert_eq!(info.symbol("text").map(|x| x.value), Some(0x0009));
+    assert_eq!(info.line_at(0x0008).map(|x| (x.file, x.line)), Some((1, 7)));
+}
+
+#[test]
+fn link_errors() {
+    let main = compile(
+        "main.s",
+        "extern missing
+global start
+start:
+    call $missing",
+    );
+    let other = compile("other.s", "global start\nstart:\n    hlt");
+
+    let mut linker = Linker::new();
+    linker.add_object(main.clone());
+    linker.add_object(other);
+    assert_eq!(
+        linker.link().map(|_| ()),
+        Err(vec![LinkError::DuplicateSymbol(
+            "start".into(),
+            Location {
+                file: "main.s".into(),
+                line: 3
+            },
+            Location {
+                file: "other.s".into(),
+                line: 2
+            },
+        )])
+    );
+
+    let mut linker = Linker::new();
+    linker.add_object(main);
+    let errors = linker.link().unwrap_err();
+    assert_eq!(
+        errors,
+        vec![LinkError::UndefinedSymbol(
+            "missing".into(),
+            Location {
+                file: "main.s".into(),
+                line: 4
+            }
+        )]
+    );
+    assert_eq!(
+        errors[0].to_string(),
+        "main.s:4: undefined symbol `missing`"
+    );
+}
+
+#[test]
+fn link_layout() {
+    let object = compile(
+        "main.s",
+        "start:
+    mov &value, r1
+    hlt
+section data
+data16 value = { $1 }",
+    );
+    let mut linker = Linker::new();
+    linker.set_layout(Layout::vm());
+    linker.add_object(object.clone());
+    assert_eq!(
+        linker.link().unwrap().segments,
+        vec![
+            Segment::new(0x0000, vec![0x13u8, 0x32u8, 0x00u8, 0x02u8, 0xFFu8]),
+            Segment::new(0x3200, vec![0x00u8, 0x01u8]),
+        ]
+    );
+
+    let mut linker = Linker::new();
+    linker.set_layout(Layout::parse("code 0x0000 0x0003\ndata 0x0010 0x001f").unwrap());
+    linker.add_object(object);
+    assert_eq!(
+        linker.link().map(|_| ()),
+        Err(vec![LinkError::RegionOverflow(
+            "code".into(),
+            "code".into()
+        )])
+    );
+
+    let object = compile(
+        "main.s",
+        "hlt
+org $0x30f0
+    hlt
+org $0xfff0
+    hlt
+section rodata
+    hlt",
+    );
+    let mut linker = Linker::new();
+    linker.set_layout(Layout::vm());
+    linker.add_object(object);
+    assert_eq!(
+        linker.link().map(|_| ()),
+        Err(vec![LinkError::NoRegion("main.s".into(), "rodata".into())])
+    );
+
+    let object = compile("main.s", "org $0x30f0\n    hlt\norg $0xfff0\n    hlt");
+    let mut linker = Linker::new();
+    linker.set_layout(Layout::vm());
+    linker.add_object(object);
+    assert_eq!(
+        linker.link().map(|_| ()),
+        Err(vec![
+            LinkError::DeviceOverlap("code".into(), "screen".into(), 0x30f0),
+            LinkError::StackCollision("code".into(), 0xfff0),
+        ])
+    );
+}
@@ -1,56 +1,566 @@
-pub mod ast;
-pub mod codegen;
-pub mod common;
-pub mod instructions;
-pub mod lexer;
-pub mod parse;
-
 use std::{
+    collections::VecDeque,
     fs::File,
     io::{prelude::*, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use clap::Parser;
+use asm::{
+    archive::Archive,
+    codegen::{CodeGen, Program},
+    coverage,
+    dap::DapServer,
+    debugger::{Debugger, DEFAULT_HISTORY},
+    debuginfo::DebugInfo,
+    emu::{self, HostInput, RawMode},
+    formats::{self, Format},
+    gdbstub::GdbStub,
+    layout::Layout,
+    linker::{LinkError, Linker},
+    object::Object,
+    parse::InstructionParser,
+    profile::{self, ProfileFormat},
+    run::{self, DEFAULT_FUEL},
+    trace::{parse_filter, TraceFormat, Tracer},
+};
+use clap::{Parser, Subcommand};
+use vm::{
+    bank::BANK_SIZE,
+    coverage::Coverage,
+    cpu::Exit,
+    profile::Profile,
+    registers::Register,
+    screen::Backend,
+    snapshot::Snapshot,
+    timing::{CostTable, Throttle},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Input file
-    input: PathBuf,
+    #[arg(required = true)]
+    input: Option<PathBuf>,
     /// Dump ast
     #[arg(short, long, default_value_t = false)]
     dump: bool,
+    /// Write relocatable object (<input>.o by default) instead of linking
+    #[arg(short, long, default_value_t = false)]
+    compile: bool,
+    #[command(flatten)]
+    out: LinkArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct LinkArgs {
     /// Output file name
     #[arg(short, long)]
     output: Option<PathBuf>,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Raw)]
+    format: Format,
+    /// Write debug info sidecar (<output>.dbg.json) next to the binary
+    #[arg(short = 'g', long, default_value_t = false)]
+    debug_info: bool,
+    /// Memory layout file placing sections into regions (see layouts/vm.layout)
+    #[arg(short, long)]
+    layout: Option<PathBuf>,
 }
 
-fn main() -> std::io::Result<()> {
-    use parse::InstructionParser;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Link object files into final image
+    Link {
+        /// Object files and archives, archive members are linked only when needed
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+        #[command(flatten)]
+        out: LinkArgs,
+    },
+    /// Bundle object files into static library with symbol index
+    Ar {
+        /// Archive file name
+        output: PathBuf,
+        /// Object files
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+    },
+    /// Run program in the emulator and dump registers once it halts
+    Emu(EmuArgs),
+    /// Run program without interaction, exit status tells how it ended
+    Run(RunArgs),
+}
 
-    let args = Args::parse();
-    let mut parser = InstructionParser::new();
+/// Program and machine options `emu` and `run` share
+#[derive(clap::Args, Debug)]
+struct MachineArgs {
+    /// Assembly source (.s) or image in any output format
+    input: PathBuf,
+    /// Lowest address the stack may grow to before pushes fault
+    #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+    stack_limit: u16,
+    /// Keyboard input: scripted file, or host terminal in raw mode with `-`
+    #[arg(long, value_name = "FILE|-")]
+    keys: Option<PathBuf>,
+    /// Map this many memory banks into the bank window, RAM stays there otherwise
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    banks: Option<u16>,
+    /// Bytes per bank, the whole window by default. Maps banks like `--banks`
+    #[arg(long, value_name = "BYTES", value_parser = parse_bank_size)]
+    bank_size: Option<u16>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+    /// Instructions to execute before giving up with status 124
+    #[arg(long, default_value_t = DEFAULT_FUEL)]
+    fuel: u64,
+    /// Exit with low byte of this register once halted, 0 otherwise
+    #[arg(long, value_name = "REG", value_parser = parse_register)]
+    exit_code: Option<Register>,
+    /// Print registers after the program stops
+    #[arg(long, default_value_t = false)]
+    registers: bool,
+    /// Print memory in `start-end` range or symbol after the program stops, can be repeated
+    #[arg(long, value_name = "RANGE|SYMBOL")]
+    dump: Vec<String>,
+}
+
+fn parse_register(name: &str) -> Result<Register, String> {
+    Register::from_name(name).ok_or(format!("unknown register `{}`", name))
+}
+
+fn parse_bank_size(input: &str) -> Result<u16, String> {
+    match parse_address(input) {
+        Ok(size) if size > 0 && size as usize <= BANK_SIZE => Ok(size),
+        _ => Err(format!(
+            "invalid bank size `{}`, expected 1 to {:#x}",
+            input, BANK_SIZE
+        )),
+    }
+}
+
+fn parse_address(input: &str) -> Result<u16, String> {
+    match input.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => input.parse(),
+    }
+    .map_err(|_| format!("invalid address `{}`", input))
+}
+
+#[derive(clap::Args, Debug)]
+struct EmuArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+    /// Print screen device contents after the registers
+    #[arg(short, long, value_enum)]
+    screen: Option<ScreenOutput>,
+    /// Start interactive debugger, images use <input>.dbg.json for symbols
+    #[arg(short, long, default_value_t = false)]
+    debug: bool,
+    /// Instructions the debugger keeps for reverse execution
+    #[arg(long, default_value_t = DEFAULT_HISTORY)]
+    history: usize,
+    /// Serve GDB remote protocol on a local TCP port, or stdio with `-`
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<String>,
+    /// Serve debug adapter protocol over stdio for editors
+    #[arg(long, default_value_t = false, conflicts_with = "gdb")]
+    dap: bool,
+    /// Write per-instruction execution trace to file
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+    /// Trace file format
+    #[arg(long, value_enum, default_value_t = TraceFormat::Jsonl)]
+    trace_format: TraceFormat,
+    /// Only trace instructions in `start-end` range or label, can be repeated
+    #[arg(long, value_name = "RANGE|SYMBOL")]
+    trace_only: Vec<String>,
+    /// Write execution profile to file, or stdout after the registers with `-`
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+    /// Profile report format
+    #[arg(long, value_enum, default_value_t = ProfileFormat::Text)]
+    profile_format: ProfileFormat,
+    /// Write LCOV coverage of source lines and conditional jumps to file
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+    /// Write sources annotated with execution counts, or stdout with `-`
+    #[arg(long, value_name = "FILE")]
+    coverage_listing: Option<PathBuf>,
+    /// Cycle cost table, `<opcode|mnemonic|fetch|access> <cycles>` per line
+    #[arg(long, value_name = "FILE")]
+    costs: Option<PathBuf>,
+    /// Throttle to the given clock rate in cycles per second
+    #[arg(long, value_name = "HZ", value_parser = clap::value_parser!(u64).range(1..))]
+    clock: Option<u64>,
+    /// Start from machine state saved with debugger `save` instead of reset
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ScreenOutput {
+    /// Escape sequences for terminal, like core prints them
+    Ansi,
+    /// Plain text grid
+    Text,
+}
 
-    let file = File::open(args.input)?;
+fn invalid_data(err: impl std::fmt::Debug) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", err))
+}
+
+fn report(errors: Vec<LinkError>) -> ! {
+    for err in errors {
+        eprintln!("error: {}", err);
+    }
+    std::process::exit(1)
+}
+
+fn read_file(path: &Path) -> std::io::Result<String> {
+    let file = File::open(path)?;
     let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
     buf_reader.read_to_string(&mut contents)?;
+    Ok(contents)
+}
 
-    let parsed = parser.parse(contents.as_str()).unwrap();
+fn linker(args: &LinkArgs) -> std::io::Result<Linker> {
+    let mut linker = Linker::new();
+    if let Some(path) = &args.layout {
+        linker.set_layout(Layout::parse(&read_file(path)?).map_err(invalid_data)?);
+    }
+    Ok(linker)
+}
+
+fn write_program(program: &Program, args: LinkArgs) -> std::io::Result<()> {
+    let out_name = args.output.unwrap_or(PathBuf::from("a.out"));
+    let output = formats::write(args.format, &program.segments);
+    let mut out_file = File::create(&out_name)?;
+    out_file.write_all(output.as_slice())?;
+
+    println!("Wrote {} bytes to {}", output.len(), out_name.display());
+
+    if args.debug_info {
+        let mut debug_name = out_name.into_os_string();
+        debug_name.push(".dbg.json");
+        let debug_name = PathBuf::from(debug_name);
+        std::fs::write(&debug_name, program.debug_info.to_json())?;
+        println!("Wrote debug info to {}", debug_name.display());
+    }
+
+    Ok(())
+}
+
+fn link(objects: &[PathBuf], args: LinkArgs) -> std::io::Result<()> {
+    let mut linker = linker(&args)?;
+    for path in objects {
+        let contents = read_file(path)?;
+        match Archive::from_json(&contents) {
+            Ok(archive) => linker.add_archive(archive),
+            Err(_) => linker.add_object(Object::from_json(&contents).map_err(invalid_data)?),
+        }
+    }
+    let program = linker.link().unwrap_or_else(|errors| report(errors));
+    write_program(&program, args)
+}
+
+fn archive(output: &Path, objects: &[PathBuf]) -> std::io::Result<()> {
+    let mut archive = Archive::new();
+    for path in objects {
+        let object = Object::from_json(&read_file(path)?).map_err(invalid_data)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        archive.add(&name, object).map_err(invalid_data)?;
+    }
+    std::fs::write(output, archive.to_json())?;
+    println!(
+        "Wrote {} members, {} symbols to {}",
+        archive.members.len(),
+        archive.index.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Sources are assembled on the fly, anything else is loaded as an image
+/// together with its debug info sidecar if there is one
+fn load_program(path: &Path) -> std::io::Result<Program> {
+    if path.extension().is_some_and(|x| x == "s") {
+        let parsed = InstructionParser::new()
+            .parse_lines(&read_file(path)?)
+            .map_err(invalid_data)?;
+        let object = CodeGen::new()
+            .compile(&path.to_string_lossy(), &parsed)
+            .map_err(invalid_data)?;
+        let mut linker = Linker::new();
+        linker.add_object(object);
+        return Ok(linker.link().unwrap_or_else(|errors| report(errors)));
+    }
+
+    let segments = formats::load(&std::fs::read(path)?).map_err(invalid_data)?;
+    let mut debug_name = path.as_os_str().to_owned();
+    debug_name.push(".dbg.json");
+    let debug_info = match std::fs::read_to_string(debug_name) {
+        Ok(contents) => DebugInfo::from_json(&contents).map_err(invalid_data)?,
+        Err(_) => DebugInfo::new(),
+    };
+    Ok(Program {
+        segments,
+        debug_info,
+    })
+}
+
+fn machine(program: &Program, args: &MachineArgs) -> emu::Machine {
+    let mut machine = match (args.banks, args.bank_size) {
+        (None, None) => emu::machine(&program.segments),
+        (banks, size) => emu::machine_with_banks(
+            &program.segments,
+            banks.map_or(emu::DEFAULT_BANKS, usize::from),
+            size.map_or(BANK_SIZE, usize::from),
+        ),
+    };
+    machine.cpu.set_stack_limit(args.stack_limit);
+    machine
+}
+
+fn emulate(args: EmuArgs) -> std::io::Result<()> {
+    let program = load_program(&args.machine.input)?;
+    let mut machine = machine(&program, &args.machine);
+    let terminal = args
+        .machine
+        .keys
+        .as_ref()
+        .is_some_and(|x| x.as_os_str() == "-");
+    if terminal && (args.debug || args.dap || args.gdb.as_deref() == Some("-")) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "`--keys -` needs stdin, use a script file",
+        ));
+    }
+    if let Some(path) = &args.costs {
+        let costs = CostTable::parse(&read_file(path)?).map_err(invalid_data)?;
+        machine.cpu.set_costs(costs);
+    }
+    if let Some(path) = &args.snapshot {
+        let snapshot = Snapshot::decode(&std::fs::read(path)?).map_err(invalid_data)?;
+        machine.cpu.restore(&snapshot).map_err(invalid_data)?;
+    }
+    // scripted keys reach debugger sessions too, `-` was refused for them
+    let raw = connect_keyboard(&machine, &args.machine)?;
+    if args.dap {
+        let mut server = DapServer::new(machine, program.debug_info);
+        return server.serve(std::io::stdin().lock(), std::io::stdout());
+    }
+    if let Some(port) = args.gdb {
+        let mut stub = GdbStub::new(machine);
+        if port == "-" {
+            return stub.serve(std::io::stdin(), std::io::stdout());
+        }
+        let listener =
+            std::net::TcpListener::bind(("127.0.0.1", port.parse().map_err(invalid_data)?))?;
+        eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        return stub.serve(stream.try_clone()?, stream);
+    }
+    if args.debug {
+        machine.cpu.record(args.history);
+        let mut debugger = Debugger::new(machine, program.debug_info);
+        return debugger.repl(std::io::stdin().lock(), std::io::stdout());
+    }
+    let mut tracer = match &args.trace {
+        Some(path) => {
+            let ranges = args
+                .trace_only
+                .iter()
+                .map(|x| parse_filter(x, &program.debug_info))
+                .collect::<Result<_, _>>()
+                .map_err(invalid_data)?;
+            let out = std::io::BufWriter::new(File::create(path)?);
+            let debug_info = program.debug_info.clone();
+            Some(Tracer::new(out, args.trace_format, ranges, debug_info)?)
+        }
+        None => None,
+    };
+    let cpu = &mut machine.cpu;
+    let throttle = args.clock.map(|hz| Throttle::new(hz, cpu.cycles()));
+    let mut profile = args
+        .profile
+        .as_ref()
+        .map(|_| Profile::new(cpu.register(Register::Ip)));
+    let mut coverage =
+        (args.coverage.is_some() || args.coverage_listing.is_some()).then(Coverage::new);
+    loop {
+        let instruction = (profile.is_some() || coverage.is_some())
+            .then(|| cpu.decode(cpu.register(Register::Ip)));
+        let executed = cpu.executed();
+        let halted = match &mut tracer {
+            Some(tracer) => tracer.step(cpu)?,
+            None => cpu.step(),
+        };
+        // faulting instructions never ran
+        if let Some(instruction) = instruction.filter(|_| cpu.executed() != executed) {
+            if let Some(profile) = &mut profile {
+                profile.count(&instruction, cpu);
+            }
+            if let Some(coverage) = &mut coverage {
+                coverage.record(&instruction, cpu);
+            }
+        }
+        if let Some(throttle) = &throttle {
+            throttle.wait(cpu.cycles());
+        }
+        if halted {
+            break;
+        }
+    }
+    drop(raw);
+
+    for (reg, value) in machine.cpu.registers() {
+        println!("[{}]: {:#06x}", reg.name(), value);
+    }
+    println!("{} cycles", machine.cpu.cycles());
+    if let Some(fault) = machine.cpu.fault() {
+        eprintln!("fault: {}", fault);
+    }
+    if let Some(output) = args.screen {
+        let backend = match output {
+            ScreenOutput::Ansi => Backend::Ansi,
+            ScreenOutput::Text => Backend::Text,
+        };
+        print!("{}", machine.screen.borrow().render(backend));
+    }
+    if let (Some(path), Some(profile)) = (&args.profile, &profile) {
+        let report = profile::render(args.profile_format, profile, &program.debug_info);
+        if path.as_os_str() == "-" {
+            print!("{}", report);
+        } else {
+            std::fs::write(path, report)?;
+        }
+    }
+    if let Some(coverage) = &coverage {
+        let debug_info = &program.debug_info;
+        if let Some(path) = &args.coverage {
+            std::fs::write(path, coverage::lcov(coverage, debug_info, &machine.cpu))?;
+        }
+        if let Some(path) = &args.coverage_listing {
+            let mut out = String::new();
+            for file in &debug_info.files {
+                match std::fs::read_to_string(&file.path) {
+                    Ok(source) => out.push_str(&coverage::listing(
+                        coverage,
+                        debug_info,
+                        &machine.cpu,
+                        file.id,
+                        &source,
+                    )),
+                    Err(err) => eprintln!("Skipping {} in listing: {}", file.path, err),
+                }
+            }
+            if path.as_os_str() == "-" {
+                print!("{}", out);
+            } else {
+                std::fs::write(path, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Feeds the keyboard from a script or the host terminal with `-`, terminal
+/// stays in raw mode until the returned guard is dropped
+fn connect_keyboard(
+    machine: &emu::Machine,
+    args: &MachineArgs,
+) -> std::io::Result<Option<RawMode>> {
+    let mut keyboard = machine.keyboard.borrow_mut();
+    match &args.keys {
+        Some(path) if path.as_os_str() == "-" => {
+            let (input, raw) = HostInput::terminal()?;
+            keyboard.set_input(input);
+            Ok(raw)
+        }
+        Some(path) => {
+            keyboard.set_input(VecDeque::from(std::fs::read(path)?));
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+fn execute(args: RunArgs) -> std::io::Result<()> {
+    let program = load_program(&args.machine.input)?;
+    let ranges: Vec<_> = args
+        .dump
+        .iter()
+        .map(|x| parse_filter(x, &program.debug_info))
+        .collect::<Result<_, _>>()
+        .map_err(invalid_data)?;
+    let mut machine = machine(&program, &args.machine);
+    let raw = connect_keyboard(&machine, &args.machine)?;
+    let cpu = &mut machine.cpu;
+
+    let exit = cpu.run_for(args.fuel);
+    drop(raw);
+    print!("{}", run::report(cpu, args.registers, &ranges));
+    if exit != Exit::Halted {
+        eprintln!("{}", run::describe(exit, cpu));
+    }
+    let code = run::halt_code(cpu, args.exit_code);
+    if exit == Exit::Halted && run::RESERVED.contains(&code) {
+        eprintln!(
+            "exit code {} is reserved, exiting with {}",
+            code,
+            run::EXIT_RESERVED
+        );
+    }
+    let status = run::exit_status(exit, cpu, args.exit_code);
+    std::io::stdout().flush()?;
+    std::process::exit(status)
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Link { objects, out }) => return link(&objects, out),
+        Some(Command::Ar { output, objects }) => return archive(&output, &objects),
+        Some(Command::Emu(args)) => return emulate(args),
+        Some(Command::Run(args)) => return execute(args),
+        None => {}
+    }
+
+    let input = args.input.expect("input is required without subcommand");
+    let mut parser = InstructionParser::new();
+    let mut codegen = CodeGen::new();
+
+    let contents = read_file(&input)?;
+    let parsed = parser
+        .parse_lines(contents.as_str())
+        .map_err(invalid_data)?;
 
     if args.dump {
         println!("{:?}", parsed);
         return Ok(());
     }
 
-    // let binary = codegen.code_gen(parsed.as_slice()).unwrap();
+    let object = codegen
+        .compile(&input.to_string_lossy(), &parsed)
+        .map_err(invalid_data)?;
 
-    // let out_name = args.output.unwrap_or(PathBuf::from("a.out"));
-    // let mut out_file = File::create(&out_name)?;
-    // out_file.write_all(binary.as_slice())?;
-    //
-    // println!("Wrote {} bytes to {}", binary.len(), out_name.display());
+    if args.compile {
+        let out_name = args.out.output.unwrap_or(input.with_extension("o"));
+        std::fs::write(&out_name, object.to_json())?;
+        println!("Wrote object to {}", out_name.display());
+        return Ok(());
+    }
 
-    Ok(())
+    let mut linker = linker(&args.out)?;
+    linker.add_object(object);
+    let program = linker.link().unwrap_or_else(|errors| report(errors));
+    write_program(&program, args.out)
 }
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::debuginfo::{LineEntry, Structure, SymbolKind};
+
+pub const OBJECT_VERSION: u32 = 1;
+
+/// Relocatable output of `asm -c`, stored as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Object {
+    pub version: u32,
+    /// Source file the object was assembled from
+    pub file: String,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub externs: Vec<Extern>,
+    pub structures: Vec<Structure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    /// Fixed load address set by `org`, linker places the rest
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    /// Line table with addresses relative to section start
+    pub lines: Vec<LineEntry>,
+}
+
+/// 16-bit big-endian address at `offset` has to be patched to `target + addend`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relocation {
+    pub offset: u16,
+    pub target: Target,
+    pub addend: u16,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// Start of section of the same object
+    Section(usize),
+    /// Global symbol of any linked object
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// `None` for absolute symbols (constants)
+    pub section: Option<usize>,
+    pub value: u16,
+    pub size: u16,
+    pub global: bool,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Extern {
+    pub name: String,
+    pub line: u32,
+}
+
+impl Section {
+    pub fn new(name: &str, address: Option<u16>) -> Self {
+        Self {
+            name: name.into(),
+            address,
+            bytes: Vec::new(),
+            relocations: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl Default for Object {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl Object {
+    pub fn new(file: &str) -> Self {
+        Self {
+            version: OBJECT_VERSION,
+            file: file.into(),
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            externs: Vec::new(),
+            structures: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("object is always serializable")
+    }
+
+    pub fn from_json(input: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(input)
+    }
+
+    pub fn globals(&self) -> impl Iterator<Item = &ObjectSymbol> {
+        self.symbols.iter().filter(|x| x.global)
+    }
+
+    /// Externs actually referenced by relocations
+    pub fn undefined(&self) -> impl Iterator<Item = &str> {
+        self.sections
+            .iter()
+            .flat_map(|x| x.relocations.iter())
+            .filter_map(|x| match &x.target {
+                Target::Symbol(name) => Some(name.as_str()),
+                Target::Section(_) => None,
+            })
+    }
+}
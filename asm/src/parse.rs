@@ -3,7 +3,7 @@ use std::iter::Peekable;
 use crate::{
     ast::{Expr, ExprArgs, ExprKind, S},
     common::{TokenEnum, Regs},
-    lexer::{tokenize_old, Token},
+    lexer::{tokenize_expr, tokenize_old, Token},
 };
 
 #[derive(Debug, Clone)]
@@ -20,20 +20,33 @@ pub enum ParserError {
 
 type ParseRes<T> = Result<T, ParserError>;
 
+impl Default for InstructionParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> InstructionParser {
     pub fn new() -> Self {
         Self { line: 0 }
     }
 
     pub fn parse(&mut self, input: &'a str) -> ParseRes<Vec<Expr>> {
+        let exprs = self.parse_lines(input)?;
+        Ok(exprs.into_iter().map(|(_, expr)| expr).collect())
+    }
+
+    /// Parses input keeping 1-based source line of every expression
+    pub fn parse_lines(&mut self, input: &'a str) -> ParseRes<Vec<(u32, Expr)>> {
         let mut lexer = tokenize_old(input)
-            .filter(|x| x.kind != TokenEnum::Whitespace)
+            .filter(|x| x.kind != TokenEnum::Whitespace && x.kind != TokenEnum::Semicolon)
             .peekable();
 
         self.line = 0;
-        let mut exprs: Vec<Expr> = Vec::new();
+        let mut exprs: Vec<(u32, Expr)> = Vec::new();
 
         while let Some(tkn) = lexer.next() {
+            let line = self.line + 1;
             let expr = match &tkn.kind {
                 TokenEnum::Ident(_) => self.parse_ident(&mut lexer, &tkn)?,
                 TokenEnum::NewLine => {
@@ -42,7 +55,7 @@ impl<'a> InstructionParser {
                 }
                 _ => return Err(self.collect_error_on_line(&mut lexer, &tkn)),
             };
-            exprs.push(expr);
+            exprs.push((line, expr));
         }
         Ok(exprs)
     }
@@ -93,7 +106,7 @@ impl<'a> InstructionParser {
     }
 
     pub fn parse_ident(
-        &self,
+        &mut self,
         lexer: &mut Peekable<impl Iterator<Item = Token>>,
         ident: &Token,
     ) -> ParseRes<Expr> {
@@ -127,9 +140,24 @@ impl<'a> InstructionParser {
             "jeq"  => self.parse_double_args(lexer, ExprKind::JmpEQ),
             "jlt"  => self.parse_double_args(lexer, ExprKind::JmpLT),
             "jgt"  => self.parse_double_args(lexer, ExprKind::JmpGT),
+            "jle"  => self.parse_double_args(lexer, ExprKind::JmpLE),
+            "jge"  => self.parse_double_args(lexer, ExprKind::JmpGE),
             "ret"  => Ok(Expr::new(ExprKind::Ret, ExprArgs::NoArgs)),
+            "int"  => self.parse_single_args(lexer, ExprKind::Int),
+            "rti"  => Ok(Expr::new(ExprKind::Rti, ExprArgs::NoArgs)),
             "hlt"  => Ok(Expr::new(ExprKind::HLT, ExprArgs::NoArgs)),
-            _      => unimplemented!(),
+            "org"       => self.parse_single_args(lexer, ExprKind::Org),
+            "constant"  => self.parse_constant(lexer),
+            "data8"     => self.parse_data(lexer, ExprKind::Data8),
+            "data16"    => self.parse_data(lexer, ExprKind::Data16),
+            "structure" => self.parse_structure(lexer),
+            "section"   => self.parse_single_args(lexer, ExprKind::Section),
+            "global"    => self.parse_single_args(lexer, ExprKind::Global),
+            "extern"    => self.parse_single_args(lexer, ExprKind::Extern),
+            _ => Err(ParserError::UnknownExpr(
+                format!("Unknown instruction {}", ident_into),
+                self.line,
+            )),
         }
     }
 
@@ -149,29 +177,28 @@ impl<'a> InstructionParser {
                 lexer.next();
                 let mut expr = lexer
                     .take_while(|x| x.kind != TokenEnum::CloseBracket)
-                    .into_iter()
                     .peekable();
 
                 let lhs = Self::expr_bp(&mut expr, 0)?;
                 assert_eq!(lexer.next().unwrap().kind, TokenEnum::Comma);
-                let rhs = lexer.next().ok_or(mov_error)?.kind;
+                let rhs = ParserHelper::operand(lexer.next().ok_or(mov_error)?.kind);
 
                 Expr::new(ExprKind::Mov, ExprArgs::Complex(lhs, rhs))
             }
             None => return Err(mov_error),
             _ => {
                 // TODO: validate
-                let lhs = lexer.next().unwrap().kind;
+                let lhs = ParserHelper::operand(lexer.next().unwrap().kind);
                 assert_eq!(lexer.next().unwrap().kind, TokenEnum::Comma);
                 // TODO: validate is mem or reg
-                let maybe_mhs = lexer.next().ok_or(mov_error)?.kind;
+                let maybe_mhs = ParserHelper::operand(lexer.next().ok_or(mov_error)?.kind);
                 if let Some(Token {
                     kind: TokenEnum::Comma,
                     ..
                 }) = lexer.peek()
                 {
                     lexer.next();
-                    let rhs = lexer.next().ok_or(mov_error_long)?.kind;
+                    let rhs = ParserHelper::operand(lexer.next().ok_or(mov_error_long)?.kind);
                     Expr::new(ExprKind::Mov, ExprArgs::Triple(lhs, maybe_mhs, rhs))
                 } else {
                     Expr::new(ExprKind::Mov, ExprArgs::Double(lhs, maybe_mhs))
@@ -186,9 +213,9 @@ impl<'a> InstructionParser {
         lexer: &mut Peekable<impl Iterator<Item = Token>>,
         kind: ExprKind,
     ) -> ParseRes<Expr> {
-        let lhs = lexer.next().unwrap().kind;
+        let lhs = ParserHelper::operand(lexer.next().unwrap().kind);
         assert_eq!(lexer.next().unwrap().kind, TokenEnum::Comma);
-        let rhs = lexer.next().unwrap().kind;
+        let rhs = ParserHelper::operand(lexer.next().unwrap().kind);
 
         Ok(Expr::new(kind, ExprArgs::Double(lhs, rhs)))
     }
@@ -198,11 +225,140 @@ impl<'a> InstructionParser {
         lexer: &mut Peekable<impl Iterator<Item = Token>>,
         kind: ExprKind,
     ) -> ParseRes<Expr> {
-        let lhs = lexer.next().unwrap().kind;
+        let lhs = ParserHelper::operand(lexer.next().unwrap().kind);
 
         Ok(Expr::new(kind, ExprArgs::Single(lhs)))
     }
 
+    pub fn parse_constant(
+        &self,
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+    ) -> ParseRes<Expr> {
+        let usage = || {
+            ParserError::UnknownExpr("Constant usage: constant <name> = <lit>".into(), self.line)
+        };
+
+        let name = match lexer.next() {
+            Some(Token {
+                kind: kind @ TokenEnum::Ident(_),
+                ..
+            }) => kind,
+            _ => return Err(usage()),
+        };
+        self.expect(lexer, TokenEnum::Equal).map_err(|_| usage())?;
+        let value = match lexer.next() {
+            Some(Token {
+                kind: kind @ (TokenEnum::Lit(_) | TokenEnum::LitSym(_)),
+                ..
+            }) => kind,
+            _ => return Err(usage()),
+        };
+
+        Ok(Expr::new(ExprKind::Constant, ExprArgs::Double(name, value)))
+    }
+
+    pub fn parse_data(
+        &mut self,
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+        kind: ExprKind,
+    ) -> ParseRes<Expr> {
+        let usage = ParserError::UnknownExpr(
+            "Data usage: data8/data16 <name> = { <lit>, <lit>, ... }".into(),
+            self.line,
+        );
+
+        let name = match lexer.next() {
+            Some(Token {
+                kind: name @ TokenEnum::Ident(_),
+                ..
+            }) => name,
+            _ => return Err(usage),
+        };
+        self.expect(lexer, TokenEnum::Equal)?;
+        self.expect(lexer, TokenEnum::OpenBrace)?;
+
+        let mut values = Vec::new();
+        loop {
+            match self.next_skip_newlines(lexer).map(|x| x.kind) {
+                Some(TokenEnum::CloseBrace) => break,
+                Some(TokenEnum::Comma) if !values.is_empty() => continue,
+                Some(value @ (TokenEnum::Lit(_) | TokenEnum::LitSym(_))) => values.push(value),
+                _ => return Err(usage),
+            }
+        }
+
+        Ok(Expr::new(kind, ExprArgs::List(name, values)))
+    }
+
+    pub fn parse_structure(
+        &mut self,
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+    ) -> ParseRes<Expr> {
+        let usage = ParserError::UnknownExpr(
+            "Structure usage: structure <name> { <field>: <lit>, ... }".into(),
+            self.line,
+        );
+
+        let name = match lexer.next() {
+            Some(Token {
+                kind: name @ TokenEnum::Ident(_),
+                ..
+            }) => name,
+            _ => return Err(usage),
+        };
+        self.expect(lexer, TokenEnum::OpenBrace)?;
+
+        let mut fields = Vec::new();
+        loop {
+            match self.next_skip_newlines(lexer).map(|x| x.kind) {
+                Some(TokenEnum::CloseBrace) => break,
+                Some(TokenEnum::Comma) if !fields.is_empty() => continue,
+                Some(field @ TokenEnum::Ident(_)) => {
+                    self.expect(lexer, TokenEnum::Colon)?;
+                    match lexer.next().map(|x| x.kind) {
+                        Some(size @ (TokenEnum::Lit(_) | TokenEnum::LitSym(_))) => {
+                            fields.push((field, size))
+                        }
+                        _ => return Err(usage),
+                    }
+                }
+                _ => return Err(usage),
+            }
+        }
+
+        Ok(Expr::new(
+            ExprKind::Structure,
+            ExprArgs::Fields(name, fields),
+        ))
+    }
+
+    fn expect(
+        &self,
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+        kind: TokenEnum,
+    ) -> ParseRes<()> {
+        match lexer.next() {
+            Some(tkn) if tkn.kind == kind => Ok(()),
+            tkn => Err(ParserError::UnknownExpr(
+                format!("Expected {:?}, got {:?}", kind, tkn.map(|x| x.kind)),
+                self.line,
+            )),
+        }
+    }
+
+    fn next_skip_newlines(
+        &mut self,
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+    ) -> Option<Token> {
+        for tkn in lexer.by_ref() {
+            if tkn.kind != TokenEnum::NewLine {
+                return Some(tkn);
+            }
+            self.line += 1;
+        }
+        None
+    }
+
     #[allow(dead_code)]
     fn expr_bp(lexer: &mut Peekable<impl Iterator<Item = Token>>, min_bp: u8) -> ParseRes<S> {
         let token = lexer.next().ok_or(ParserError::EmptyExpr)?.kind;
@@ -214,21 +370,22 @@ impl<'a> InstructionParser {
             None => match token {
                 TokenEnum::OpenParen => {
                     let lhs = Self::expr_bp(lexer, 0)?;
-                    assert_eq!(lexer.next().unwrap().kind, TokenEnum::CloseParen);
+                    Self::expect_kind(lexer, TokenEnum::CloseParen)?;
                     lhs
                 }
-                TokenEnum::Mem(_) | TokenEnum::Lit(_) | TokenEnum::Ident(_) => S::Atom(token),
+                TokenEnum::Mem(_)
+                | TokenEnum::Lit(_)
+                | TokenEnum::Ident(_)
+                | TokenEnum::LitSym(_)
+                | TokenEnum::Ref(_) => S::Atom(ParserHelper::operand(token)),
                 _ => return Err(ParserError::UnknownExpr(format!("{:?}", token), 0)),
             },
         };
 
-        loop {
-            let op = match lexer.peek() {
-                Some(tkn) => &tkn.kind,
-                None => break,
-            };
+        while let Some(tkn) = lexer.peek() {
+            let op = &tkn.kind;
 
-            if let Some((l_bp, r_bp)) = Self::infix_binding_power(&op) {
+            if let Some((l_bp, r_bp)) = Self::infix_binding_power(op) {
                 if l_bp < min_bp {
                     break;
                 }
@@ -237,7 +394,7 @@ impl<'a> InstructionParser {
 
                 lhs = if op == TokenEnum::Question {
                     let mhs = Self::expr_bp(lexer, 0)?;
-                    assert_eq!(lexer.next().unwrap().kind, TokenEnum::Colon);
+                    Self::expect_kind(lexer, TokenEnum::Colon)?;
                     let rhs = Self::expr_bp(lexer, r_bp)?;
                     S::Cons(op, vec![lhs, mhs, rhs])
                 } else {
@@ -254,9 +411,32 @@ impl<'a> InstructionParser {
         Ok(lhs)
     }
 
+    fn expect_kind(
+        lexer: &mut Peekable<impl Iterator<Item = Token>>,
+        kind: TokenEnum,
+    ) -> ParseRes<()> {
+        match lexer.next() {
+            Some(token) if token.kind == kind => Ok(()),
+            token => Err(ParserError::UnknownExpr(
+                format!("expected {:?}, got {:?}", kind, token.map(|x| x.kind)),
+                0,
+            )),
+        }
+    }
+
+    /// Standalone expression like `acc == $10 && r1 > $3`, used by the debugger
+    pub fn parse_expr(input: &str) -> ParseRes<S> {
+        let mut tokens = tokenize_expr(input);
+        let expr = Self::expr_bp(&mut tokens, 0)?;
+        match tokens.next() {
+            None => Ok(expr),
+            Some(token) => Err(ParserError::UnknownExpr(format!("{:?}", token.kind), 0)),
+        }
+    }
+
     fn prefix_binding_power(op: &TokenEnum) -> Option<((), u8)> {
         let res = match op {
-            TokenEnum::Plus | TokenEnum::Minus => ((), 9),
+            TokenEnum::Plus | TokenEnum::Minus | TokenEnum::Neg => ((), 17),
             _ => return None,
         };
         Some(res)
@@ -265,8 +445,15 @@ impl<'a> InstructionParser {
     fn infix_binding_power(op: &TokenEnum) -> Option<(u8, u8)> {
         let res = match op {
             TokenEnum::Question => (2, 1),
-            TokenEnum::Plus | TokenEnum::Minus => (3, 4),
-            TokenEnum::Star => (5, 6),
+            TokenEnum::Or => (3, 4),
+            TokenEnum::And => (5, 6),
+            TokenEnum::DoubleEqual | TokenEnum::NotEqual => (7, 8),
+            TokenEnum::Less
+            | TokenEnum::Greater
+            | TokenEnum::LessEqual
+            | TokenEnum::GreaterEqual => (9, 10),
+            TokenEnum::Plus | TokenEnum::Minus => (11, 12),
+            TokenEnum::Star => (13, 14),
             // TODO: can add '.'
             _ => return None,
         };
@@ -294,12 +481,18 @@ impl ParserHelper {
             _ => Err(ParserError::FailedToParseReg(token.into())),
         }
     }
+
+    /// `&name` is a register pointer only for register names, otherwise it's a symbol address
+    pub fn operand(token: TokenEnum) -> TokenEnum {
+        match token {
+            TokenEnum::Ref(name) if Self::parse_reg(&name).is_err() => TokenEnum::MemSym(name),
+            token => token,
+        }
+    }
 }
 
 #[test]
 fn tests() {
-    use crate::lexer::tokenize_expr;
-
     let mut tokens = tokenize_expr("&1");
     let s = InstructionParser::expr_bp(&mut tokens, 0).unwrap();
     assert_eq!(s.to_string(), "&1");
@@ -331,6 +524,15 @@ fn tests() {
     let mut tokens = tokenize_expr("(&1 + &2) * &3");
     let s = InstructionParser::expr_bp(&mut tokens, 0).unwrap();
     assert_eq!(s.to_string(), "(* (+ &1 &2) &3)");
+
+    let s = InstructionParser::parse_expr("acc == $10 && r1 > $3 || !r2").unwrap();
+    assert_eq!(s.to_string(), "(|| (&& (== acc 10) (> r1 3)) (! r2))");
+
+    let s = InstructionParser::parse_expr("$1 + $2 <= $3 * $4 != $0").unwrap();
+    assert_eq!(s.to_string(), "(!= (<= (+ 1 2) (* 3 4)) 0)");
+
+    assert!(InstructionParser::parse_expr("($1 + $2").is_err());
+    assert!(InstructionParser::parse_expr("$1 $2").is_err());
 }
 
 #[test]
@@ -410,3 +612,62 @@ fn parse_expr() {
         )]
     );
 }
+
+#[test]
+fn parse_directives() {
+    let mut parser = InstructionParser::new();
+    let parsed = parser
+        .parse_lines(
+            "constant size = $4 ; comment, with $ymbols
+data8 bytes = { $1, $2,
+    $3 }
+structure Point { x: $2, y: $2 }
+mov &bytes, r1",
+        )
+        .unwrap();
+    assert_eq!(
+        parsed,
+        vec![
+            (
+                1,
+                Expr::new(
+                    ExprKind::Constant,
+                    ExprArgs::Double(TokenEnum::Ident("size".into()), TokenEnum::Lit(4))
+                )
+            ),
+            (
+                2,
+                Expr::new(
+                    ExprKind::Data8,
+                    ExprArgs::List(
+                        TokenEnum::Ident("bytes".into()),
+                        vec![TokenEnum::Lit(1), TokenEnum::Lit(2), TokenEnum::Lit(3)]
+                    )
+                )
+            ),
+            (
+                4,
+                Expr::new(
+                    ExprKind::Structure,
+                    ExprArgs::Fields(
+                        TokenEnum::Ident("Point".into()),
+                        vec![
+                            (TokenEnum::Ident("x".into()), TokenEnum::Lit(2)),
+                            (TokenEnum::Ident("y".into()), TokenEnum::Lit(2)),
+                        ]
+                    )
+                )
+            ),
+            (
+                5,
+                Expr::new(
+                    ExprKind::Mov,
+                    ExprArgs::Double(
+                        TokenEnum::MemSym("bytes".into()),
+                        TokenEnum::Ident("r1".into())
+                    )
+                )
+            ),
+        ]
+    );
+}
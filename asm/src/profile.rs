@@ -0,0 +1,212 @@
+use std::{collections::HashMap, fmt::Write};
+
+use vm::{instructions::Instructions, profile::Profile};
+
+use crate::debuginfo::DebugInfo;
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+#[cfg(test)]
+use vm::registers::Register;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ProfileFormat {
+    /// Tables by function, label, source line and opcode
+    Text,
+    /// `outer;inner count` lines for flamegraph tools
+    Folded,
+}
+
+pub fn render(format: ProfileFormat, profile: &Profile, debug_info: &DebugInfo) -> String {
+    match format {
+        ProfileFormat::Text => report(profile, debug_info),
+        ProfileFormat::Folded => folded(profile, debug_info),
+    }
+}
+
+/// Function is named after the symbol at its entry address
+fn function_name(entry: u16, debug_info: &DebugInfo) -> String {
+    match debug_info.symbol_at(entry) {
+        Some((symbol, 0)) => symbol.name.clone(),
+        Some((symbol, offset)) => format!("{}+{}", symbol.name, offset),
+        None => format!("{:#06x}", entry),
+    }
+}
+
+/// Sorted by count, biggest first, then by name
+fn sorted<K: Ord + Clone>(counts: HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut counts: Vec<(K, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// Self counts run on top of the stack, inclusive ones anywhere in it
+pub fn report(profile: &Profile, debug_info: &DebugInfo) -> String {
+    let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+    for (stack, count) in profile.stacks() {
+        let names: Vec<String> = stack
+            .iter()
+            .map(|x| function_name(*x, debug_info))
+            .collect();
+        let mut seen = Vec::new();
+        for name in &names {
+            // recursion counts once per stack
+            if !seen.contains(&name) {
+                functions.entry(name.clone()).or_default().1 += count;
+                seen.push(name);
+            }
+        }
+        functions
+            .entry(names.last().unwrap().clone())
+            .or_default()
+            .0 += count;
+    }
+    let mut functions: Vec<(String, (u64, u64))> = functions.into_iter().collect();
+    functions.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut labels = HashMap::new();
+    let mut lines = HashMap::new();
+    for (addr, count) in profile.addresses() {
+        let label = match debug_info.symbol_at(addr) {
+            Some((symbol, _)) => symbol.name.clone(),
+            None => "??".to_string(),
+        };
+        *labels.entry(label).or_default() += count;
+        let line = match debug_info.line_at(addr) {
+            Some(entry) => {
+                let path = debug_info.file_path(entry.file).unwrap_or("?");
+                format!("{}:{}", path, entry.line)
+            }
+            None => format!("{:#06x}", addr),
+        };
+        *lines.entry(line).or_default() += count;
+    }
+    let opcodes: HashMap<String, u64> = profile
+        .opcodes()
+        .map(|(opcode, count)| match Instructions::try_from(opcode) {
+            Ok(kind) => (format!("{:?}", kind), count),
+            Err(opcode) => (format!("{:#04x}", opcode), count),
+        })
+        .collect();
+
+    let total = profile.total();
+    let mut out = format!("{} instructions executed\n", total);
+    writeln!(
+        out,
+        "\n{:<24}{:>10}{:>10}{:>8}",
+        "function", "self", "total", "%"
+    )
+    .unwrap();
+    for (name, (own, inclusive)) in functions {
+        let percent = inclusive as f64 * 100.0 / total.max(1) as f64;
+        writeln!(
+            out,
+            "{:<24}{:>10}{:>10}{:>8.1}",
+            name, own, inclusive, percent
+        )
+        .unwrap();
+    }
+    for (title, counts) in [("label", labels), ("line", lines), ("opcode", opcodes)] {
+        writeln!(out, "\n{:<24}{:>10}", title, "count").unwrap();
+        for (name, count) in sorted(counts) {
+            writeln!(out, "{:<24}{:>10}", name, count).unwrap();
+        }
+    }
+    out
+}
+
+/// Folded stacks, one line per distinct stack of function names
+pub fn folded(profile: &Profile, debug_info: &DebugInfo) -> String {
+    let mut stacks: HashMap<String, u64> = HashMap::new();
+    for (stack, count) in profile.stacks() {
+        let names: Vec<String> = stack
+            .iter()
+            .map(|x| function_name(*x, debug_info))
+            .collect();
+        *stacks.entry(names.join(";")).or_default() += count;
+    }
+    let mut stacks: Vec<(String, u64)> = stacks.into_iter().collect();
+    stacks.sort();
+    stacks
+        .into_iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect()
+}
+
+#[test]
+fn profile_reports() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $3, acc
+loop:
+    push $0
+    call $square
+    inc r1
+    jne r1, &loop
+    hlt
+square:
+    mov r1, r2
+    push $0
+    call $noop
+    ret
+noop:
+    ret",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut cpu = machine(&program.segments).cpu;
+    let mut profile = Profile::new(0);
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let halted = cpu.step();
+        profile.count(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(
+        folded(&profile, &program.debug_info),
+        "start 14\nstart;square 12\nstart;square;noop 3\n"
+    );
+    assert_eq!(
+        report(&profile, &program.debug_info),
+        "29 instructions executed
+
+function                      self     total       %
+start                           14        29   100.0
+square                          12        15    51.7
+noop                             3         3    10.3
+
+label                        count
+loop                            13
+square                          12
+noop                             3
+start                            1
+
+line                         count
+main.s:10                        3
+main.s:11                        3
+main.s:12                        3
+main.s:13                        3
+main.s:15                        3
+main.s:4                         3
+main.s:5                         3
+main.s:6                         3
+main.s:7                         3
+main.s:2                         1
+main.s:8                         1
+
+opcode                       count
+CALL_LIT                         6
+PSH_LIT                          6
+RET                              6
+INC_REG                          3
+JNE_REG                          3
+MOV_REG_REG                      3
+HLT                              1
+MOV_LIT_REG                      1
+"
+    );
+}
@@ -0,0 +1,143 @@
+use std::{fmt::Write, ops::RangeInclusive};
+
+use vm::{
+    cpu::{Cpu, Exit},
+    device::MemoryMappedDevice,
+    registers::Register,
+};
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+
+/// Instructions `run` executes unless told otherwise
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+/// Exit status once fuel runs out, same as `timeout` uses
+pub const EXIT_OUT_OF_FUEL: i32 = 124;
+/// Exit status after any [`Fault`](vm::fault::Fault)
+pub const EXIT_FAULT: i32 = 125;
+/// Exit status of halted programs asking for a status in [`RESERVED`]
+pub const EXIT_RESERVED: i32 = 1;
+/// Statuses only `run` itself exits with
+pub const RESERVED: [i32; 2] = [EXIT_OUT_OF_FUEL, EXIT_FAULT];
+
+/// Low byte of `exit_reg` a halted program asks for, 0 without it
+pub fn halt_code(cpu: &Cpu, exit_reg: Option<Register>) -> i32 {
+    exit_reg.map_or(0, |x| (cpu.register(x) & 0xff) as i32)
+}
+
+/// Process exit status, halted programs exit with [`halt_code`] unless it is
+/// reserved, then with [`EXIT_RESERVED`]
+pub fn exit_status(exit: Exit, cpu: &Cpu, exit_reg: Option<Register>) -> i32 {
+    match exit {
+        Exit::Halted => match halt_code(cpu, exit_reg) {
+            code if RESERVED.contains(&code) => EXIT_RESERVED,
+            code => code,
+        },
+        Exit::OutOfFuel => EXIT_OUT_OF_FUEL,
+        Exit::Fault(_) => EXIT_FAULT,
+    }
+}
+
+/// Why the program stopped, one line
+pub fn describe(exit: Exit, cpu: &Cpu) -> String {
+    match exit {
+        Exit::Halted => format!("halted after {} instructions", cpu.executed()),
+        Exit::OutOfFuel => format!(
+            "out of fuel after {} instructions at {:#06x}",
+            cpu.executed(),
+            cpu.register(Register::Ip)
+        ),
+        Exit::Fault(fault) => format!("fault: {}", fault),
+    }
+}
+
+/// Registers when `registers` is set, then `ranges` of memory 8 bytes per row
+pub fn report(cpu: &Cpu, registers: bool, ranges: &[RangeInclusive<u16>]) -> String {
+    let mut out = String::new();
+    if registers {
+        for (reg, value) in cpu.registers() {
+            writeln!(out, "[{}]: {:#06x}", reg.name(), value).unwrap();
+        }
+    }
+    for range in ranges {
+        let (start, end) = (*range.start() as u32, *range.end() as u32);
+        for row in (start..=end).step_by(8) {
+            write!(out, "{:#06x}:", row).unwrap();
+            for addr in row..=end.min(row + 7) {
+                write!(out, " {:02x}", cpu.memory().peek_u8(addr as u16)).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn run_outcomes() {
+    let assemble = |source: &str| {
+        let parsed = InstructionParser::new().parse_lines(source).unwrap();
+        let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+        machine(&program.segments).cpu
+    };
+
+    let mut cpu = assemble(
+        "mov $0x1234, r1
+    mov r1, &0x0100
+    mov $0x0203, acc
+    hlt",
+    );
+    let exit = cpu.run_for(DEFAULT_FUEL);
+    assert_eq!(exit, Exit::Halted);
+    assert_eq!(exit_status(exit, &cpu, Some(Register::Acc)), 3);
+    assert_eq!(exit_status(exit, &cpu, None), 0);
+    assert_eq!(describe(exit, &cpu), "halted after 4 instructions");
+    let report = report(&cpu, true, &[0x0100..=0x0109]);
+    assert!(report.starts_with("[ip]: 0x000d\n[acc]: 0x0203\n[r1]: 0x1234\n"));
+    assert!(report.ends_with(
+        "[fp]: 0xfffe
+0x0100: 12 34 00 00 00 00 00 00
+0x0108: 00 00
+"
+    ));
+    // halting with a status of its own does not look like a fault
+    cpu.set_register(Register::Acc, 0x017d);
+    assert_eq!(halt_code(&cpu, Some(Register::Acc)), EXIT_FAULT);
+    assert_eq!(exit_status(exit, &cpu, Some(Register::Acc)), EXIT_RESERVED);
+    cpu.set_register(Register::Acc, 0x7c);
+    assert_eq!(exit_status(exit, &cpu, Some(Register::Acc)), EXIT_RESERVED);
+
+    let mut cpu = assemble(
+        "loop:
+    inc r1
+    jne r1, &loop
+    hlt",
+    );
+    let exit = cpu.run_for(11);
+    assert_eq!(
+        exit_status(exit, &cpu, Some(Register::Acc)),
+        EXIT_OUT_OF_FUEL
+    );
+    assert_eq!(
+        describe(exit, &cpu),
+        "out of fuel after 11 instructions at 0x0002"
+    );
+
+    let mut cpu = assemble("inc r1");
+    let exit = cpu.run_for(DEFAULT_FUEL);
+    assert_eq!(exit_status(exit, &cpu, Some(Register::Acc)), EXIT_FAULT);
+    assert_eq!(describe(exit, &cpu), "fault: invalid opcode 0x00 at 0x0002");
+
+    let mut cpu = assemble(
+        "push $1
+    push $2
+    push $3
+    hlt",
+    );
+    cpu.set_stack_limit(0xfffa);
+    let exit = cpu.run_for(DEFAULT_FUEL);
+    assert_eq!(exit_status(exit, &cpu, None), EXIT_FAULT);
+    assert_eq!(
+        describe(exit, &cpu),
+        "fault: stack overflow at 0x0006 with sp 0xfffa"
+    );
+}
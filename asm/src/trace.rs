@@ -0,0 +1,223 @@
+use std::{
+    io::{self, Write},
+    ops::RangeInclusive,
+};
+
+use serde_json::{json, Map, Value};
+use vm::{
+    cpu::Cpu,
+    instructions::Operand,
+    trace::{TraceEntry, TRACE_MAGIC},
+};
+
+use crate::debuginfo::{DebugInfo, SymbolKind};
+
+#[cfg(test)]
+use crate::{codegen::CodeGen, emu::machine, parse::InstructionParser};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// `16TR` magic followed by encoded entries, see `vm::trace`
+    Binary,
+    /// One JSON object per instruction
+    Jsonl,
+}
+
+/// Address range from `start-end` (inclusive) or a label or data symbol.
+/// Labels reach up to the next symbol, data symbols cover their size.
+pub fn parse_filter(spec: &str, debug_info: &DebugInfo) -> Result<RangeInclusive<u16>, String> {
+    let number = |x: &str| match x.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => x.parse().ok(),
+    };
+    if let Some((start, end)) = spec.split_once('-') {
+        return match (number(start), number(end)) {
+            (Some(start), Some(end)) if start <= end => Ok(start..=end),
+            _ => Err(format!("invalid address range `{}`", spec)),
+        };
+    }
+
+    let symbol = debug_info
+        .symbol(spec)
+        .filter(|x| x.kind != SymbolKind::Constant)
+        .ok_or(format!("no label or data symbol `{}`", spec))?;
+    if symbol.size > 0 {
+        return Ok(symbol.value..=symbol.value.saturating_add(symbol.size - 1));
+    }
+    let end = debug_info
+        .symbols
+        .iter()
+        .filter(|x| x.kind != SymbolKind::Constant && x.value > symbol.value)
+        .map(|x| x.value - 1)
+        .min()
+        .unwrap_or(u16::MAX);
+    Ok(symbol.value..=end)
+}
+
+/// Writes entries for instructions inside any of `ranges`, everything if empty
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    ranges: Vec<RangeInclusive<u16>>,
+    debug_info: DebugInfo,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(
+        mut out: W,
+        format: TraceFormat,
+        ranges: Vec<RangeInclusive<u16>>,
+        debug_info: DebugInfo,
+    ) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            out.write_all(TRACE_MAGIC)?;
+        }
+        Ok(Self {
+            out,
+            format,
+            ranges,
+            debug_info,
+        })
+    }
+
+    /// Runs `cpu` until it halts
+    pub fn run(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        while !self.step(cpu)? {}
+        Ok(())
+    }
+
+    /// Executes and records one instruction, `true` once halted. Instructions
+    /// faulting before they run leave no entry.
+    pub fn step(&mut self, cpu: &mut Cpu) -> io::Result<bool> {
+        let executed = cpu.executed();
+        let (halted, entry) = TraceEntry::step(cpu);
+        if cpu.executed() != executed {
+            self.record(&entry)?;
+        }
+        if halted {
+            self.out.flush()?;
+        }
+        Ok(halted)
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|x| x.contains(&entry.ip)) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Binary => {
+                let mut bytes = Vec::new();
+                entry.encode(&mut bytes);
+                self.out.write_all(&bytes)
+            }
+            TraceFormat::Jsonl => writeln!(self.out, "{}", self.to_json(entry)),
+        }
+    }
+
+    fn to_json(&self, entry: &TraceEntry) -> Value {
+        let operands: Vec<Value> = entry
+            .operands
+            .iter()
+            .map(|(operand, value)| match operand {
+                Operand::Reg(reg) => json!({ "reg": reg.name(), "value": value }),
+                Operand::Lit(_) => json!({ "lit": value }),
+                Operand::Addr(_) => json!({ "addr": value }),
+            })
+            .collect();
+        let registers: Map<String, Value> = entry
+            .registers
+            .iter()
+            .map(|(reg, value)| (reg.name().to_string(), json!(value)))
+            .collect();
+        let writes: Vec<Value> = entry
+            .writes
+            .iter()
+            .map(|x| json!({ "addr": x.addr, "old": x.old, "new": x.new }))
+            .collect();
+
+        let mut json = json!({
+            "index": entry.index,
+            "ip": entry.ip,
+            "opcode": entry.opcode,
+            "op": entry.kind.map_or("(bad)", |x| x.mnemonic()),
+            "operands": operands,
+            "regs": registers,
+            "writes": writes,
+        });
+        match self.debug_info.symbol_at(entry.ip) {
+            Some((symbol, 0)) => json["at"] = json!(symbol.name),
+            Some((symbol, offset)) => json["at"] = json!(format!("{}+{}", symbol.name, offset)),
+            None => {}
+        }
+        json
+    }
+}
+
+#[test]
+fn trace_export() {
+    let parsed = InstructionParser::new()
+        .parse_lines(
+            "start:
+    mov $2, acc
+loop:
+    inc r1
+    mov r1, &slot
+    jne r1, &loop
+    hlt
+data16 slot = { $0 }",
+        )
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let debug_info = program.debug_info;
+
+    assert_eq!(parse_filter("loop", &debug_info), Ok(0x0004..=0x000e));
+    assert_eq!(parse_filter("slot", &debug_info), Ok(0x000f..=0x0010));
+    assert_eq!(parse_filter("0x10-0x20", &debug_info), Ok(0x0010..=0x0020));
+    assert!(parse_filter("0x20-0x10", &debug_info).is_err());
+    assert!(parse_filter("nope", &debug_info).is_err());
+
+    let mut out = Vec::new();
+    let mut tracer = Tracer::new(
+        &mut out,
+        TraceFormat::Jsonl,
+        vec![0x0006..=0x0009],
+        debug_info.clone(),
+    )
+    .unwrap();
+    tracer.run(&mut machine(&program.segments).cpu).unwrap();
+    let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"at":"loop+2","index":2,"ip":6,"op":"mov","opcode":18,"operands":[{"reg":"r1","value":1},{"addr":15}],"regs":{},"writes":[{"addr":15,"new":0,"old":0},{"addr":16,"new":1,"old":0}]}"#,
+            r#"{"at":"loop+2","index":5,"ip":6,"op":"mov","opcode":18,"operands":[{"reg":"r1","value":2},{"addr":15}],"regs":{},"writes":[{"addr":15,"new":0,"old":0},{"addr":16,"new":2,"old":1}]}"#,
+        ]
+    );
+
+    let mut out = Vec::new();
+    let mut tracer = Tracer::new(&mut out, TraceFormat::Binary, vec![], debug_info).unwrap();
+    tracer.run(&mut machine(&program.segments).cpu).unwrap();
+    assert_eq!(&out[..4], TRACE_MAGIC);
+    let mut rest = &out[4..];
+    let mut entries = Vec::new();
+    while let Some((entry, size)) = TraceEntry::decode(rest) {
+        entries.push(entry);
+        rest = &rest[size..];
+    }
+    assert!(rest.is_empty());
+    // mov, two loop iterations and hlt
+    assert_eq!(entries.len(), 8);
+    assert_eq!(entries.last().unwrap().index, 7);
+
+    // ret without a frame faults before it runs
+    let parsed = InstructionParser::new()
+        .parse_lines("start:\n    mov $1, r1\n    ret")
+        .unwrap();
+    let program = CodeGen::new().assemble("main.s", &parsed).unwrap();
+    let mut out = Vec::new();
+    let mut tracer = Tracer::new(&mut out, TraceFormat::Jsonl, vec![], DebugInfo::new()).unwrap();
+    let mut cpu = machine(&program.segments).cpu;
+    tracer.run(&mut cpu).unwrap();
+    assert!(cpu.fault().is_some());
+    assert_eq!(std::str::from_utf8(&out).unwrap().lines().count(), 1);
+}
@@ -0,0 +1,179 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::device::MemoryMappedDevice;
+
+#[cfg(test)]
+use crate::{cpu::Cpu, mapper::Region, memory::Memory, registers::Register};
+
+/// Where emu maps the bank window, `bank` region of `layouts/vm.layout`
+pub const BANK_START: u16 = 0xd000;
+pub const BANK_END: u16 = 0xefff;
+/// Bank select register, inside `io` window after the interrupt controller
+pub const BANK_SELECT: u16 = 0x310a;
+/// Bank size that fills the window
+pub const BANK_SIZE: usize = 0x2000;
+
+/// `count` banks of `size` bytes, one of them visible at a time. Map it over
+/// a window of `size` bytes and [`BankSelect`] somewhere else to switch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BankedMemory {
+    banks: Vec<Vec<u8>>,
+    selected: usize,
+}
+
+impl BankedMemory {
+    pub fn new(count: usize, size: usize) -> Self {
+        assert!(
+            count > 0 && size > 0,
+            "banked memory needs at least one bank of one byte"
+        );
+        Self {
+            banks: vec![vec![0; size]; count],
+            selected: 0,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.banks[0].len()
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Banks past the count wrap around
+    pub fn select(&mut self, bank: usize) {
+        self.selected = bank % self.count();
+    }
+
+    /// Contents of any bank, selected or not
+    pub fn bank(&self, bank: usize) -> Option<&[u8]> {
+        self.banks.get(bank).map(Vec::as_slice)
+    }
+
+    pub fn bank_mut(&mut self, bank: usize) -> Option<&mut [u8]> {
+        self.banks.get_mut(bank).map(Vec::as_mut_slice)
+    }
+}
+
+impl MemoryMappedDevice for BankedMemory {
+    fn get_u8(&self, addr: u16) -> u8 {
+        let bank = &self.banks[self.selected];
+        bank[addr as usize % bank.len()]
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let bank = &mut self.banks[self.selected];
+        let len = bank.len();
+        bank[addr as usize % len] = value;
+    }
+
+    /// Selected bank as big-endian word, then every bank in order
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state = (self.selected as u16).to_be_bytes().to_vec();
+        for bank in &self.banks {
+            state.extend(bank);
+        }
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 2 + self.count() * self.size() {
+            return false;
+        }
+        let selected = u16::from_be_bytes([state[0], state[1]]) as usize;
+        if selected >= self.count() {
+            return false;
+        }
+        let size = self.size();
+        for (bank, bytes) in self.banks.iter_mut().zip(state[2..].chunks(size)) {
+            bank.copy_from_slice(bytes);
+        }
+        self.selected = selected;
+        true
+    }
+}
+
+/// Big-endian word register holding the selected bank of shared banked memory
+#[derive(Debug, Clone)]
+pub struct BankSelect {
+    banks: Rc<RefCell<BankedMemory>>,
+}
+
+impl BankSelect {
+    pub fn new(banks: Rc<RefCell<BankedMemory>>) -> Self {
+        Self { banks }
+    }
+}
+
+impl MemoryMappedDevice for BankSelect {
+    fn get_u8(&self, addr: u16) -> u8 {
+        self.get_u16(addr & !1).to_be_bytes()[(addr & 1) as usize]
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let mut word = self.get_u16(addr & !1).to_be_bytes();
+        word[(addr & 1) as usize] = value;
+        self.set_u16(addr & !1, u16::from_be_bytes(word));
+    }
+
+    fn get_u16(&self, _addr: u16) -> u16 {
+        self.banks.borrow().selected() as u16
+    }
+
+    fn set_u16(&mut self, _addr: u16, value: u16) {
+        self.banks.borrow_mut().select(value as usize);
+    }
+}
+
+#[test]
+fn bank_switching() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x1B, 0x11, 0x11, 0xD0, 0x00, // mov $0x1111, &0xd000
+            0x1B, 0x00, 0x02, 0x31, 0x0A, // mov $2, &0x310a
+            0x1B, 0x22, 0x22, 0xD0, 0x00, // mov $0x2222, &0xd000
+            0x1B, 0x00, 0x05, 0x31, 0x0A, // mov $5, &0x310a
+            0x13, 0xD0, 0x00, 0x02, // mov &0xd000, r1
+            0xFF,
+        ],
+    );
+    let banks = Rc::new(RefCell::new(BankedMemory::new(4, BANK_SIZE)));
+    let mut cpu = Cpu::new(memory);
+    cpu.memory_mut()
+        .map(Region::new(banks.clone(), BANK_START, BANK_END, true));
+    cpu.memory_mut().map(Region::new(
+        BankSelect::new(banks.clone()),
+        BANK_SELECT,
+        BANK_SELECT + 1,
+        true,
+    ));
+    cpu.record(16);
+    cpu.run();
+
+    // 5 wraps around to bank 1, which nobody wrote to
+    assert_eq!(banks.borrow().selected(), 1);
+    assert_eq!(cpu.register(Register::R1), 0);
+    assert_eq!(cpu.memory().get_u16(BANK_SELECT), 1);
+    assert_eq!(banks.borrow().bank(0).unwrap()[..2], [0x11, 0x11]);
+    assert_eq!(banks.borrow().bank(2).unwrap()[..2], [0x22, 0x22]);
+    assert_eq!(banks.borrow().bank(4), None);
+
+    // undo restores the selection before the bytes written under it
+    while cpu.step_back().is_some() {}
+    assert_eq!(banks.borrow().selected(), 0);
+    assert_eq!(banks.borrow().bank(0).unwrap()[..2], [0, 0]);
+    assert_eq!(banks.borrow().bank(2).unwrap()[..2], [0, 0]);
+
+    let mut other = BankedMemory::new(4, BANK_SIZE);
+    banks.borrow_mut().select(3);
+    assert!(other.load_state(&banks.borrow().save_state().unwrap()));
+    assert_eq!(other, *banks.borrow());
+    assert!(!BankedMemory::new(2, 16).load_state(&[0, 0]));
+}
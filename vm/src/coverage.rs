@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::{cpu::Cpu, instructions::Instruction};
+
+#[cfg(test)]
+use crate::{
+    device::MemoryMappedDevice,
+    mapper::Region,
+    memory::Memory,
+    registers::Register,
+    timer::{Timer, TIMER_ENABLE, TIMER_END, TIMER_INTERRUPT, TIMER_LINE, TIMER_START},
+};
+
+/// How often a conditional jump went each way
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Executed instruction addresses and conditional jump outcomes
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    branches: BTreeMap<u16, Branch>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; 0x10000],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Records `instruction` once `cpu` executed it. Jump to the instruction
+    /// right after it counts as not taken, even when an interrupt came next.
+    pub fn record(&mut self, instruction: &Instruction, cpu: &Cpu) {
+        let ip = cpu.next_ip();
+        self.hits[instruction.address as usize] += 1;
+        if !instruction.kind.is_some_and(|x| x.is_conditional_jump()) {
+            return;
+        }
+        let branch = self.branches.entry(instruction.address).or_default();
+        if ip == instruction.address.wrapping_add(instruction.size) {
+            branch.not_taken += 1;
+        } else {
+            branch.taken += 1;
+        }
+    }
+
+    /// Times instruction at `addr` was executed
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits[addr as usize]
+    }
+
+    /// Outcomes of conditional jump at `addr`, `None` if it never ran
+    pub fn branch(&self, addr: u16) -> Option<Branch> {
+        self.branches.get(&addr).copied()
+    }
+
+    /// Executed conditional jumps in address order
+    pub fn branches(&self) -> impl Iterator<Item = (u16, Branch)> + '_ {
+        self.branches.iter().map(|(addr, branch)| (*addr, *branch))
+    }
+}
+
+#[test]
+fn coverage_branches() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x10, 0x00, 0x02, 0x01, // mov $2, acc
+            0x35, 0x02, // inc r1
+            0x40, 0x02, 0x00, 0x04, // jne r1, &0x0004
+            0x41, 0x00, 0x00, 0x00, 0x00, // jeq $0, &0x0000
+            0xFF,
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let mut coverage = Coverage::new();
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let halted = cpu.step();
+        coverage.record(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(coverage.hits(0x00), 1);
+    assert_eq!(coverage.hits(0x04), 2);
+    assert_eq!(coverage.hits(0x05), 0);
+    assert_eq!(coverage.hits(0x0F), 1);
+    assert_eq!(
+        coverage.branch(0x06),
+        Some(Branch {
+            taken: 1,
+            not_taken: 1
+        })
+    );
+    assert_eq!(
+        coverage.branches().collect::<Vec<_>>(),
+        vec![
+            (
+                0x06,
+                Branch {
+                    taken: 1,
+                    not_taken: 1
+                }
+            ),
+            (
+                0x0A,
+                Branch {
+                    taken: 0,
+                    not_taken: 1
+                }
+            ),
+        ]
+    );
+    assert_eq!(coverage.branch(0x00), None);
+}
+
+#[test]
+fn coverage_interrupted_branch() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x10, 0x00, 0x00, 0x01, // mov $0, acc
+            0x40, 0x02, 0x00, 0x00, // jne r1, &0x0000
+            0xFF,
+        ],
+    );
+    memory.load(0x0100, &[0x02, 0x00]);
+    memory.load(0x0200, &[0xFC]); // rti
+    let mut cpu = Cpu::new(memory);
+    cpu.interrupts().set_vectors(0x0100);
+    // expires on the second instruction, the handler runs right after `jne`
+    let mut timer = Timer::new(cpu.interrupt_line(TIMER_LINE));
+    timer.set_u16(2, 2);
+    timer.set_u16(0, TIMER_ENABLE | TIMER_INTERRUPT);
+    cpu.memory_mut()
+        .map(Region::new(timer, TIMER_START, TIMER_END, true));
+    let mut coverage = Coverage::new();
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let halted = cpu.step();
+        if instruction.address == 0x04 {
+            assert_eq!(cpu.interrupted(), Some(0x08));
+        }
+        coverage.record(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(coverage.hits(0x0200), 1);
+    assert_eq!(
+        coverage.branch(0x04),
+        Some(Branch {
+            taken: 0,
+            not_taken: 1
+        })
+    );
+}
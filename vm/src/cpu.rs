@@ -0,0 +1,777 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    device::MemoryMappedDevice,
+    fault::Fault,
+    history::{History, Record},
+    instructions::{Instruction, Instructions, Operand, OperandKind},
+    interrupt::{InterruptController, InterruptLine, Interrupts},
+    mapper::MemoryMapper,
+    registers::Register,
+    snapshot::{Snapshot, SnapshotError},
+    timing::{CostTable, CycleCounter},
+};
+
+#[cfg(test)]
+use crate::{mapper::MemoryWrite, memory::Memory};
+
+/// Initial `sp` and `fp`, stack grows down from the top of memory
+pub const STACK_START: u16 = 0xffff - 1;
+
+/// Why [`Cpu::run_for`] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// `hlt` was executed
+    Halted,
+    /// Fuel ran out before the program halted
+    OutOfFuel,
+    /// Instruction faulted, see [`Cpu::fault`]
+    Fault(Fault),
+}
+
+/// Rust counterpart of `core/src/cpu/cpu.cpp`, same register file and frame layout
+#[derive(Debug)]
+pub struct Cpu {
+    memory: MemoryMapper,
+    registers: [u16; 12],
+    // bytes pushed since the current frame was entered
+    frame_size: u16,
+    executed: u64,
+    // shared with cycle counter devices
+    cycles: Rc<Cell<u64>>,
+    costs: CostTable,
+    history: Option<History>,
+    fault: Option<Fault>,
+    // lowest `sp` pushes may leave behind
+    stack_limit: u16,
+    // shared with the interrupt controller and devices raising lines
+    interrupts: Rc<Interrupts>,
+    // interrupt handlers entered and not returned from with `rti`
+    handlers: u16,
+    // `ip` the last instruction left when a handler was entered right after it
+    interrupted: Option<u16>,
+}
+
+impl Cpu {
+    /// Takes a ready mapper or plain [`Memory`](crate::memory::Memory) for the whole bus
+    pub fn new(memory: impl Into<MemoryMapper>) -> Self {
+        let mut cpu = Self {
+            memory: memory.into(),
+            registers: [0; 12],
+            frame_size: 0,
+            executed: 0,
+            cycles: Rc::new(Cell::new(0)),
+            costs: CostTable::default(),
+            history: None,
+            fault: None,
+            stack_limit: 0,
+            interrupts: Rc::new(Interrupts::default()),
+            handlers: 0,
+            interrupted: None,
+        };
+        cpu.set_register(Register::Sp, STACK_START);
+        cpu.set_register(Register::Fp, STACK_START);
+        cpu
+    }
+
+    pub fn register(&self, reg: Register) -> u16 {
+        self.registers[reg as usize]
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: u16) {
+        self.registers[reg as usize] = value;
+    }
+
+    pub fn registers(&self) -> impl Iterator<Item = (Register, u16)> + '_ {
+        Register::ALL.iter().map(|x| (*x, self.register(*x)))
+    }
+
+    pub fn memory(&self) -> &MemoryMapper {
+        &self.memory
+    }
+
+    /// Devices can be mapped and unmapped while the CPU is running
+    pub fn memory_mut(&mut self) -> &mut MemoryMapper {
+        &mut self.memory
+    }
+
+    /// Instructions executed so far, undone ones are not counted
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Cycles spent so far according to [`Self::costs`], undone ones are not counted
+    pub fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    pub fn costs(&self) -> &CostTable {
+        &self.costs
+    }
+
+    pub fn set_costs(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    /// Why the last step stopped the CPU if it was not `hlt`
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn stack_limit(&self) -> u16 {
+        self.stack_limit
+    }
+
+    /// Pushes and calls moving `sp` below `limit` fault with
+    /// [`Fault::StackOverflow`], 0 only catches wrapping around
+    pub fn set_stack_limit(&mut self, limit: u16) {
+        self.stack_limit = limit;
+    }
+
+    pub fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    /// Interrupt handlers running, hardware interrupts wait until it is 0
+    pub fn handlers(&self) -> u16 {
+        self.handlers
+    }
+
+    /// Where the last instruction sent execution when an interrupt handler
+    /// was entered right after it, `None` if none was
+    pub fn interrupted(&self) -> Option<u16> {
+        self.interrupted
+    }
+
+    /// Where the last instruction sent execution, before any handler
+    pub fn next_ip(&self) -> u16 {
+        self.interrupted
+            .unwrap_or_else(|| self.register(Register::Ip))
+    }
+
+    /// Handle for a device raising `line`
+    pub fn interrupt_line(&self, line: u8) -> InterruptLine {
+        InterruptLine::new(self.interrupts.clone(), line)
+    }
+
+    /// Device exposing interrupt mask and vector table to programs once mapped
+    pub fn interrupt_controller(&self) -> InterruptController {
+        InterruptController::new(self.interrupts.clone())
+    }
+
+    /// Device showing [`Self::cycles`] to programs once mapped
+    pub fn cycle_counter(&self) -> CycleCounter {
+        CycleCounter::new(self.cycles.clone())
+    }
+
+    /// Registers, frame bookkeeping, counters and every mapped device
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            frame_size: self.frame_size,
+            handlers: self.handlers,
+            executed: self.executed,
+            cycles: self.cycles(),
+            devices: self.memory.save_states(),
+        }
+    }
+
+    /// Brings back state from [`Self::snapshot`] and forgets undo history.
+    /// Devices have to be mapped like they were when it was taken.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        self.memory.load_states(&snapshot.devices)?;
+        self.registers = snapshot.registers;
+        self.frame_size = snapshot.frame_size;
+        self.handlers = snapshot.handlers;
+        self.executed = snapshot.executed;
+        self.cycles.set(snapshot.cycles);
+        self.fault = None;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    /// Keep undo records for the last `depth` instructions, see [`Self::step_back`]
+    pub fn record(&mut self, depth: usize) {
+        self.history = Some(History::new(depth));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undoes the last recorded instruction, `None` once history is exhausted
+    pub fn step_back(&mut self) -> Option<Record> {
+        let record = self.history.as_mut()?.pop()?;
+        self.memory.restore(&record.writes);
+        // after the writes, those went through device registers
+        self.memory.undo(&record.devices);
+        self.interrupts.set_pending(record.pending);
+        self.registers = record.registers;
+        self.frame_size = record.frame_size;
+        self.handlers = record.handlers;
+        self.executed -= 1;
+        self.cycles.set(self.cycles.get() - record.cycles);
+        self.fault = None;
+        Some(record)
+    }
+
+    pub fn decode(&self, addr: u16) -> Instruction {
+        Instruction::decode(addr, |x| self.memory.get_u8(x))
+    }
+
+    /// Decodes the instruction at `addr` the way core fetches it, which reads
+    /// the literal of `SUB_REG_LIT` before its register
+    fn fetch(&self, addr: u16) -> Instruction {
+        let mut instruction = self.decode(addr);
+        if instruction.kind == Some(Instructions::SUB_REG_LIT) {
+            let read = |x: u16| self.memory.get_u8(addr.wrapping_add(x));
+            instruction.operands = vec![
+                Operand::Lit(u16::from_be_bytes([read(1), read(2)])),
+                Operand::Reg(Register::from_index(read(3))),
+            ];
+        }
+        instruction
+    }
+
+    /// Executes one instruction, returns `true` once the CPU halted or faulted
+    pub fn step(&mut self) -> bool {
+        let (halted, record) = self.step_inner(self.history.is_some());
+        if let (Some(history), Some(record)) = (&mut self.history, record) {
+            history.push(record);
+        }
+        halted
+    }
+
+    /// Like [`Self::step`], also returns undo record of the instruction.
+    /// Instructions faulting before they run get an empty one.
+    pub fn step_recorded(&mut self) -> (bool, Record) {
+        let (halted, record) = self.step_inner(true);
+        let record = match record {
+            Some(record) => {
+                if let Some(history) = &mut self.history {
+                    history.push(record.clone());
+                }
+                record
+            }
+            None => Record {
+                registers: self.registers,
+                frame_size: self.frame_size,
+                handlers: self.handlers,
+                cycles: 0,
+                writes: Vec::new(),
+                devices: Vec::new(),
+                pending: self.interrupts.pending(),
+                hits: Vec::new(),
+            },
+        };
+        (halted, record)
+    }
+
+    // writes are left out of the record unless `journal` is set, no record
+    // when the instruction faulted before it ran
+    fn step_inner(&mut self, journal: bool) -> (bool, Option<Record>) {
+        self.fault = None;
+        self.interrupted = None;
+        // misses of debugger reads since the last step are not this instruction's
+        self.memory.take_unmapped();
+        let instruction = self.fetch(self.register(Register::Ip));
+        let fault = match self.memory.take_unmapped() {
+            Some((addr, access)) => Some(Fault::UnmappedMemory(instruction.address, addr, access)),
+            None => self.check(&instruction),
+        };
+        // fetching and checks are not data accesses for watchpoints
+        self.memory.take_hits();
+        self.memory.take_unmapped();
+        if fault.is_some() {
+            self.fault = fault;
+            return (true, None);
+        }
+        let (registers, frame_size, handlers) = (self.registers, self.frame_size, self.handlers);
+        let (mut devices, pending) = (Vec::new(), self.interrupts.pending());
+        if journal {
+            self.memory.start_journal();
+            devices = self.memory.undo_states();
+        }
+
+        let next = instruction.address.wrapping_add(instruction.size);
+        self.set_register(Register::Ip, next);
+        let halted = self.execute(&instruction);
+        self.executed += 1;
+        let mut cycles = self.costs.cost(instruction.opcode);
+        if let Some((addr, access)) = self.memory.take_unmapped() {
+            self.fault = Some(Fault::UnmappedMemory(instruction.address, addr, access));
+        } else if !halted {
+            // devices may raise lines for the interrupt check right after
+            self.memory.tick(cycles);
+            cycles += self.deliver_interrupt();
+        }
+        self.cycles.set(self.cycles.get() + cycles);
+
+        let record = Record {
+            registers,
+            frame_size,
+            handlers,
+            cycles,
+            writes: self.memory.take_journal(),
+            devices,
+            pending,
+            hits: self.memory.pending_hits(),
+        };
+        (halted || self.fault.is_some(), Some(record))
+    }
+
+    /// Enters handler of the lowest pending line between instructions like
+    /// `int` does, returns cycles it took. Lines wait while a handler runs.
+    fn deliver_interrupt(&mut self) -> u64 {
+        let Some(line) = self.interrupts.next().filter(|_| self.handlers == 0) else {
+            return 0;
+        };
+        let (ip, sp) = (self.register(Register::Ip), self.register(Register::Sp));
+        if (sp as i32) - 22 < self.stack_limit as i32 {
+            self.fault = Some(Fault::StackOverflow(ip, sp));
+            return 0;
+        }
+        self.interrupts.acknowledge(line);
+        self.interrupted = Some(ip);
+        self.interrupt(line);
+        self.costs.cost(Instructions::INT_LIT as u8)
+    }
+
+    /// Fault `instruction` would cause, register operands are read again
+    /// since decoding wraps their index
+    fn check(&self, instruction: &Instruction) -> Option<Fault> {
+        use Instructions::*;
+
+        let ip = instruction.address;
+        let Some(kind) = instruction.kind else {
+            return Some(Fault::InvalidOpcode(ip, instruction.opcode));
+        };
+        let operands = match kind {
+            SUB_REG_LIT => &[OperandKind::Lit, OperandKind::Reg],
+            kind => kind.operands(),
+        };
+        let mut pos = ip.wrapping_add(1);
+        for operand in operands {
+            let index = self.memory.get_u8(pos);
+            if *operand == OperandKind::Reg && index as usize >= Register::ALL.len() {
+                return Some(Fault::InvalidRegister(ip, index));
+            }
+            pos = pos.wrapping_add(operand.size());
+        }
+
+        let (sp, fp) = (self.register(Register::Sp), self.register(Register::Fp));
+        let pushed = match (kind, instruction.operands.as_slice()) {
+            (PSH_LIT | PSH_REG, _) => 1,
+            (CALL_LIT | CALL_REG, _) => 10,
+            (INT_LIT, [Operand::Lit(line)]) if self.interrupts.enabled(*line as u8) => 11,
+            _ => 0,
+        };
+        if pushed > 0 && (sp as i32) - 2 * pushed < self.stack_limit as i32 {
+            return Some(Fault::StackOverflow(ip, sp));
+        }
+        match kind {
+            POP if sp as u32 + 2 > STACK_START as u32 => Some(Fault::StackUnderflow(ip, sp)),
+            RET | RET_INT if fp == STACK_START => Some(Fault::NoFrame(ip)),
+            RET | RET_INT => {
+                // argument count sits above the saved registers, see `pop_state`
+                let args = self.memory.get_u16(fp.wrapping_add(22));
+                let end = fp as u32 + 22 + 2 * args as u32;
+                (end > STACK_START as u32).then_some(Fault::StackUnderflow(ip, sp))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        while !self.step() {}
+    }
+
+    /// Executes at most `fuel` instructions, so endless loops stop too
+    pub fn run_for(&mut self, fuel: u64) -> Exit {
+        for _ in 0..fuel {
+            if self.step() {
+                return self.fault.map_or(Exit::Halted, Exit::Fault);
+            }
+        }
+        Exit::OutOfFuel
+    }
+
+    /// `ip` has to point past `instruction` already
+    pub fn execute(&mut self, instruction: &Instruction) -> bool {
+        use Instructions::*;
+        use Operand::*;
+
+        let acc = self.register(Register::Acc);
+        match (instruction.kind, instruction.operands.as_slice()) {
+            (Some(MOV_LIT_REG), [Lit(lit), Reg(reg)]) => self.set_register(*reg, *lit),
+            (Some(MOV_REG_REG), [Reg(from), Reg(to)]) => {
+                self.set_register(*to, self.register(*from))
+            }
+            (Some(MOV_REG_MEM), [Reg(from), Addr(addr)]) => {
+                self.memory.set_u16(*addr, self.register(*from))
+            }
+            (Some(MOV_MEM_REG), [Addr(addr), Reg(to)]) => {
+                self.set_register(*to, self.memory.get_u16(*addr))
+            }
+            (Some(MOV_LIT_MEM), [Lit(lit), Addr(addr)]) => self.memory.set_u16(*addr, *lit),
+            (Some(MOV_REG_PTR_REG), [Reg(ptr), Reg(to)]) => {
+                let value = self.memory.get_u16(self.register(*ptr));
+                self.set_register(*to, value)
+            }
+            (Some(MOV_LIT_OFF_REG), [Addr(base), Reg(offset), Reg(to)]) => {
+                let addr = base.wrapping_add(self.register(*offset));
+                self.set_register(*to, self.memory.get_u16(addr))
+            }
+
+            (Some(ADD_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1).wrapping_add(self.register(*r2)))
+            }
+            (Some(ADD_LIT_REG), [Lit(lit), Reg(reg)]) => {
+                self.alu(lit.wrapping_add(self.register(*reg)))
+            }
+            (Some(SUB_LIT_REG), [Lit(lit), Reg(reg)]) => {
+                self.alu(self.register(*reg).wrapping_sub(*lit))
+            }
+            // operands in core's fetch order, see `fetch`
+            (Some(SUB_REG_LIT), [Lit(lit), Reg(reg)]) => {
+                self.alu(lit.wrapping_sub(self.register(*reg)))
+            }
+            (Some(SUB_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1).wrapping_sub(self.register(*r2)))
+            }
+            // core adds here
+            (Some(MUL_LIT_REG), [Lit(lit), Reg(reg)]) => {
+                self.alu(lit.wrapping_add(self.register(*reg)))
+            }
+            (Some(MUL_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1).wrapping_mul(self.register(*r2)))
+            }
+            (Some(INC_REG), [Reg(reg)]) => {
+                self.set_register(*reg, self.register(*reg).wrapping_add(1))
+            }
+            (Some(DEC_REG), [Reg(reg)]) => {
+                self.set_register(*reg, self.register(*reg).wrapping_sub(1))
+            }
+
+            (Some(LSF_REG_LIT), [Reg(reg), Lit(lit)]) => {
+                self.set_register(*reg, shift_left(self.register(*reg), *lit))
+            }
+            (Some(LSF_REG_REG), [Reg(reg), Reg(by)]) => {
+                self.set_register(*reg, shift_left(self.register(*reg), self.register(*by)))
+            }
+            (Some(RSF_REG_LIT), [Reg(reg), Lit(lit)]) => {
+                self.set_register(*reg, shift_right(self.register(*reg), *lit))
+            }
+            (Some(RSF_REG_REG), [Reg(reg), Reg(by)]) => {
+                self.set_register(*reg, shift_right(self.register(*reg), self.register(*by)))
+            }
+            (Some(AND_REG_LIT), [Reg(reg), Lit(lit)]) => self.alu(self.register(*reg) & lit),
+            (Some(AND_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1) & self.register(*r2))
+            }
+            (Some(OR_REG_LIT), [Reg(reg), Lit(lit)]) => self.alu(self.register(*reg) | lit),
+            (Some(OR_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1) | self.register(*r2))
+            }
+            (Some(XOR_REG_LIT), [Reg(reg), Lit(lit)]) => self.alu(self.register(*reg) ^ lit),
+            (Some(XOR_REG_REG), [Reg(r1), Reg(r2)]) => {
+                self.alu(self.register(*r1) ^ self.register(*r2))
+            }
+            (Some(NOT), [Reg(reg)]) => self.set_register(*reg, !self.register(*reg)),
+
+            // jumps compare the operand against acc: `value OP acc`
+            (Some(JMP_NOT_EQ), [Lit(value), Addr(addr)]) => self.jump(*value != acc, *addr),
+            (Some(JNE_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) != acc, *addr),
+            (Some(JEQ_LIT), [Lit(value), Addr(addr)]) => self.jump(*value == acc, *addr),
+            (Some(JEQ_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) == acc, *addr),
+            (Some(JLT_LIT), [Lit(value), Addr(addr)]) => self.jump(*value < acc, *addr),
+            (Some(JLT_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) < acc, *addr),
+            (Some(JGT_LIT), [Lit(value), Addr(addr)]) => self.jump(*value > acc, *addr),
+            (Some(JGT_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) > acc, *addr),
+            (Some(JLE_LIT), [Lit(value), Addr(addr)]) => self.jump(*value <= acc, *addr),
+            (Some(JLE_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) <= acc, *addr),
+            (Some(JGE_LIT), [Lit(value), Addr(addr)]) => self.jump(*value >= acc, *addr),
+            (Some(JGE_REG), [Reg(reg), Addr(addr)]) => self.jump(self.register(*reg) >= acc, *addr),
+
+            (Some(PSH_LIT), [Lit(lit)]) => self.push(*lit),
+            (Some(PSH_REG), [Reg(reg)]) => self.push(self.register(*reg)),
+            (Some(POP), [Reg(reg)]) => {
+                let value = self.pop();
+                self.set_register(*reg, value)
+            }
+            (Some(CALL_LIT), [Addr(addr)]) => self.call(*addr),
+            (Some(CALL_REG), [Reg(reg)]) => self.call(self.register(*reg)),
+            (Some(RET), []) => self.pop_state(),
+            (Some(INT_LIT), [Lit(line)]) => {
+                if self.interrupts.enabled(*line as u8) {
+                    self.interrupt(*line as u8);
+                }
+            }
+            (Some(RET_INT), []) => {
+                self.handlers = self.handlers.saturating_sub(1);
+                self.pop_state()
+            }
+
+            // HLT, unknown opcodes stop the CPU just like core does, `step`
+            // reports them as faults before getting here
+            _ => return true,
+        }
+        false
+    }
+
+    fn alu(&mut self, value: u16) {
+        self.set_register(Register::Acc, value);
+    }
+
+    fn jump(&mut self, cond: bool, addr: u16) {
+        if cond {
+            self.set_register(Register::Ip, addr);
+        }
+    }
+
+    fn call(&mut self, addr: u16) {
+        self.push_state();
+        self.set_register(Register::Ip, addr);
+    }
+
+    /// Calls handler of `line` from the vector table with no arguments
+    fn interrupt(&mut self, line: u8) {
+        let handler = self.memory.get_u16(self.interrupts.vector(line));
+        self.push(0);
+        self.call(handler);
+        self.handlers += 1;
+    }
+
+    pub fn push(&mut self, value: u16) {
+        let sp = self.register(Register::Sp);
+        self.memory.set_u16(sp, value);
+        self.set_register(Register::Sp, sp.wrapping_sub(2));
+        self.frame_size = self.frame_size.wrapping_add(2);
+    }
+
+    pub fn pop(&mut self) -> u16 {
+        let sp = self.register(Register::Sp).wrapping_add(2);
+        self.set_register(Register::Sp, sp);
+        self.frame_size = self.frame_size.wrapping_sub(2);
+        self.memory.get_u16(sp)
+    }
+
+    /// Frame: r1..r8, return ip, size of the caller frame (arguments included)
+    fn push_state(&mut self) {
+        for reg in &Register::ALL[Register::R1 as usize..=Register::R8 as usize] {
+            self.push(self.register(*reg));
+        }
+        self.push(self.register(Register::Ip));
+        self.push(self.frame_size.wrapping_add(2));
+
+        self.set_register(Register::Fp, self.register(Register::Sp));
+        self.frame_size = 0;
+    }
+
+    fn pop_state(&mut self) {
+        let fp = self.register(Register::Fp);
+        self.set_register(Register::Sp, fp);
+
+        self.frame_size = self.pop();
+        let frame_size = self.frame_size;
+
+        let ip = self.pop();
+        self.set_register(Register::Ip, ip);
+        for reg in Register::ALL[Register::R1 as usize..=Register::R8 as usize]
+            .iter()
+            .rev()
+        {
+            let value = self.pop();
+            self.set_register(*reg, value);
+        }
+
+        let args = self.pop();
+        for _ in 0..args {
+            self.pop();
+        }
+
+        self.set_register(Register::Fp, fp.wrapping_add(frame_size));
+    }
+}
+
+fn shift_left(value: u16, by: u16) -> u16 {
+    value.checked_shl(by as u32).unwrap_or(0)
+}
+
+fn shift_right(value: u16, by: u16) -> u16 {
+    value.checked_shr(by as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+fn cpu_with(program: &[u8]) -> Cpu {
+    let mut memory = Memory::default();
+    memory.load(0, program);
+    Cpu::new(memory)
+}
+
+#[test]
+fn cpu_arithmetic() {
+    let mut cpu = cpu_with(&[
+        0x10, 0x00, 0x05, 0x02, // mov $5, r1
+        0x10, 0x00, 0x03, 0x03, // mov $3, r2
+        0x14, 0x02, 0x03, // add r1, r2
+        0x11, 0x01, 0x04, // mov acc, r3
+        0x20, 0x00, 0x04, 0x02, // mul $4, r1
+        0x11, 0x01, 0x05, // mov acc, r4
+        0x1E, 0x00, 0x0A, 0x03, // sub r2, $10 literal first like core
+        0x11, 0x01, 0x06, // mov acc, r5
+        0x16, 0x00, 0x01, 0x02, // sub $1, r1
+        0x26, 0x02, 0x00, 0x02, // lsf r1, $2
+        0x34, 0x03, // not r2
+        0x12, 0x02, 0x01, 0x00, // mov r1, &0x0100
+        0xFF,
+    ]);
+    cpu.run();
+
+    assert_eq!(cpu.register(Register::R3), 8);
+    // core's `mul $lit, reg` adds
+    assert_eq!(cpu.register(Register::R4), 9);
+    assert_eq!(cpu.register(Register::R5), 7);
+    assert_eq!(cpu.register(Register::Acc), 4);
+    assert_eq!(cpu.register(Register::R1), 20);
+    assert_eq!(cpu.register(Register::R2), !3);
+    assert_eq!(cpu.memory().get_u16(0x0100), 20);
+    assert_eq!(cpu.register(Register::Ip), 0x2B);
+}
+
+#[test]
+fn cpu_jumps() {
+    // count r1 up to 3
+    let mut cpu = cpu_with(&[
+        0x10, 0x00, 0x03, 0x01, // mov $3, acc
+        0x35, 0x02, // loop: inc r1
+        0x40, 0x02, 0x00, 0x04, // jne r1, &loop
+        0x15, 0x00, 0x03, 0x00, 0x00, // jne $3, &0 (not taken)
+        0xFF,
+    ]);
+    cpu.run();
+    assert_eq!(cpu.register(Register::R1), 3);
+    assert_eq!(cpu.register(Register::Ip), 0x10);
+
+    // unknown opcode faults without running
+    let mut cpu = cpu_with(&[0x35, 0x02, 0x00, 0x35, 0x02]);
+    cpu.run();
+    assert_eq!(cpu.register(Register::R1), 1);
+    assert_eq!(cpu.register(Register::Ip), 2);
+    assert_eq!(cpu.fault(), Some(Fault::InvalidOpcode(2, 0x00)));
+}
+
+#[test]
+fn cpu_call_frame() {
+    let mut cpu = cpu_with(&[
+        0x10, 0x00, 0x11, 0x02, // mov $0x11, r1
+        0x17, 0x00, 0xAA, // push $0xAA (argument)
+        0x17, 0x00, 0x01, // push $1 (number of arguments)
+        0x5E, 0x00, 0x20, // call &0x20
+        0xFF, // 0x0D
+    ]);
+    cpu.memory_mut().load(
+        0x20,
+        &[
+            0x10, 0x00, 0x22, 0x02, // mov $0x22, r1
+            0x60, // ret
+        ],
+    );
+
+    // stop right after the call
+    for _ in 0..4 {
+        assert!(!cpu.step());
+    }
+    assert_eq!(cpu.register(Register::Ip), 0x20);
+    let memory = cpu.memory();
+    assert_eq!(memory.get_u16(0xfffe), 0xAA);
+    assert_eq!(memory.get_u16(0xfffc), 1);
+    assert_eq!(memory.get_u16(0xfffa), 0x11);
+    // return address and size of caller frame: two pushes plus r1..r8 and ip
+    assert_eq!(memory.get_u16(0xffea), 0x0D);
+    assert_eq!(memory.get_u16(0xffe8), 2 * 11 + 2);
+    assert_eq!(cpu.register(Register::Sp), 0xffe6);
+    assert_eq!(cpu.register(Register::Fp), 0xffe6);
+
+    cpu.run();
+    assert_eq!(cpu.register(Register::Ip), 0x0E);
+    assert_eq!(cpu.register(Register::R1), 0x11);
+    // arguments are dropped on return
+    assert_eq!(cpu.register(Register::Sp), STACK_START);
+    assert_eq!(cpu.register(Register::Fp), STACK_START);
+}
+
+#[test]
+fn cpu_step_back() {
+    let mut cpu = cpu_with(&[
+        0x10, 0x00, 0x05, 0x02, // mov $5, r1
+        0x17, 0x00, 0xAA, // push $0xAA
+        0x12, 0x02, 0x01, 0x00, // mov r1, &0x0100
+        0x35, 0x02, // inc r1
+        0xFF,
+    ]);
+    cpu.record(3);
+    cpu.run();
+    assert_eq!(cpu.executed(), 5);
+    assert_eq!(cpu.history().unwrap().len(), 3);
+
+    // hlt, inc and the memory write are undone, push is out of history
+    assert!(cpu.step_back().is_some());
+    assert_eq!(cpu.register(Register::Ip), 0x0D);
+    assert!(cpu.step_back().is_some());
+    assert_eq!(cpu.register(Register::R1), 5);
+    let record = cpu.step_back().unwrap();
+    assert_eq!(
+        record.writes,
+        vec![
+            MemoryWrite {
+                addr: 0x0100,
+                old: 0x00,
+                new: 0x00
+            },
+            MemoryWrite {
+                addr: 0x0101,
+                old: 0x00,
+                new: 0x05
+            },
+        ]
+    );
+    assert_eq!(cpu.memory().get_u16(0x0100), 0);
+    assert!(cpu.step_back().is_none());
+    assert_eq!(cpu.executed(), 2);
+    assert_eq!(cpu.cycles(), 4 + 5);
+    assert_eq!(cpu.register(Register::Ip), 0x07);
+    assert_eq!(cpu.register(Register::Sp), STACK_START - 2);
+
+    // replaying gives the same result
+    cpu.run();
+    assert_eq!(cpu.memory().get_u16(0x0100), 5);
+    assert_eq!(cpu.register(Register::R1), 6);
+    assert_eq!(cpu.executed(), 5);
+    assert_eq!(cpu.cycles(), 4 + 5 + 6 + 2 + 1);
+}
+
+#[test]
+fn cpu_run_for() {
+    let mut cpu = cpu_with(&[
+        0x35, 0x02, // inc r1
+        0x15, 0x00, 0x00, 0x00, 0x00, // jne $0, &0x0000 with acc != 0
+        0xFF,
+    ]);
+    cpu.set_register(Register::Acc, 1);
+    assert_eq!(cpu.run_for(7), Exit::OutOfFuel);
+    assert_eq!(cpu.executed(), 7);
+    assert_eq!(cpu.register(Register::R1), 4);
+
+    let mut cpu = cpu_with(&[0x35, 0x02, 0xFF]);
+    assert_eq!(cpu.run_for(100), Exit::Halted);
+    assert_eq!(cpu.executed(), 2);
+
+    let mut cpu = cpu_with(&[0x35, 0x02, 0x01]);
+    assert_eq!(
+        cpu.run_for(100),
+        Exit::Fault(Fault::InvalidOpcode(0x0002, 0x01))
+    );
+}
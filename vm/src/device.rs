@@ -0,0 +1,111 @@
+use std::{cell::RefCell, rc::Rc};
+
+/// Anything the CPU can read and write through the address bus, see
+/// `core/src/memory/IMemoryMappedDevice.hpp`
+pub trait MemoryMappedDevice {
+    fn get_u8(&self, addr: u16) -> u8;
+
+    fn set_u8(&mut self, addr: u16, value: u8);
+
+    /// Big-endian by default like RAM
+    fn get_u16(&self, addr: u16) -> u16 {
+        u16::from_be_bytes([self.get_u8(addr), self.get_u8(addr.wrapping_add(1))])
+    }
+
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        let [h, l] = value.to_be_bytes();
+        self.set_u8(addr, h);
+        self.set_u8(addr.wrapping_add(1), l);
+    }
+
+    /// Like [`Self::get_u8`] without side effects, for debuggers. Devices
+    /// whose reads change state override it.
+    fn peek_u8(&self, addr: u16) -> u8 {
+        self.get_u8(addr)
+    }
+
+    fn peek_u16(&self, addr: u16) -> u16 {
+        u16::from_be_bytes([self.peek_u8(addr), self.peek_u8(addr.wrapping_add(1))])
+    }
+
+    /// Copies `bytes` starting at `addr`
+    fn load(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.set_u8(addr.wrapping_add(i as u16), *byte);
+        }
+    }
+
+    /// State for snapshots, `None` for devices without state of their own
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Takes back what [`Self::save_state`] returned, `false` if it does not fit
+    fn load_state(&mut self, _state: &[u8]) -> bool {
+        false
+    }
+
+    /// State [`Self::undo`] needs to take back one instruction, `None` when
+    /// putting back written bytes is enough
+    fn undo_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Goes back to what [`Self::undo_state`] returned
+    fn undo(&mut self, _state: &[u8]) {}
+
+    /// Called after every instruction with the cycles it took, devices fed
+    /// from outside poll here and timers count
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+/// Shared devices stay reachable from outside once mapped, handy for fakes in tests
+impl<T: MemoryMappedDevice> MemoryMappedDevice for Rc<RefCell<T>> {
+    fn get_u8(&self, addr: u16) -> u8 {
+        self.borrow().get_u8(addr)
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        self.borrow_mut().set_u8(addr, value)
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        self.borrow().get_u16(addr)
+    }
+
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        self.borrow_mut().set_u16(addr, value)
+    }
+
+    fn peek_u8(&self, addr: u16) -> u8 {
+        self.borrow().peek_u8(addr)
+    }
+
+    fn peek_u16(&self, addr: u16) -> u16 {
+        self.borrow().peek_u16(addr)
+    }
+
+    fn load(&mut self, addr: u16, bytes: &[u8]) {
+        self.borrow_mut().load(addr, bytes)
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        self.borrow().save_state()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        self.borrow_mut().load_state(state)
+    }
+
+    fn undo_state(&self) -> Option<Vec<u8>> {
+        self.borrow().undo_state()
+    }
+
+    fn undo(&mut self, state: &[u8]) {
+        self.borrow_mut().undo(state)
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.borrow_mut().tick(cycles)
+    }
+}
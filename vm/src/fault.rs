@@ -0,0 +1,134 @@
+use std::fmt;
+
+use crate::mapper::Access;
+
+#[cfg(test)]
+use crate::{
+    cpu::{Cpu, STACK_START},
+    device::MemoryMappedDevice,
+    mapper::{MemoryMapper, Region},
+    memory::Memory,
+    registers::Register,
+};
+
+/// Why the CPU stopped other than `hlt`, first field is address of the
+/// faulting instruction. Instructions are checked before they run and leave
+/// no trace when they fault, only unmapped accesses are noticed afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Opcode core logs as not implemented
+    InvalidOpcode(u16, u8),
+    /// Register operand byte past the register file, `fetchRegisterIndex` wraps it
+    InvalidRegister(u16, u8),
+    /// Push or call would move `sp` below the stack limit, `sp` before it
+    StackOverflow(u16, u16),
+    /// Pop or return would move `sp` above its initial value, `sp` before it
+    StackUnderflow(u16, u16),
+    /// `ret` with `fp` still at the initial value
+    NoFrame(u16),
+    /// No device is mapped at the address
+    UnmappedMemory(u16, u16, Access),
+}
+
+impl Fault {
+    /// Address of the faulting instruction
+    pub fn ip(&self) -> u16 {
+        match *self {
+            Self::InvalidOpcode(ip, _)
+            | Self::InvalidRegister(ip, _)
+            | Self::StackOverflow(ip, _)
+            | Self::StackUnderflow(ip, _)
+            | Self::NoFrame(ip)
+            | Self::UnmappedMemory(ip, _, _) => ip,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode(ip, opcode) => {
+                write!(f, "invalid opcode {:#04x} at {:#06x}", opcode, ip)
+            }
+            Self::InvalidRegister(ip, index) => {
+                write!(f, "invalid register index {:#04x} at {:#06x}", index, ip)
+            }
+            Self::StackOverflow(ip, sp) => {
+                write!(f, "stack overflow at {:#06x} with sp {:#06x}", ip, sp)
+            }
+            Self::StackUnderflow(ip, sp) => {
+                write!(f, "stack underflow at {:#06x} with sp {:#06x}", ip, sp)
+            }
+            Self::NoFrame(ip) => write!(f, "ret without call frame at {:#06x}", ip),
+            Self::UnmappedMemory(ip, addr, access) => {
+                let access = match access {
+                    Access::Read => "read of",
+                    Access::Write => "write to",
+                };
+                write!(
+                    f,
+                    "{} unmapped address {:#06x} at {:#06x}",
+                    access, addr, ip
+                )
+            }
+        }
+    }
+}
+
+#[test]
+fn fault_checks() {
+    let fault = |program: &[u8], limit: u16| {
+        let mut memory = Memory::default();
+        memory.load(0, program);
+        let mut cpu = Cpu::new(memory);
+        cpu.set_stack_limit(limit);
+        cpu.run();
+        (cpu.fault(), cpu.executed(), cpu.register(Register::Ip))
+    };
+    assert_eq!(fault(&[0xFF], 0), (None, 1, 1));
+    assert_eq!(
+        fault(&[0x35, 0x02, 0x35, 0x0C], 0),
+        (Some(Fault::InvalidRegister(2, 0x0C)), 1, 2)
+    );
+    assert_eq!(
+        fault(&[0x17, 0x00, 0x01, 0x17, 0x00, 0x02], STACK_START - 2),
+        (Some(Fault::StackOverflow(3, STACK_START - 2)), 1, 3)
+    );
+    assert_eq!(
+        fault(&[0x1A, 0x02], 0),
+        (Some(Fault::StackUnderflow(0, STACK_START)), 0, 0)
+    );
+    assert_eq!(fault(&[0x60], 0), (Some(Fault::NoFrame(0)), 0, 0));
+    // call without pushing an argument count first
+    assert_eq!(
+        fault(&[0x5E, 0x00, 0x04, 0xFF, 0x60], 0).0,
+        Some(Fault::StackUnderflow(4, STACK_START - 20))
+    );
+
+    let mut memory = Memory::new(0x100);
+    memory.load(0, &[0x13, 0x30, 0x00, 0x02, 0xFF]); // mov &0x3000, r1
+    let mut mapper = MemoryMapper::new();
+    mapper.map(Region::new(memory, 0x0000, 0x00ff, false));
+    let mut cpu = Cpu::new(mapper);
+    cpu.record(4);
+    assert!(cpu.step());
+    // the access already happened, so it can be undone
+    assert_eq!(
+        cpu.fault(),
+        Some(Fault::UnmappedMemory(0, 0x3000, Access::Read))
+    );
+    assert_eq!(cpu.register(Register::Ip), 4);
+    assert!(cpu.step_back().is_some());
+    assert_eq!(cpu.fault(), None);
+    // tools reading unmapped memory between steps do not fault the next one
+    cpu.set_register(Register::Ip, 4);
+    cpu.memory().get_u16(0x5000);
+    assert!(cpu.step());
+    assert_eq!(cpu.fault(), None);
+    cpu.set_register(Register::Ip, 0x4000);
+    assert!(cpu.step());
+    assert_eq!(
+        cpu.fault().unwrap().to_string(),
+        "read of unmapped address 0x4000 at 0x4000"
+    );
+}
@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+
+use crate::mapper::{Mapping, MemoryWrite, WatchHit};
+
+/// Everything needed to undo one executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub registers: [u16; 12],
+    pub frame_size: u16,
+    /// Interrupt handlers the CPU was in
+    pub handlers: u16,
+    /// Cycles the instruction took
+    pub cycles: u64,
+    /// Bytes the instruction wrote, in write order
+    pub writes: Vec<MemoryWrite>,
+    /// Device state written bytes do not cover, like latches and counters
+    pub devices: Vec<(Mapping, Vec<u8>)>,
+    /// Interrupt lines pending before
+    pub pending: u16,
+    /// Watchpoints the instruction tripped
+    pub hits: Vec<WatchHit>,
+}
+
+/// Undo log of the last `depth` instructions, oldest ones are dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    records: VecDeque<Record>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            depth,
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.records.len() == self.depth {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Most recent record, the one [`Self::pop`] returns next
+    pub fn last(&self) -> Option<&Record> {
+        self.records.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn pop(&mut self) -> Option<Record> {
+        self.records.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
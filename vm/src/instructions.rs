@@ -0,0 +1,325 @@
+use crate::registers::Register;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum Instructions {
+    MOV_LIT_REG = 0x10,
+    MOV_REG_REG = 0x11,
+    MOV_REG_MEM = 0x12,
+    MOV_MEM_REG = 0x13,
+    MOV_LIT_MEM = 0x1B,
+    MOV_REG_PTR_REG = 0x1C,
+    MOV_LIT_OFF_REG = 0x1D,
+
+    ADD_REG_REG = 0x14,
+    ADD_LIT_REG = 0x3F,
+    SUB_LIT_REG = 0x16,
+    SUB_REG_LIT = 0x1E,
+    SUB_REG_REG = 0x1F,
+    INC_REG = 0x35,
+    DEC_REG = 0x36,
+    MUL_LIT_REG = 0x20,
+    MUL_REG_REG = 0x21,
+
+    LSF_REG_LIT = 0x26,
+    LSF_REG_REG = 0x27,
+    RSF_REG_LIT = 0x2A,
+    RSF_REG_REG = 0x2B,
+    AND_REG_LIT = 0x2E,
+    AND_REG_REG = 0x2F,
+    OR_REG_LIT = 0x30,
+    OR_REG_REG = 0x31,
+    XOR_REG_LIT = 0x32,
+    XOR_REG_REG = 0x33,
+    NOT = 0x34,
+
+    JMP_NOT_EQ = 0x15,
+    JNE_REG = 0x40,
+    JEQ_REG = 0x3E,
+    JEQ_LIT = 0x41,
+    JLT_REG = 0x42,
+    JLT_LIT = 0x43,
+    JGT_REG = 0x44,
+    JGT_LIT = 0x45,
+    JLE_REG = 0x46,
+    JLE_LIT = 0x47,
+    JGE_REG = 0x48,
+    JGE_LIT = 0x49,
+
+    PSH_LIT = 0x17,
+    PSH_REG = 0x18,
+    POP = 0x1A,
+
+    CALL_LIT = 0x5E,
+    CALL_REG = 0x5F,
+    RET = 0x60,
+
+    INT_LIT = 0xFD,
+    RET_INT = 0xFC,
+
+    HLT = 0xFF,
+}
+
+impl TryFrom<u8> for Instructions {
+    type Error = u8;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|x| *x as u8 == opcode)
+            .ok_or(opcode)
+    }
+}
+
+impl Instructions {
+    pub const ALL: [Instructions; 48] = [
+        Self::MOV_LIT_REG,
+        Self::MOV_REG_REG,
+        Self::MOV_REG_MEM,
+        Self::MOV_MEM_REG,
+        Self::MOV_LIT_MEM,
+        Self::MOV_REG_PTR_REG,
+        Self::MOV_LIT_OFF_REG,
+        Self::ADD_REG_REG,
+        Self::ADD_LIT_REG,
+        Self::SUB_LIT_REG,
+        Self::SUB_REG_LIT,
+        Self::SUB_REG_REG,
+        Self::INC_REG,
+        Self::DEC_REG,
+        Self::MUL_LIT_REG,
+        Self::MUL_REG_REG,
+        Self::LSF_REG_LIT,
+        Self::LSF_REG_REG,
+        Self::RSF_REG_LIT,
+        Self::RSF_REG_REG,
+        Self::AND_REG_LIT,
+        Self::AND_REG_REG,
+        Self::OR_REG_LIT,
+        Self::OR_REG_REG,
+        Self::XOR_REG_LIT,
+        Self::XOR_REG_REG,
+        Self::NOT,
+        Self::JMP_NOT_EQ,
+        Self::JNE_REG,
+        Self::JEQ_REG,
+        Self::JEQ_LIT,
+        Self::JLT_REG,
+        Self::JLT_LIT,
+        Self::JGT_REG,
+        Self::JGT_LIT,
+        Self::JLE_REG,
+        Self::JLE_LIT,
+        Self::JGE_REG,
+        Self::JGE_LIT,
+        Self::PSH_LIT,
+        Self::PSH_REG,
+        Self::POP,
+        Self::CALL_LIT,
+        Self::CALL_REG,
+        Self::RET,
+        Self::INT_LIT,
+        Self::RET_INT,
+        Self::HLT,
+    ];
+
+    /// Operands following the opcode in the order `asm` emits them
+    pub fn operands(self) -> &'static [OperandKind] {
+        use Instructions::*;
+        use OperandKind::*;
+
+        match self {
+            MOV_LIT_REG => &[Lit, Reg],
+            MOV_REG_REG => &[Reg, Reg],
+            MOV_REG_MEM => &[Reg, Addr],
+            MOV_MEM_REG => &[Addr, Reg],
+            MOV_LIT_MEM => &[Lit, Addr],
+            MOV_REG_PTR_REG => &[Reg, Reg],
+            MOV_LIT_OFF_REG => &[Addr, Reg, Reg],
+
+            ADD_REG_REG | SUB_REG_REG | MUL_REG_REG => &[Reg, Reg],
+            ADD_LIT_REG | SUB_LIT_REG | MUL_LIT_REG => &[Lit, Reg],
+            SUB_REG_LIT => &[Reg, Lit],
+            INC_REG | DEC_REG | NOT => &[Reg],
+
+            LSF_REG_LIT | RSF_REG_LIT | AND_REG_LIT | OR_REG_LIT | XOR_REG_LIT => &[Reg, Lit],
+            LSF_REG_REG | RSF_REG_REG | AND_REG_REG | OR_REG_REG | XOR_REG_REG => &[Reg, Reg],
+
+            JMP_NOT_EQ | JEQ_LIT | JLT_LIT | JGT_LIT | JLE_LIT | JGE_LIT => &[Lit, Addr],
+            JNE_REG | JEQ_REG | JLT_REG | JGT_REG | JLE_REG | JGE_REG => &[Reg, Addr],
+
+            PSH_LIT => &[Lit],
+            PSH_REG | POP => &[Reg],
+            CALL_LIT => &[Addr],
+            CALL_REG => &[Reg],
+            INT_LIT => &[Lit],
+            RET | RET_INT | HLT => &[],
+        }
+    }
+
+    /// Assembler mnemonic producing this opcode
+    pub fn mnemonic(self) -> &'static str {
+        use Instructions::*;
+
+        match self {
+            MOV_LIT_REG | MOV_REG_REG | MOV_REG_MEM | MOV_MEM_REG | MOV_LIT_MEM
+            | MOV_REG_PTR_REG | MOV_LIT_OFF_REG => "mov",
+            ADD_REG_REG | ADD_LIT_REG => "add",
+            SUB_LIT_REG | SUB_REG_LIT | SUB_REG_REG => "sub",
+            INC_REG => "inc",
+            DEC_REG => "dec",
+            MUL_LIT_REG | MUL_REG_REG => "mul",
+            LSF_REG_LIT | LSF_REG_REG => "lsf",
+            RSF_REG_LIT | RSF_REG_REG => "rsf",
+            AND_REG_LIT | AND_REG_REG => "and",
+            OR_REG_LIT | OR_REG_REG => "or",
+            XOR_REG_LIT | XOR_REG_REG => "xor",
+            NOT => "not",
+            JMP_NOT_EQ | JNE_REG => "jne",
+            JEQ_REG | JEQ_LIT => "jeq",
+            JLT_REG | JLT_LIT => "jlt",
+            JGT_REG | JGT_LIT => "jgt",
+            JLE_REG | JLE_LIT => "jle",
+            JGE_REG | JGE_LIT => "jge",
+            PSH_LIT | PSH_REG => "push",
+            POP => "pop",
+            CALL_LIT | CALL_REG => "call",
+            RET => "ret",
+            INT_LIT => "int",
+            RET_INT => "rti",
+            HLT => "hlt",
+        }
+    }
+
+    /// Encoded size in bytes, opcode included
+    pub fn size(self) -> u16 {
+        1 + self.operands().iter().map(|x| x.size()).sum::<u16>()
+    }
+
+    /// Data words read or written besides the fetch. `CALL_*` pushes 10 words
+    /// and `RET` pops 11, arguments it drops are not included. `INT_LIT`
+    /// pushes an empty argument count first.
+    pub fn memory_accesses(self) -> u16 {
+        use Instructions::*;
+
+        match self {
+            MOV_REG_MEM | MOV_MEM_REG | MOV_LIT_MEM | MOV_REG_PTR_REG | MOV_LIT_OFF_REG => 1,
+            PSH_LIT | PSH_REG | POP => 1,
+            CALL_LIT | CALL_REG => 10,
+            RET | INT_LIT | RET_INT => 11,
+            _ => 0,
+        }
+    }
+
+    /// Jumps that may fall through to the next instruction
+    pub fn is_conditional_jump(self) -> bool {
+        use Instructions::*;
+
+        matches!(
+            self,
+            JMP_NOT_EQ
+                | JNE_REG
+                | JEQ_REG
+                | JEQ_LIT
+                | JLT_REG
+                | JLT_LIT
+                | JGT_REG
+                | JGT_LIT
+                | JLE_REG
+                | JLE_LIT
+                | JGE_REG
+                | JGE_LIT
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    /// Register index, one byte
+    Reg,
+    /// 16-bit literal value
+    Lit,
+    /// 16-bit memory address
+    Addr,
+}
+
+impl OperandKind {
+    pub fn size(self) -> u16 {
+        match self {
+            OperandKind::Reg => 1,
+            OperandKind::Lit | OperandKind::Addr => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(Register),
+    Lit(u16),
+    Addr(u16),
+}
+
+/// Decoded instruction, `kind` is `None` for unknown opcodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: u8,
+    pub kind: Option<Instructions>,
+    pub operands: Vec<Operand>,
+    pub size: u16,
+}
+
+impl Instruction {
+    /// Decodes instruction at `address` reading bytes through `read`
+    pub fn decode(address: u16, read: impl Fn(u16) -> u8) -> Self {
+        let opcode = read(address);
+        let kind = Instructions::try_from(opcode).ok();
+
+        let mut pos = address.wrapping_add(1);
+        let mut operands = Vec::new();
+        for operand in kind.map(|x| x.operands()).unwrap_or_default() {
+            let word = || u16::from_be_bytes([read(pos), read(pos.wrapping_add(1))]);
+            operands.push(match operand {
+                OperandKind::Reg => Operand::Reg(Register::from_index(read(pos))),
+                OperandKind::Lit => Operand::Lit(word()),
+                OperandKind::Addr => Operand::Addr(word()),
+            });
+            pos = pos.wrapping_add(operand.size());
+        }
+
+        Self {
+            address,
+            opcode,
+            kind,
+            operands,
+            size: pos.wrapping_sub(address),
+        }
+    }
+}
+
+#[test]
+fn instructions_decode() {
+    for instruction in Instructions::ALL {
+        assert_eq!(Instructions::try_from(instruction as u8), Ok(instruction));
+    }
+    assert_eq!(Instructions::try_from(0x00), Err(0x00));
+
+    let bytes = [0x1Du8, 0x30, 0x00, 0x02, 0x0F];
+    let decoded = Instruction::decode(0, |addr| bytes[addr as usize]);
+    assert_eq!(decoded.kind, Some(Instructions::MOV_LIT_OFF_REG));
+    assert_eq!(
+        decoded.operands,
+        vec![
+            Operand::Addr(0x3000),
+            Operand::Reg(Register::R1),
+            // register index wraps around like `fetchRegisterIndex`
+            Operand::Reg(Register::R2),
+        ]
+    );
+    assert_eq!(decoded.size, Instructions::MOV_LIT_OFF_REG.size());
+
+    let decoded = Instruction::decode(0, |_| 0x01);
+    assert_eq!((decoded.kind, decoded.size), (None, 1));
+}
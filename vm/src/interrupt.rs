@@ -0,0 +1,259 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::device::MemoryMappedDevice;
+
+#[cfg(test)]
+use crate::{
+    cpu::{Cpu, STACK_START},
+    mapper::Region,
+    memory::Memory,
+    registers::Register,
+};
+
+/// Where emu maps the interrupt controller, right after the cycle counter
+pub const INTERRUPT_START: u16 = 0x3104;
+pub const INTERRUPT_END: u16 = 0x3109;
+
+/// Interrupt lines, `int` takes the line number modulo this
+pub const INTERRUPT_LINES: u8 = 16;
+
+/// Pending lines, mask and vector table address shared by the CPU, the
+/// controller and devices raising lines. Line `n` runs the handler whose
+/// address is the `n`th word of the vector table, lowest pending line first.
+#[derive(Debug)]
+pub struct Interrupts {
+    pending: Cell<u16>,
+    mask: Cell<u16>,
+    vectors: Cell<u16>,
+}
+
+impl Default for Interrupts {
+    /// Every line enabled like `im` starts in the series, table at 0
+    fn default() -> Self {
+        Self {
+            pending: Cell::new(0),
+            mask: Cell::new(0xffff),
+            vectors: Cell::new(0),
+        }
+    }
+}
+
+impl Interrupts {
+    pub fn raise(&self, line: u8) {
+        self.pending
+            .set(self.pending.get() | 1 << (line % INTERRUPT_LINES));
+    }
+
+    pub fn pending(&self) -> u16 {
+        self.pending.get()
+    }
+
+    pub(crate) fn set_pending(&self, pending: u16) {
+        self.pending.set(pending);
+    }
+
+    /// Set bits enable their lines
+    pub fn mask(&self) -> u16 {
+        self.mask.get()
+    }
+
+    pub fn set_mask(&self, mask: u16) {
+        self.mask.set(mask);
+    }
+
+    pub fn vectors(&self) -> u16 {
+        self.vectors.get()
+    }
+
+    pub fn set_vectors(&self, addr: u16) {
+        self.vectors.set(addr);
+    }
+
+    pub fn enabled(&self, line: u8) -> bool {
+        self.mask() & 1 << (line % INTERRUPT_LINES) != 0
+    }
+
+    /// Address of the vector table entry for `line`
+    pub fn vector(&self, line: u8) -> u16 {
+        self.vectors()
+            .wrapping_add(2 * (line % INTERRUPT_LINES) as u16)
+    }
+
+    /// Lowest pending line that is not masked
+    pub fn next(&self) -> Option<u8> {
+        let ready = self.pending() & self.mask();
+        (ready != 0).then_some(ready.trailing_zeros() as u8)
+    }
+
+    /// Clears `line` once its handler was entered
+    pub fn acknowledge(&self, line: u8) {
+        self.pending
+            .set(self.pending.get() & !(1 << (line % INTERRUPT_LINES)));
+    }
+}
+
+/// Handle devices keep to raise their line
+#[derive(Debug, Clone)]
+pub struct InterruptLine {
+    interrupts: Rc<Interrupts>,
+    line: u8,
+}
+
+impl InterruptLine {
+    pub fn new(interrupts: Rc<Interrupts>, line: u8) -> Self {
+        Self { interrupts, line }
+    }
+
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+
+    pub fn raise(&self) {
+        self.interrupts.raise(self.line);
+    }
+}
+
+/// Program side of [`Interrupts`], big-endian words: mask at 0, vector
+/// table address at 2 and pending lines at 4
+#[derive(Debug, Clone)]
+pub struct InterruptController {
+    interrupts: Rc<Interrupts>,
+}
+
+impl InterruptController {
+    pub fn new(interrupts: Rc<Interrupts>) -> Self {
+        Self { interrupts }
+    }
+}
+
+impl MemoryMappedDevice for InterruptController {
+    fn get_u8(&self, addr: u16) -> u8 {
+        self.get_u16(addr & !1).to_be_bytes()[(addr & 1) as usize]
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let mut word = self.get_u16(addr & !1).to_be_bytes();
+        word[(addr & 1) as usize] = value;
+        self.set_u16(addr & !1, u16::from_be_bytes(word));
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        match addr {
+            0 => self.interrupts.mask(),
+            2 => self.interrupts.vectors(),
+            4 => self.interrupts.pending(),
+            _ => 0,
+        }
+    }
+
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        match addr {
+            0 => self.interrupts.set_mask(value),
+            2 => self.interrupts.set_vectors(value),
+            4 => self.interrupts.pending.set(value),
+            _ => {}
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(
+            (0..6)
+                .step_by(2)
+                .flat_map(|x| self.get_u16(x).to_be_bytes())
+                .collect(),
+        )
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 6 {
+            return false;
+        }
+        for (i, word) in state.chunks(2).enumerate() {
+            self.set_u16(2 * i as u16, u16::from_be_bytes([word[0], word[1]]));
+        }
+        true
+    }
+}
+
+#[test]
+fn interrupt_timer_handler() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x1B, 0x01, 0x00, 0x31, 0x06, // mov $0x0100, &0x3106
+            0x35, 0x02, // loop: inc r1
+            0x13, 0x03, 0x00, 0x04, // mov &0x0300, r3
+            0x10, 0x00, 0x03, 0x01, // mov $3, acc
+            0x40, 0x04, 0x00, 0x05, // jne r3, &loop
+            0xFF,
+        ],
+    );
+    // line 2 handler counts ticks at 0x0300
+    let handler = [
+        0x13, 0x03, 0x00, 0x02, // mov &0x0300, r1
+        0x35, 0x02, // inc r1
+        0x12, 0x02, 0x03, 0x00, // mov r1, &0x0300
+        0xFC, // rti
+    ];
+    memory.load(0x0100, &[0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    memory.load(0x0200, &handler);
+    let mut cpu = Cpu::new(memory);
+    let controller = cpu.interrupt_controller();
+    cpu.memory_mut().map(Region::new(
+        controller,
+        INTERRUPT_START,
+        INTERRUPT_END,
+        true,
+    ));
+    let timer = cpu.interrupt_line(2);
+
+    let mut handlers = 0;
+    for tick in 1.. {
+        if tick % 10 == 0 {
+            timer.raise();
+        }
+        if cpu.register(Register::Ip) == 0x0200 {
+            handlers += 1;
+            assert_eq!(cpu.register(Register::Sp), STACK_START - 22);
+        }
+        if cpu.step() {
+            break;
+        }
+    }
+    assert_eq!(handlers, 3);
+    assert_eq!(cpu.fault(), None);
+    // handler frames keep r1, the loop went on counting
+    assert_eq!(cpu.register(Register::R3), 3);
+    assert_eq!(cpu.register(Register::R1), 6);
+    assert_eq!(cpu.register(Register::Sp), STACK_START);
+    assert_eq!(cpu.interrupts().pending(), 0);
+
+    // software interrupts run the same handler, masked lines stay pending
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0xFD, 0x00, 0x02, // int $2
+            0x1B, 0x00, 0x00, 0x31, 0x04, // mov $0, &0x3104
+            0xFD, 0x00, 0x02, // int $2
+            0xFF,
+        ],
+    );
+    memory.load(0x0104, &[0x02, 0x00]);
+    memory.load(0x0200, &handler);
+    let mut cpu = Cpu::new(memory);
+    let controller = cpu.interrupt_controller();
+    cpu.memory_mut().map(Region::new(
+        controller,
+        INTERRUPT_START,
+        INTERRUPT_END,
+        true,
+    ));
+    cpu.interrupts().set_vectors(0x0100);
+    cpu.run();
+    assert_eq!(cpu.memory().get_u16(0x0300), 1);
+    cpu.interrupts().raise(2);
+    assert_eq!(cpu.interrupts().next(), None);
+    assert_eq!(cpu.memory().get_u16(INTERRUPT_START + 4), 0b100);
+}
@@ -0,0 +1,334 @@
+use std::{cell::Cell, collections::VecDeque};
+
+use crate::{device::MemoryMappedDevice, interrupt::InterruptLine};
+
+#[cfg(test)]
+use crate::{
+    cpu::Cpu,
+    interrupt::{Interrupts, INTERRUPT_END, INTERRUPT_START},
+    mapper::Region,
+    memory::Memory,
+    registers::Register,
+};
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+/// Where emu maps the keyboard, right after the bank select register
+pub const KEYBOARD_START: u16 = 0x310c;
+pub const KEYBOARD_END: u16 = 0x310f;
+
+/// Line raised on key press when the program asked for it
+pub const KEYBOARD_LINE: u8 = 1;
+
+/// Status bit set while a key waits in the data register
+pub const KEY_READY: u16 = 1;
+/// Status bit the program sets to get [`KEYBOARD_LINE`] raised per key
+pub const KEY_INTERRUPT: u16 = 1 << 1;
+/// Status bit set once the input has no keys left
+pub const KEY_CLOSED: u16 = 1 << 2;
+
+/// Where keys come from, polled between instructions while no key waits
+pub trait KeyInput {
+    /// Next key if one was pressed
+    fn poll(&mut self) -> Option<u8>;
+
+    /// No key will come anymore
+    fn closed(&self) -> bool {
+        false
+    }
+}
+
+/// Scripted input, every key is pressed right after the previous one was read
+impl KeyInput for VecDeque<u8> {
+    fn poll(&mut self) -> Option<u8> {
+        self.pop_front()
+    }
+
+    fn closed(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// Big-endian words: status at 0 (see [`KEY_READY`], [`KEY_INTERRUPT`] and
+/// [`KEY_CLOSED`]) and data at 2. Reading data takes the key, 0 without one,
+/// peeking leaves it there.
+pub struct Keyboard {
+    input: Box<dyn KeyInput>,
+    // every key polled so far, kept so stepping back can unread them and
+    // later ticks hand them out again
+    typed: Vec<u8>,
+    // keys handed out of `typed`
+    arrived: usize,
+    // next key the program reads, reads go through `&self`
+    position: Cell<usize>,
+    interrupt: bool,
+    line: InterruptLine,
+}
+
+impl Keyboard {
+    pub fn new(input: impl KeyInput + 'static, line: InterruptLine) -> Self {
+        Self {
+            input: Box::new(input),
+            typed: Vec::new(),
+            arrived: 0,
+            position: Cell::new(0),
+            interrupt: false,
+            line,
+        }
+    }
+
+    /// Replaces the input, a key already waiting stays
+    pub fn set_input(&mut self, input: impl KeyInput + 'static) {
+        self.input = Box::new(input);
+    }
+
+    /// Key waiting in the data register
+    pub fn key(&self) -> Option<u8> {
+        let position = self.position.get();
+        (position < self.arrived).then(|| self.typed[position])
+    }
+
+    pub fn status(&self) -> u16 {
+        let mut status = 0;
+        if self.key().is_some() {
+            status |= KEY_READY;
+        }
+        if self.interrupt {
+            status |= KEY_INTERRUPT;
+        }
+        if self.arrived == self.typed.len() && self.input.closed() {
+            status |= KEY_CLOSED;
+        }
+        status
+    }
+}
+
+impl MemoryMappedDevice for Keyboard {
+    fn get_u8(&self, addr: u16) -> u8 {
+        match addr {
+            0 | 1 => self.status().to_be_bytes()[addr as usize],
+            3 => match self.key() {
+                Some(key) => {
+                    self.position.set(self.position.get() + 1);
+                    key
+                }
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    fn peek_u8(&self, addr: u16) -> u8 {
+        match addr {
+            3 => self.key().unwrap_or(0),
+            _ => self.get_u8(addr),
+        }
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        if addr == 1 {
+            self.set_u16(0, value as u16);
+        }
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        u16::from_be_bytes([self.get_u8(addr), self.get_u8(addr.wrapping_add(1))])
+    }
+
+    /// Only [`KEY_INTERRUPT`] is writable, enabling it with a key waiting
+    /// raises the line right away
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        if addr != 0 {
+            return;
+        }
+        let enable = value & KEY_INTERRUPT != 0;
+        if enable && !self.interrupt && self.key().is_some() {
+            self.line.raise();
+        }
+        self.interrupt = enable;
+    }
+
+    /// Waiting key as flag and byte, then the interrupt flag. Input is not
+    /// part of it.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let key = self.key();
+        Some(vec![
+            key.is_some() as u8,
+            key.unwrap_or(0),
+            self.interrupt as u8,
+        ])
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        let [ready, key, interrupt] = state else {
+            return false;
+        };
+        self.typed = (*ready != 0).then_some(*key).into_iter().collect();
+        self.arrived = self.typed.len();
+        self.position.set(0);
+        self.interrupt = *interrupt != 0;
+        true
+    }
+
+    /// Read position, arrived keys and interrupt flag. Keys polled since
+    /// stay typed and arrive again.
+    fn undo_state(&self) -> Option<Vec<u8>> {
+        let mut state = (self.position.get() as u32).to_be_bytes().to_vec();
+        state.extend((self.arrived as u32).to_be_bytes());
+        state.push(self.interrupt as u8);
+        Some(state)
+    }
+
+    fn undo(&mut self, state: &[u8]) {
+        let [p0, p1, p2, p3, a0, a1, a2, a3, interrupt] = *state else {
+            return;
+        };
+        self.position
+            .set(u32::from_be_bytes([p0, p1, p2, p3]) as usize);
+        self.arrived = (u32::from_be_bytes([a0, a1, a2, a3]) as usize).min(self.typed.len());
+        self.interrupt = interrupt != 0;
+    }
+
+    fn tick(&mut self, _cycles: u64) {
+        if self.key().is_some() {
+            return;
+        }
+        if self.arrived == self.typed.len() {
+            let Some(key) = self.input.poll() else {
+                return;
+            };
+            self.typed.push(key);
+        }
+        self.arrived += 1;
+        if self.interrupt {
+            self.line.raise();
+        }
+    }
+}
+
+#[test]
+fn keyboard_input() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x1B, 0x00, 0x02, 0x31, 0x0C, // mov $2, &0x310c
+            0x13, 0x03, 0x00, 0x04, // loop: mov &0x0300, r3
+            0x10, 0x00, 0x02, 0x01, // mov $2, acc
+            0x40, 0x04, 0x00, 0x05, // jne r3, &loop
+            0xFF,
+        ],
+    );
+    // line 1 handler sums keys at 0x0302 and counts them at 0x0300
+    memory.load(0x0102, &[0x02, 0x00]);
+    memory.load(
+        0x0200,
+        &[
+            0x13, 0x31, 0x0E, 0x02, // mov &0x310e, r1
+            0x13, 0x03, 0x02, 0x03, // mov &0x0302, r2
+            0x14, 0x02, 0x03, // add r1, r2
+            0x12, 0x01, 0x03, 0x02, // mov acc, &0x0302
+            0x13, 0x03, 0x00, 0x04, // mov &0x0300, r3
+            0x35, 0x04, // inc r3
+            0x12, 0x04, 0x03, 0x00, // mov r3, &0x0300
+            0xFC, // rti
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let controller = cpu.interrupt_controller();
+    cpu.memory_mut().map(Region::new(
+        controller,
+        INTERRUPT_START,
+        INTERRUPT_END,
+        true,
+    ));
+    cpu.interrupts().set_vectors(0x0100);
+    let keyboard = Rc::new(RefCell::new(Keyboard::new(
+        VecDeque::from(*b"hi"),
+        cpu.interrupt_line(KEYBOARD_LINE),
+    )));
+    cpu.memory_mut().map(Region::new(
+        keyboard.clone(),
+        KEYBOARD_START,
+        KEYBOARD_END,
+        true,
+    ));
+    assert_eq!(cpu.run_for(1000), crate::cpu::Exit::Halted);
+
+    assert_eq!(cpu.memory().get_u16(0x0302), (b'h' + b'i') as u16);
+    assert_eq!(cpu.register(Register::R3), 2);
+    assert_eq!(
+        cpu.memory().get_u16(KEYBOARD_START),
+        KEY_INTERRUPT | KEY_CLOSED
+    );
+    assert_eq!(cpu.memory().get_u16(KEYBOARD_START + 2), 0);
+
+    // polled keys wait for the read, enabling with a key waiting raises
+    let interrupts = Rc::new(Interrupts::default());
+    let mut keyboard = Keyboard::new(
+        VecDeque::from(*b"ab"),
+        InterruptLine::new(interrupts.clone(), KEYBOARD_LINE),
+    );
+    assert_eq!(keyboard.get_u16(0), 0);
+    keyboard.tick(1);
+    keyboard.tick(1);
+    assert_eq!(keyboard.get_u16(0), KEY_READY);
+    assert_eq!(interrupts.pending(), 0);
+    keyboard.set_u8(1, KEY_INTERRUPT as u8);
+    assert_eq!(interrupts.pending(), 1 << KEYBOARD_LINE);
+
+    let state = keyboard.save_state().unwrap();
+    assert_eq!(keyboard.peek_u16(2), b'a' as u16);
+    assert_eq!(keyboard.get_u8(3), b'a');
+    keyboard.tick(1);
+    assert_eq!(keyboard.get_u16(2), b'b' as u16);
+    assert_eq!(keyboard.get_u16(0), KEY_INTERRUPT | KEY_CLOSED);
+    assert!(keyboard.load_state(&state));
+    assert_eq!(keyboard.get_u16(2), b'a' as u16);
+    assert!(!keyboard.load_state(&[1]));
+}
+
+#[test]
+fn keyboard_step_back() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x10, 0x00, 0x01, 0x04, // mov $1, r3
+            0x13, 0x31, 0x0E, 0x02, // mov &0x310e, r1
+            0x13, 0x31, 0x0E, 0x03, // mov &0x310e, r2
+            0xFF,
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    cpu.interrupts().set_mask(0);
+    let mut keyboard = Keyboard::new(VecDeque::from(*b"ab"), cpu.interrupt_line(KEYBOARD_LINE));
+    keyboard.set_u8(1, KEY_INTERRUPT as u8);
+    let keyboard = Rc::new(RefCell::new(keyboard));
+    cpu.memory_mut().map(Region::new(
+        keyboard.clone(),
+        KEYBOARD_START,
+        KEYBOARD_END,
+        true,
+    ));
+    cpu.record(10);
+    for _ in 0..3 {
+        cpu.step();
+    }
+    assert_eq!(cpu.register(Register::R2), b'b' as u16);
+
+    // the read key comes back, keys typed later arrive again on the same ticks
+    cpu.step_back();
+    assert_eq!(keyboard.borrow().key(), Some(b'b'));
+    cpu.step_back();
+    assert_eq!(keyboard.borrow().key(), Some(b'a'));
+    cpu.step_back();
+    assert_eq!(keyboard.borrow().status(), KEY_INTERRUPT);
+    assert_eq!(cpu.interrupts().pending(), 0);
+    cpu.step();
+    assert_eq!(cpu.interrupts().pending(), 1 << KEYBOARD_LINE);
+    cpu.run();
+    assert_eq!(cpu.register(Register::R1), b'a' as u16);
+    assert_eq!(cpu.register(Register::R2), b'b' as u16);
+    assert_eq!(keyboard.borrow().status(), KEY_INTERRUPT | KEY_CLOSED);
+}
@@ -0,0 +1,19 @@
+pub mod bank;
+pub mod coverage;
+pub mod cpu;
+pub mod device;
+pub mod fault;
+pub mod history;
+pub mod instructions;
+pub mod interrupt;
+pub mod keyboard;
+pub mod mapper;
+pub mod memory;
+pub mod profile;
+pub mod registers;
+pub mod screen;
+pub mod snapshot;
+pub mod timer;
+pub mod timing;
+pub mod trace;
+pub mod unwind;
@@ -0,0 +1,476 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+};
+
+use crate::{device::MemoryMappedDevice, memory::Memory, snapshot::SnapshotError};
+
+#[cfg(test)]
+use std::rc::Rc;
+
+/// Device mapped at `start..=end`, with `remap` it sees addresses relative to `start`
+pub struct Region {
+    pub device: Box<dyn MemoryMappedDevice>,
+    pub start: u16,
+    pub end: u16,
+    pub remap: bool,
+}
+
+impl Region {
+    pub fn new(
+        device: impl MemoryMappedDevice + 'static,
+        start: u16,
+        end: u16,
+        remap: bool,
+    ) -> Self {
+        Self {
+            device: Box::new(device),
+            start,
+            end,
+            remap,
+        }
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+/// Returned by [`MemoryMapper::map`], unmaps its region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, Access::Read)
+                | (WatchKind::Write, Access::Write)
+        )
+    }
+}
+
+/// Bus accesses touching `start..=end`, id is picked by the caller
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+/// Access caught by a watchpoint, `value` is what was read or written
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub access: Access,
+    pub addr: u16,
+    pub value: u16,
+}
+
+/// Byte written while journaling, `new` is what went on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Address bus, see `core/src/memory/memorymapper.cpp`. Most recently mapped
+/// region wins, unmapped addresses read as 0 and ignore writes.
+/// Watchpoints see every access before it reaches a device.
+#[derive(Default)]
+pub struct MemoryMapper {
+    // newest first
+    regions: Vec<(Mapping, Region)>,
+    next: u64,
+    watchpoints: Vec<Watchpoint>,
+    // reads go through `&self`
+    hits: RefCell<Vec<WatchHit>>,
+    unmapped: Cell<Option<(u16, Access)>>,
+    // every write while recording
+    journal: Option<Vec<MemoryWrite>>,
+}
+
+impl MemoryMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map(&mut self, region: Region) -> Mapping {
+        let mapping = Mapping(self.next);
+        self.next += 1;
+        self.regions.insert(0, (mapping, region));
+        mapping
+    }
+
+    /// Gives the device back, `None` if it was already unmapped
+    pub fn unmap(&mut self, mapping: Mapping) -> Option<Box<dyn MemoryMappedDevice>> {
+        let pos = self.regions.iter().position(|(x, _)| *x == mapping)?;
+        Some(self.regions.remove(pos).1.device)
+    }
+
+    pub fn find_region(&self, addr: u16) -> Option<&Region> {
+        self.regions
+            .iter()
+            .map(|(_, region)| region)
+            .find(|region| region.contains(addr))
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|x| x.id != id);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Hits since the last call, oldest first
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        self.hits.take()
+    }
+
+    /// Hits since the last [`Self::take_hits`] without consuming them
+    pub fn pending_hits(&self) -> Vec<WatchHit> {
+        self.hits.borrow().clone()
+    }
+
+    /// First access no device answered since the last call
+    pub fn take_unmapped(&self) -> Option<(u16, Access)> {
+        self.unmapped.take()
+    }
+
+    /// Starts remembering what writes overwrite, see [`Self::restore`]
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Written bytes in write order, stops recording
+    pub fn take_journal(&mut self) -> Vec<MemoryWrite> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Puts journaled bytes back, newest first. Bypasses watchpoints, devices
+    /// only get back what they return on reads.
+    pub fn restore(&mut self, journal: &[MemoryWrite]) {
+        for write in journal.iter().rev() {
+            if let Some((region, addr)) = self.resolve_mut(write.addr) {
+                region.device.set_u8(addr, write.old)
+            }
+        }
+    }
+
+    /// Undo states of devices keeping one, see [`Self::undo`]
+    pub fn undo_states(&self) -> Vec<(Mapping, Vec<u8>)> {
+        self.regions
+            .iter()
+            .filter_map(|(mapping, region)| Some((*mapping, region.device.undo_state()?)))
+            .collect()
+    }
+
+    /// Puts [`Self::undo_states`] back, devices unmapped since are skipped
+    pub fn undo(&mut self, states: &[(Mapping, Vec<u8>)]) {
+        for (mapping, state) in states {
+            if let Some((_, region)) = self.regions.iter_mut().find(|(x, _)| x == mapping) {
+                region.device.undo(state);
+            }
+        }
+    }
+
+    /// Device states for snapshots, oldest mapping first
+    pub fn save_states(&self) -> Vec<Option<Vec<u8>>> {
+        self.regions
+            .iter()
+            .rev()
+            .map(|(_, region)| region.device.save_state())
+            .collect()
+    }
+
+    /// Puts [`Self::save_states`] back, devices have to be mapped the same way.
+    /// Devices before the one rejecting its state are already restored.
+    pub fn load_states(&mut self, states: &[Option<Vec<u8>>]) -> Result<(), SnapshotError> {
+        if states.len() != self.regions.len() {
+            return Err(SnapshotError::DeviceCount(states.len(), self.regions.len()));
+        }
+        let regions = self.regions.iter_mut().rev().map(|(_, region)| region);
+        for (i, (region, state)) in regions.zip(states).enumerate() {
+            let loaded = match state {
+                Some(state) => region.device.load_state(state),
+                None => region.device.save_state().is_none(),
+            };
+            if !loaded {
+                return Err(SnapshotError::DeviceState(i));
+            }
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, addr: u16, bytes: &[u8]) {
+        if self.journal.is_none() {
+            return;
+        }
+        let writes: Vec<MemoryWrite> = bytes
+            .iter()
+            .enumerate()
+            .map(|(i, new)| {
+                let addr = addr.wrapping_add(i as u16);
+                let old = self.peek_u8(addr);
+                MemoryWrite {
+                    addr,
+                    old,
+                    new: *new,
+                }
+            })
+            .collect();
+        if let Some(journal) = &mut self.journal {
+            journal.extend(writes);
+        }
+    }
+
+    fn watch(&self, access: Access, addr: u16, size: u16, value: u16) {
+        for watchpoint in &self.watchpoints {
+            let touched = (0..size)
+                .map(|i| addr.wrapping_add(i))
+                .any(|x| watchpoint.start <= x && x <= watchpoint.end);
+            if touched && watchpoint.kind.matches(access) {
+                self.hits.borrow_mut().push(WatchHit {
+                    id: watchpoint.id,
+                    access,
+                    addr,
+                    value,
+                });
+            }
+        }
+    }
+
+    fn resolve(&self, addr: u16) -> Option<(&Region, u16)> {
+        let region = self.find_region(addr)?;
+        Some((region, device_addr(region, addr)))
+    }
+
+    fn miss(&self, addr: u16, access: Access) {
+        if self.unmapped.get().is_none() {
+            self.unmapped.set(Some((addr, access)));
+        }
+    }
+
+    fn resolve_mut(&mut self, addr: u16) -> Option<(&mut Region, u16)> {
+        let region = self
+            .regions
+            .iter_mut()
+            .map(|(_, region)| region)
+            .find(|region| region.contains(addr))?;
+        let addr = device_addr(region, addr);
+        Some((region, addr))
+    }
+}
+
+fn device_addr(region: &Region, addr: u16) -> u16 {
+    if region.remap {
+        addr - region.start
+    } else {
+        addr
+    }
+}
+
+/// Plain RAM over the whole address space
+impl From<Memory> for MemoryMapper {
+    fn from(memory: Memory) -> Self {
+        let mut mapper = Self::new();
+        mapper.map(Region::new(memory, 0x0000, 0xffff, false));
+        mapper
+    }
+}
+
+impl MemoryMappedDevice for MemoryMapper {
+    fn get_u8(&self, addr: u16) -> u8 {
+        let value = match self.resolve(addr) {
+            Some((region, device_addr)) => region.device.get_u8(device_addr),
+            None => {
+                self.miss(addr, Access::Read);
+                0
+            }
+        };
+        self.watch(Access::Read, addr, 1, value as u16);
+        value
+    }
+
+    /// Skips watchpoints and unmapped access tracking too
+    fn peek_u8(&self, addr: u16) -> u8 {
+        self.resolve(addr).map_or(0, |(region, device_addr)| {
+            region.device.peek_u8(device_addr)
+        })
+    }
+
+    fn peek_u16(&self, addr: u16) -> u16 {
+        self.resolve(addr).map_or(0, |(region, device_addr)| {
+            region.device.peek_u16(device_addr)
+        })
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        self.watch(Access::Write, addr, 1, value as u16);
+        self.remember(addr, &[value]);
+        match self.resolve_mut(addr) {
+            Some((region, addr)) => region.device.set_u8(addr, value),
+            None => self.miss(addr, Access::Write),
+        }
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        let value = match self.resolve(addr) {
+            Some((region, device_addr)) => region.device.get_u16(device_addr),
+            None => {
+                self.miss(addr, Access::Read);
+                0
+            }
+        };
+        self.watch(Access::Read, addr, 2, value);
+        value
+    }
+
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        self.watch(Access::Write, addr, 2, value);
+        self.remember(addr, &value.to_be_bytes());
+        match self.resolve_mut(addr) {
+            Some((region, addr)) => region.device.set_u16(addr, value),
+            None => self.miss(addr, Access::Write),
+        }
+    }
+
+    /// Ticks every mapped device, oldest mapping first
+    fn tick(&mut self, cycles: u64) {
+        for (_, region) in self.regions.iter_mut().rev() {
+            region.device.tick(cycles);
+        }
+    }
+}
+
+impl fmt::Debug for MemoryMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.regions
+                    .iter()
+                    .map(|(mapping, region)| (mapping, region.start..=region.end, region.remap)),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FakeDevice {
+    writes: Vec<(u16, u8)>,
+}
+
+#[cfg(test)]
+impl MemoryMappedDevice for FakeDevice {
+    fn get_u8(&self, addr: u16) -> u8 {
+        addr as u8
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        self.writes.push((addr, value));
+    }
+}
+
+#[test]
+fn mapper_regions() {
+    let mut mapper = MemoryMapper::from(Memory::default());
+    mapper.set_u16(0x3010, 0xBEEF);
+
+    let fake = Rc::new(RefCell::new(FakeDevice::default()));
+    let mapping = mapper.map(Region::new(fake.clone(), 0x3000, 0x30ff, true));
+    // newest region shadows RAM, addresses are relative to its start
+    assert_eq!(mapper.get_u16(0x3010), 0x1011);
+    mapper.set_u16(0x3020, 0x4142);
+    assert_eq!(fake.borrow().writes, vec![(0x20, 0x41), (0x21, 0x42)]);
+    assert_eq!(mapper.get_u8(0x3100), 0x00);
+
+    assert!(mapper.unmap(mapping).is_some());
+    assert!(mapper.unmap(mapping).is_none());
+    assert_eq!(mapper.get_u16(0x3010), 0xBEEF);
+
+    // without remap device sees absolute addresses
+    mapper.map(Region::new(FakeDevice::default(), 0x3000, 0x30ff, false));
+    assert_eq!(mapper.get_u8(0x3010), 0x10);
+
+    let empty = MemoryMapper::new();
+    assert_eq!(empty.get_u16(0x1234), 0);
+}
+
+#[test]
+fn mapper_watchpoints() {
+    let mut mapper = MemoryMapper::from(Memory::default());
+    mapper.map(Region::new(FakeDevice::default(), 0x3000, 0x30ff, true));
+    mapper.add_watchpoint(Watchpoint {
+        id: 1,
+        start: 0x3010,
+        end: 0x3011,
+        kind: WatchKind::Write,
+    });
+    mapper.add_watchpoint(Watchpoint {
+        id: 2,
+        start: 0x0100,
+        end: 0x0100,
+        kind: WatchKind::Access,
+    });
+
+    // device window is watched like RAM, reads do not trip a write watchpoint
+    mapper.set_u16(0x300f, 0x1234);
+    mapper.get_u16(0x3010);
+    mapper.set_u8(0x3012, 0x01);
+    // second byte of the word is inside the range
+    mapper.set_u16(0x00ff, 0xABCD);
+    mapper.get_u8(0x0100);
+    assert_eq!(
+        mapper.take_hits(),
+        vec![
+            WatchHit {
+                id: 1,
+                access: Access::Write,
+                addr: 0x300f,
+                value: 0x1234
+            },
+            WatchHit {
+                id: 2,
+                access: Access::Write,
+                addr: 0x00ff,
+                value: 0xABCD
+            },
+            WatchHit {
+                id: 2,
+                access: Access::Read,
+                addr: 0x0100,
+                value: 0xCD
+            },
+        ]
+    );
+    assert!(mapper.take_hits().is_empty());
+
+    assert!(mapper.remove_watchpoint(2));
+    assert!(!mapper.remove_watchpoint(2));
+    mapper.get_u8(0x0100);
+    assert!(mapper.take_hits().is_empty());
+}
@@ -0,0 +1,98 @@
+use crate::device::MemoryMappedDevice;
+
+pub const MEM_SIZE: usize = 256 * 256;
+
+/// Byte addressable RAM, 16-bit values are big-endian. Addresses past the
+/// end wrap around to the start, so smaller RAM repeats over its region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    buffer: Vec<u8>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(MEM_SIZE)
+    }
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "memory needs at least one byte");
+        Self {
+            buffer: vec![0; size],
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl MemoryMappedDevice for Memory {
+    fn get_u8(&self, addr: u16) -> u8 {
+        self.buffer[addr as usize % self.buffer.len()]
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let len = self.buffer.len();
+        self.buffer[addr as usize % len] = value;
+    }
+
+    fn load(&mut self, addr: u16, bytes: &[u8]) {
+        let start = addr as usize;
+        match self.buffer.get_mut(start..start + bytes.len()) {
+            Some(window) => window.copy_from_slice(bytes),
+            None => {
+                let len = self.buffer.len();
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.buffer[addr.wrapping_add(i as u16) as usize % len] = *byte;
+                }
+            }
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(self.buffer.clone())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != self.buffer.len() {
+            return false;
+        }
+        self.buffer.copy_from_slice(state);
+        true
+    }
+}
+
+#[test]
+fn memory_big_endian() {
+    let mut memory = Memory::default();
+    memory.set_u16(0x0010, 0x1234);
+    assert_eq!(memory.get_u8(0x0010), 0x12);
+    assert_eq!(memory.get_u8(0x0011), 0x34);
+    assert_eq!(memory.get_u16(0x0010), 0x1234);
+
+    memory.load(0xfffe, &[0xAB, 0xCD]);
+    assert_eq!(memory.get_u16(0xfffe), 0xABCD);
+    memory.load(0xffff, &[0x01, 0x02, 0x03]);
+    assert_eq!(memory.get_u8(0xffff), 0x01);
+    assert_eq!(memory.get_u16(0x0000), 0x0203);
+}
+
+#[test]
+fn memory_small() {
+    let mut memory = Memory::new(4);
+    memory.set_u16(0x0006, 0x1234);
+    assert_eq!(memory.bytes(), [0x00, 0x00, 0x12, 0x34]);
+    assert_eq!(memory.get_u16(0x0102), 0x1234);
+    assert_eq!(memory.get_u16(0xffff), 0x3400);
+
+    memory.load(0x0003, &[0xAB, 0xCD, 0xEF, 0x01, 0x02]);
+    assert_eq!(memory.bytes(), [0xCD, 0xEF, 0x01, 0x02]);
+
+    // addresses wrap at 0x10000 first like on the bus
+    let mut memory = Memory::new(7);
+    memory.load(0xffff, &[0x01, 0x02]);
+    assert_eq!(memory.bytes()[..2], [0x02, 0x01]);
+    assert_eq!(memory.get_u16(0xffff), 0x0102);
+}
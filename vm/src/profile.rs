@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use crate::{
+    cpu::Cpu,
+    instructions::{Instruction, Instructions},
+    registers::Register,
+};
+
+#[cfg(test)]
+use crate::{device::MemoryMappedDevice, memory::Memory};
+
+/// Execution counts per address, opcode and call stack. Calls are followed
+/// through a shadow stack of entry addresses: `CALL_*` pushes the target,
+/// `RET` pops it, so counts roll up to every function on the stack. `int`,
+/// delivered interrupts and `rti` count as calls and returns of handlers.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    addresses: Vec<u64>,
+    opcodes: Vec<u64>,
+    // entry addresses outermost first, with instructions executed on top of them
+    stacks: Vec<(Vec<u16>, u64)>,
+    index: HashMap<Vec<u16>, usize>,
+    stack: Vec<u16>,
+    current: usize,
+}
+
+impl Profile {
+    /// Program starts running at `entry`
+    pub fn new(entry: u16) -> Self {
+        let stack = vec![entry];
+        Self {
+            addresses: vec![0; 0x10000],
+            opcodes: vec![0; 0x100],
+            stacks: vec![(stack.clone(), 0)],
+            index: HashMap::from([(stack.clone(), 0)]),
+            stack,
+            current: 0,
+        }
+    }
+
+    /// Counts `instruction` once `cpu` executed it
+    pub fn count(&mut self, instruction: &Instruction, cpu: &Cpu) {
+        self.addresses[instruction.address as usize] += 1;
+        self.opcodes[instruction.opcode as usize] += 1;
+        self.stacks[self.current].1 += 1;
+
+        let ip = cpu.next_ip();
+        let fall_through = instruction.address.wrapping_add(instruction.size);
+        let mut changed = true;
+        match instruction.kind {
+            Some(Instructions::CALL_LIT | Instructions::CALL_REG) => self.stack.push(ip),
+            // masked lines fall through
+            Some(Instructions::INT_LIT) if ip != fall_through => self.stack.push(ip),
+            // return from the outermost frame leaves nothing to pop
+            Some(Instructions::RET | Instructions::RET_INT) if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => changed = false,
+        }
+        if cpu.interrupted().is_some() {
+            self.stack.push(cpu.register(Register::Ip));
+            changed = true;
+        }
+        if !changed {
+            return;
+        }
+        self.current = match self.index.get(&self.stack) {
+            Some(id) => *id,
+            None => {
+                self.stacks.push((self.stack.clone(), 0));
+                self.index.insert(self.stack.clone(), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+    }
+
+    pub fn total(&self) -> u64 {
+        self.stacks.iter().map(|(_, count)| count).sum()
+    }
+
+    /// Executed addresses with their counts in address order
+    pub fn addresses(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| (addr as u16, *count))
+    }
+
+    /// Executed opcodes with their counts, unknown ones included
+    pub fn opcodes(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        self.opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(opcode, count)| (opcode as u8, *count))
+    }
+
+    /// Call stacks of entry addresses, outermost first, with own instruction counts
+    pub fn stacks(&self) -> impl Iterator<Item = (&[u16], u64)> + '_ {
+        self.stacks
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(stack, count)| (stack.as_slice(), *count))
+    }
+}
+
+#[test]
+fn profile_counts() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x17, 0x00, 0x00, // push $0
+            0x5E, 0x00, 0x10, // call &0x10
+            0x17, 0x00, 0x00, // push $0
+            0x5E, 0x00, 0x10, // call &0x10
+            0xFF,
+        ],
+    );
+    memory.load(
+        0x10,
+        &[
+            0x35, 0x02, // inc r1
+            0x60, // ret
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let mut profile = Profile::new(0);
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let halted = cpu.step();
+        profile.count(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(profile.total(), 9);
+    assert_eq!(
+        profile.addresses().collect::<Vec<_>>(),
+        vec![
+            (0x00, 1),
+            (0x03, 1),
+            (0x06, 1),
+            (0x09, 1),
+            (0x0C, 1),
+            (0x10, 2),
+            (0x12, 2)
+        ]
+    );
+    assert_eq!(
+        profile.opcodes().collect::<Vec<_>>(),
+        vec![(0x17, 2), (0x35, 2), (0x5E, 2), (0x60, 2), (0xFF, 1)]
+    );
+    // pushes, calls and hlt run in the outer frame, ret still belongs to the callee
+    assert_eq!(
+        profile.stacks().collect::<Vec<_>>(),
+        vec![(&[0x00][..], 5), (&[0x00, 0x10][..], 4)]
+    );
+}
+
+#[test]
+fn profile_interrupts() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0xFD, 0x00, 0x01, // int $1
+            0x35, 0x02, // inc r1
+            0xFF,
+        ],
+    );
+    // both lines run the same handler
+    memory.load(0x0100, &[0x02, 0x00, 0x02, 0x00]);
+    memory.load(
+        0x0200,
+        &[
+            0x35, 0x03, // inc r2
+            0xFC, // rti
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    cpu.interrupts().set_vectors(0x0100);
+    let mut profile = Profile::new(0);
+    loop {
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        // line 0 comes in right after `inc r1`
+        if instruction.address == 0x03 {
+            cpu.interrupt_line(0).raise();
+        }
+        let halted = cpu.step();
+        profile.count(&instruction, &cpu);
+        if halted {
+            break;
+        }
+    }
+
+    assert_eq!(profile.total(), 7);
+    assert_eq!(
+        profile.stacks().collect::<Vec<_>>(),
+        vec![(&[0x00][..], 3), (&[0x00, 0x0200][..], 4)]
+    );
+}
@@ -0,0 +1,60 @@
+/// Register file in the order of `global_registers` in `core/src/cpu/cpu.hpp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Register {
+    Ip,
+    Acc,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    Sp,
+    Fp,
+}
+
+impl Register {
+    pub const ALL: [Register; 12] = [
+        Self::Ip,
+        Self::Acc,
+        Self::R1,
+        Self::R2,
+        Self::R3,
+        Self::R4,
+        Self::R5,
+        Self::R6,
+        Self::R7,
+        Self::R8,
+        Self::Sp,
+        Self::Fp,
+    ];
+
+    /// Register encoded in instruction byte, out of range indexes wrap around
+    pub fn from_index(index: u8) -> Self {
+        Self::ALL[index as usize % Self::ALL.len()]
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::Acc => "acc",
+            Self::R1 => "r1",
+            Self::R2 => "r2",
+            Self::R3 => "r3",
+            Self::R4 => "r4",
+            Self::R5 => "r5",
+            Self::R6 => "r6",
+            Self::R7 => "r7",
+            Self::R8 => "r8",
+            Self::Sp => "sp",
+            Self::Fp => "fp",
+        }
+    }
+}
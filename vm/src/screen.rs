@@ -0,0 +1,241 @@
+use crate::device::MemoryMappedDevice;
+
+/// Where `core/src/main.cpp` maps the screen, device addresses are remapped
+pub const SCREEN_START: u16 = 0x3000;
+pub const SCREEN_END: u16 = 0x30ff;
+
+pub const SCREEN_WIDTH: usize = 16;
+pub const SCREEN_HEIGHT: usize = 16;
+
+// high byte of a 16-bit write
+const CMD_CLEAR: u8 = 0xff;
+const CMD_BOLD: u8 = 0x01;
+const CMD_REGULAR: u8 = 0x02;
+const CMD_DIM: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Attribute {
+    #[default]
+    Regular,
+    Bold,
+    Dim,
+}
+
+impl Attribute {
+    /// Command byte selecting the attribute
+    fn command(self) -> u8 {
+        match self {
+            Self::Regular => CMD_REGULAR,
+            Self::Bold => CMD_BOLD,
+            Self::Dim => CMD_DIM,
+        }
+    }
+
+    fn from_command(command: u8) -> Option<Self> {
+        match command {
+            CMD_REGULAR => Some(Self::Regular),
+            CMD_BOLD => Some(Self::Bold),
+            CMD_DIM => Some(Self::Dim),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub attr: Attribute,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            ch: ' ',
+            attr: Attribute::Regular,
+        }
+    }
+}
+
+/// Headless `core/src/devices/screendevice.cpp`: same write protocol, but
+/// cells are kept in a grid and drawn by a [`Backend`] on demand.
+/// Attribute commands stick for following writes like ANSI modes do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    cells: Vec<Cell>,
+    attr: Attribute,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Self {
+            cells: vec![Cell::default(); SCREEN_WIDTH * SCREEN_HEIGHT],
+            attr: Attribute::Regular,
+        }
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Cell {
+        self.cells[y * SCREEN_WIDTH + x]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[Cell]> {
+        self.cells.chunks(SCREEN_WIDTH)
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.fill(Cell::default());
+    }
+
+    pub fn render(&self, backend: Backend) -> String {
+        match backend {
+            Backend::Ansi => self.to_ansi(),
+            Backend::Text => self.to_text(),
+        }
+    }
+
+    /// Escape sequences core would print, cells are two columns apart
+    fn to_ansi(&self) -> String {
+        let mut out = String::from("\x1b[2J");
+        for (y, row) in self.rows().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                if *cell == Cell::default() {
+                    continue;
+                }
+                let mode = match cell.attr {
+                    Attribute::Regular => 0,
+                    Attribute::Bold => 1,
+                    Attribute::Dim => 2,
+                };
+                out += &format!("\x1b[{};{}H\x1b[{}m{}", y + 1, (x + 1) * 2, mode, cell.ch);
+            }
+        }
+        out + "\x1b[0m"
+    }
+
+    /// One line per row without trailing blanks, attributes are dropped
+    fn to_text(&self) -> String {
+        self.rows()
+            .map(|row| {
+                let line: String = row.iter().map(|x| x.ch).collect();
+                line.trim_end().to_string() + "\n"
+            })
+            .collect()
+    }
+}
+
+/// How [`Screen::render`] draws the grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Terminal output with cursor movement and attributes
+    Ansi,
+    /// Plain text for golden tests
+    Text,
+}
+
+impl MemoryMappedDevice for Screen {
+    fn get_u8(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn set_u8(&mut self, _addr: u16, _value: u8) {}
+
+    fn get_u16(&self, _addr: u16) -> u16 {
+        0
+    }
+
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        let [command, ch] = value.to_be_bytes();
+        match command {
+            CMD_CLEAR => self.clear(),
+            CMD_BOLD => self.attr = Attribute::Bold,
+            CMD_REGULAR => self.attr = Attribute::Regular,
+            CMD_DIM => self.attr = Attribute::Dim,
+            _ => {}
+        }
+
+        let Some(cell) = self.cells.get_mut(addr as usize) else {
+            return;
+        };
+        *cell = Cell {
+            ch: if ch == 0 { ' ' } else { ch as char },
+            attr: self.attr,
+        };
+    }
+
+    /// Character and attribute command byte per cell, then current attribute
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state: Vec<u8> = self
+            .cells
+            .iter()
+            .flat_map(|x| [x.ch as u8, x.attr.command()])
+            .collect();
+        state.push(self.attr.command());
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        let Some((attr, cells)) = state.split_last() else {
+            return false;
+        };
+        if cells.len() != self.cells.len() * 2 {
+            return false;
+        }
+        let cells: Option<Vec<Cell>> = cells
+            .chunks(2)
+            .map(|x| {
+                Some(Cell {
+                    ch: x[0] as char,
+                    attr: Attribute::from_command(x[1])?,
+                })
+            })
+            .collect();
+        match (cells, Attribute::from_command(*attr)) {
+            (Some(cells), Some(attr)) => {
+                self.cells = cells;
+                self.attr = attr;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn screen_protocol() {
+    let mut screen = Screen::new();
+    screen.set_u16(0x00, u16::from(b'h'));
+    screen.set_u16(0x01, 0x0100 | u16::from(b'i'));
+    screen.set_u16(0x12, u16::from(b'!'));
+    screen.set_u16(0x13, 0x0300 | u16::from(b'.'));
+
+    assert_eq!(
+        screen.cell(1, 0),
+        Cell {
+            ch: 'i',
+            attr: Attribute::Bold
+        }
+    );
+    // bold sticks until another command
+    assert_eq!(screen.cell(2, 1).attr, Attribute::Bold);
+    assert_eq!(screen.cell(3, 1).attr, Attribute::Dim);
+    assert_eq!(
+        screen.render(Backend::Text),
+        format!("hi\n  !.\n{}", "\n".repeat(14))
+    );
+    assert_eq!(
+        screen.render(Backend::Ansi),
+        "\x1b[2J\x1b[1;2H\x1b[0mh\x1b[1;4H\x1b[1mi\x1b[2;6H\x1b[1m!\x1b[2;8H\x1b[2m.\x1b[0m"
+    );
+
+    // clear wipes the grid, the char is still drawn
+    screen.set_u16(0x20, 0xff00 | u16::from(b'x'));
+    assert_eq!(
+        screen.render(Backend::Text),
+        format!("\n\nx\n{}", "\n".repeat(13))
+    );
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+use crate::trace::Reader;
+
+#[cfg(test)]
+use crate::{
+    cpu::{Cpu, STACK_START},
+    device::MemoryMappedDevice,
+    mapper::{MemoryMapper, Region},
+    memory::Memory,
+    registers::Register,
+    screen::{Screen, SCREEN_END, SCREEN_START},
+};
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+/// Starts snapshot file, followed by big-endian [`SNAPSHOT_VERSION`]
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"16SN";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Full machine state, see [`Cpu::snapshot`](crate::cpu::Cpu::snapshot)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [u16; 12],
+    /// `_stackframe_size` of the current frame
+    pub frame_size: u16,
+    /// Interrupt handlers not returned from yet
+    pub handlers: u16,
+    pub executed: u64,
+    pub cycles: u64,
+    /// Device states oldest mapping first, RAM included
+    pub devices: Vec<Option<Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    /// Devices in snapshot and mapped ones
+    DeviceCount(usize, usize),
+    /// Device at this position, oldest mapping first, rejected its state
+    DeviceState(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a snapshot"),
+            Self::UnsupportedVersion(x) => write!(f, "unsupported snapshot version {}", x),
+            Self::Truncated => write!(f, "snapshot is truncated"),
+            Self::DeviceCount(saved, mapped) => {
+                write!(f, "snapshot has {} devices, machine maps {}", saved, mapped)
+            }
+            Self::DeviceState(x) => write!(f, "device {} does not fit its saved state", x),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Magic and version, registers, frame size, handler depth, instruction and cycle counts,
+    /// then counted devices as presence byte, 32-bit length and state
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        out.extend(SNAPSHOT_VERSION.to_be_bytes());
+        for reg in self.registers {
+            out.extend(reg.to_be_bytes());
+        }
+        out.extend(self.frame_size.to_be_bytes());
+        out.extend(self.handlers.to_be_bytes());
+        out.extend(self.executed.to_be_bytes());
+        out.extend(self.cycles.to_be_bytes());
+
+        out.extend((self.devices.len() as u16).to_be_bytes());
+        for device in &self.devices {
+            match device {
+                Some(state) => {
+                    out.push(1);
+                    out.extend((state.len() as u32).to_be_bytes());
+                    out.extend(state);
+                }
+                None => out.push(0),
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take::<4>().as_ref() != Some(SNAPSHOT_MAGIC) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u16().ok_or(SnapshotError::Truncated)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Self::decode_body(&mut reader).ok_or(SnapshotError::Truncated)
+    }
+
+    fn decode_body(reader: &mut Reader) -> Option<Self> {
+        let mut registers = [0; 12];
+        for reg in registers.iter_mut() {
+            *reg = reader.u16()?;
+        }
+        let frame_size = reader.u16()?;
+        let handlers = reader.u16()?;
+        let executed = u64::from_be_bytes(reader.take()?);
+        let cycles = u64::from_be_bytes(reader.take()?);
+
+        let mut devices = Vec::new();
+        for _ in 0..reader.u16()? {
+            devices.push(match reader.u8()? {
+                0 => None,
+                _ => {
+                    let len = u32::from_be_bytes(reader.take()?);
+                    Some(reader.slice(len as usize)?.to_vec())
+                }
+            });
+        }
+        Some(Self {
+            registers,
+            frame_size,
+            handlers,
+            executed,
+            cycles,
+            devices,
+        })
+    }
+}
+
+#[test]
+fn snapshot_restore() {
+    let machine = || {
+        let mut memory = Memory::default();
+        memory.load(
+            0,
+            &[
+                0x17, 0x00, 0xAA, // push $0xAA
+                0x1B, 0x01, 0x48, 0x30, 0x00, // mov $0x0148, &0x3000
+                0x35, 0x02, // inc r1
+                0xFF,
+            ],
+        );
+        let screen = Rc::new(RefCell::new(Screen::new()));
+        let mut mapper = MemoryMapper::from(memory);
+        mapper.map(Region::new(screen.clone(), SCREEN_START, SCREEN_END, true));
+        (Cpu::new(mapper), screen)
+    };
+
+    let (mut cpu, _) = machine();
+    cpu.step();
+    cpu.step();
+    let snapshot = cpu.snapshot();
+    let bytes = snapshot.encode();
+    assert_eq!(&bytes[..6], b"16SN\x00\x02");
+    assert_eq!(Snapshot::decode(&bytes), Ok(snapshot.clone()));
+
+    let (mut other, screen) = machine();
+    other.restore(&Snapshot::decode(&bytes).unwrap()).unwrap();
+    assert_eq!(other.register(Register::Ip), 0x08);
+    assert_eq!(other.memory().get_u16(STACK_START), 0xAA);
+    assert_eq!(screen.borrow().cell(0, 0).ch, 'H');
+    assert_eq!(other.executed(), 2);
+    assert_eq!(other.cycles(), cpu.cycles());
+    // frame size comes back too, so the pushed word is still counted
+    assert_eq!(other.snapshot(), snapshot);
+    other.run();
+    assert_eq!(other.register(Register::R1), 1);
+
+    assert_eq!(Snapshot::decode(b"16TR"), Err(SnapshotError::BadMagic));
+    assert_eq!(
+        Snapshot::decode(b"16SN\x00\x01"),
+        Err(SnapshotError::UnsupportedVersion(1))
+    );
+    assert_eq!(
+        Snapshot::decode(&bytes[..bytes.len() - 1]),
+        Err(SnapshotError::Truncated)
+    );
+
+    let mut plain = Cpu::new(Memory::default());
+    assert_eq!(
+        plain.restore(&snapshot),
+        Err(SnapshotError::DeviceCount(2, 1))
+    );
+    let mut broken = snapshot.clone();
+    broken.devices[1] = Some(vec![0x41]);
+    assert_eq!(
+        machine().0.restore(&broken).unwrap_err().to_string(),
+        "device 1 does not fit its saved state"
+    );
+}
@@ -0,0 +1,257 @@
+use crate::{device::MemoryMappedDevice, interrupt::InterruptLine};
+
+#[cfg(test)]
+use crate::{
+    cpu::Cpu,
+    interrupt::{Interrupts, INTERRUPT_END, INTERRUPT_START},
+    mapper::Region,
+    memory::Memory,
+    registers::Register,
+};
+#[cfg(test)]
+use std::rc::Rc;
+
+/// Where emu maps the timer, right after the keyboard
+pub const TIMER_START: u16 = 0x3110;
+pub const TIMER_END: u16 = 0x3117;
+
+/// Line raised on expiry when the program asked for it, taken before the
+/// keyboard
+pub const TIMER_LINE: u8 = 0;
+
+/// Control bit that starts the timer from the reload value
+pub const TIMER_ENABLE: u16 = 1;
+/// Control bit to count cycles instead of instructions
+pub const TIMER_CYCLES: u16 = 1 << 1;
+/// Control bit to get [`TIMER_LINE`] raised on expiry
+pub const TIMER_INTERRUPT: u16 = 1 << 2;
+/// Control bit to start over from the reload value on expiry instead of stopping
+pub const TIMER_PERIODIC: u16 = 1 << 3;
+
+/// Status bit set on expiry until the program writes the status word
+pub const TIMER_EXPIRED: u16 = 1;
+
+/// Counts down once per instruction or per cycle, so runs with the same cost
+/// table expire at the same instruction every time. Big-endian words:
+/// control at 0, reload value at 2, current count at 4 and status at 6.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    control: u16,
+    reload: u16,
+    counter: u16,
+    status: u16,
+    line: InterruptLine,
+}
+
+impl Timer {
+    pub fn new(line: InterruptLine) -> Self {
+        Self {
+            control: 0,
+            reload: 0,
+            counter: 0,
+            status: 0,
+            line,
+        }
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    fn expire(&mut self) {
+        self.status |= TIMER_EXPIRED;
+        if self.control & TIMER_PERIODIC != 0 && self.reload != 0 {
+            self.counter = self.reload;
+        } else {
+            self.counter = 0;
+            self.control &= !TIMER_ENABLE;
+        }
+    }
+}
+
+impl MemoryMappedDevice for Timer {
+    fn get_u8(&self, addr: u16) -> u8 {
+        self.get_u16(addr & !1).to_be_bytes()[(addr & 1) as usize]
+    }
+
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let mut word = self.get_u16(addr & !1).to_be_bytes();
+        word[(addr & 1) as usize] = value;
+        self.set_u16(addr & !1, u16::from_be_bytes(word));
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        match addr {
+            0 => self.control,
+            2 => self.reload,
+            4 => self.counter,
+            6 => self.status,
+            _ => 0,
+        }
+    }
+
+    /// Setting [`TIMER_ENABLE`] loads the reload value, any status write
+    /// clears [`TIMER_EXPIRED`]
+    fn set_u16(&mut self, addr: u16, value: u16) {
+        match addr {
+            0 => {
+                if value & TIMER_ENABLE != 0 && self.control & TIMER_ENABLE == 0 {
+                    self.counter = self.reload;
+                }
+                self.control = value;
+            }
+            2 => self.reload = value,
+            4 => self.counter = value,
+            6 => self.status = 0,
+            _ => {}
+        }
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(
+            (0..8)
+                .step_by(2)
+                .flat_map(|x| self.get_u16(x).to_be_bytes())
+                .collect(),
+        )
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 8 {
+            return false;
+        }
+        // straight into the fields, register writes have side effects
+        let word = |i: usize| u16::from_be_bytes([state[i], state[i + 1]]);
+        self.control = word(0);
+        self.reload = word(2);
+        self.counter = word(4);
+        self.status = word(6);
+        true
+    }
+
+    fn undo_state(&self) -> Option<Vec<u8>> {
+        self.save_state()
+    }
+
+    fn undo(&mut self, state: &[u8]) {
+        self.load_state(state);
+    }
+
+    /// Counts the instruction that just ran, or its cycles. Cycles past an
+    /// expiry carry over into the next period.
+    fn tick(&mut self, cycles: u64) {
+        if self.control & TIMER_ENABLE == 0 {
+            return;
+        }
+        let mut left = match self.control & TIMER_CYCLES {
+            0 => 1,
+            _ => cycles,
+        };
+        let mut expired = false;
+        while self.control & TIMER_ENABLE != 0 && left >= self.counter as u64 {
+            left -= self.counter as u64;
+            self.expire();
+            expired = true;
+        }
+        if self.control & TIMER_ENABLE != 0 {
+            self.counter -= left as u16;
+        }
+        if expired && self.control & TIMER_INTERRUPT != 0 {
+            self.line.raise();
+        }
+    }
+}
+
+#[test]
+fn timer_expiry() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x1B, 0x00, 0x0A, 0x31, 0x12, // mov $10, &0x3112
+            0x1B, 0x00, 0x0D, 0x31, 0x10, // mov $0b1101, &0x3110
+            0x13, 0x03, 0x00, 0x04, // loop: mov &0x0300, r3
+            0x10, 0x00, 0x03, 0x01, // mov $3, acc
+            0x40, 0x04, 0x00, 0x0A, // jne r3, &loop
+            0xFF,
+        ],
+    );
+    // line 0 handler counts expiries at 0x0300 and clears the flag
+    memory.load(0x0100, &[0x02, 0x00]);
+    memory.load(
+        0x0200,
+        &[
+            0x13, 0x03, 0x00, 0x02, // mov &0x0300, r1
+            0x35, 0x02, // inc r1
+            0x12, 0x02, 0x03, 0x00, // mov r1, &0x0300
+            0x1B, 0x00, 0x00, 0x31, 0x16, // mov $0, &0x3116
+            0xFC, // rti
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let controller = cpu.interrupt_controller();
+    cpu.memory_mut().map(Region::new(
+        controller,
+        INTERRUPT_START,
+        INTERRUPT_END,
+        true,
+    ));
+    cpu.interrupts().set_vectors(0x0100);
+    let timer = Timer::new(cpu.interrupt_line(TIMER_LINE));
+    cpu.memory_mut()
+        .map(Region::new(timer, TIMER_START, TIMER_END, true));
+
+    cpu.record(100);
+    let counter = |cpu: &Cpu| cpu.memory().peek_u16(TIMER_START + 4);
+    let (mut entered, mut counts) = (Vec::new(), vec![0]);
+    while !cpu.step() {
+        if cpu.register(Register::Ip) == 0x0200 {
+            entered.push(cpu.executed());
+        }
+        counts.push(counter(&cpu));
+    }
+    assert_eq!(cpu.fault(), None);
+    // the instruction enabling it counts first, then every 10th one expires
+    // until the loop sees the third count
+    assert_eq!(entered, [11, 21, 31, 41]);
+    assert_eq!(cpu.memory().get_u16(0x0300), 4);
+    assert_eq!(cpu.memory().get_u16(TIMER_START), 0b1101);
+    assert_eq!(cpu.memory().get_u16(TIMER_START + 6), 0);
+
+    // stepping back winds the count back too
+    while cpu.step_back().is_some() {
+        assert_eq!(counter(&cpu), counts.pop().unwrap());
+    }
+    assert!(counts.is_empty());
+
+    // one-shot on cycles sets the flag and stops
+    let interrupts = Rc::new(Interrupts::default());
+    let mut timer = Timer::new(InterruptLine::new(interrupts.clone(), TIMER_LINE));
+    timer.set_u16(2, 10);
+    timer.set_u16(0, TIMER_ENABLE | TIMER_CYCLES);
+    timer.tick(4);
+    assert_eq!(timer.get_u16(4), 6);
+    timer.tick(7);
+    assert_eq!(timer.get_u16(6), TIMER_EXPIRED);
+    assert_eq!((timer.counter(), timer.get_u16(0)), (0, TIMER_CYCLES));
+    timer.tick(7);
+    assert_eq!(interrupts.pending(), 0);
+    timer.set_u8(7, 0);
+    assert_eq!(timer.get_u16(6), 0);
+
+    // periodic carries cycles past expiry, expiring twice raises once
+    timer.set_u16(2, 5);
+    timer.set_u16(
+        0,
+        TIMER_ENABLE | TIMER_CYCLES | TIMER_PERIODIC | TIMER_INTERRUPT,
+    );
+    timer.tick(12);
+    assert_eq!(timer.counter(), 3);
+    assert_eq!(interrupts.pending(), 1 << TIMER_LINE);
+
+    let state = timer.save_state().unwrap();
+    let mut other = Timer::new(InterruptLine::new(interrupts, TIMER_LINE));
+    assert!(other.load_state(&state));
+    assert_eq!(other.save_state().unwrap(), state);
+    assert!(!other.load_state(&state[..6]));
+}
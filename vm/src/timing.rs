@@ -0,0 +1,273 @@
+use std::{
+    cell::Cell,
+    fmt,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{device::MemoryMappedDevice, instructions::Instructions};
+
+#[cfg(test)]
+use crate::{cpu::Cpu, mapper::Region, memory::Memory, registers::Register};
+
+/// Where emu maps the cycle counter, inside `io` window of `layouts/vm.layout`
+pub const CYCLES_START: u16 = 0x3100;
+pub const CYCLES_END: u16 = 0x3103;
+
+/// Cycles per fetched instruction byte in the default table
+pub const DEFAULT_FETCH: u64 = 1;
+/// Cycles per data word read or written in the default table
+pub const DEFAULT_ACCESS: u64 = 2;
+
+/// Cycles every opcode takes, unknown opcodes cost a one byte fetch
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    cycles: [u64; 256],
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::model(DEFAULT_FETCH, DEFAULT_ACCESS)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CostError {
+    /// Line is not `<name> <cycles>`
+    Syntax(usize),
+    /// Name is neither opcode, mnemonic, `fetch` nor `access`
+    UnknownInstruction(usize, String),
+}
+
+impl fmt::Display for CostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(line) => write!(f, "line {}: expected `<name> <cycles>`", line),
+            Self::UnknownInstruction(line, name) => {
+                write!(f, "line {}: unknown instruction `{}`", line, name)
+            }
+        }
+    }
+}
+
+impl CostTable {
+    /// Every instruction costs `fetch` per encoded byte plus `access` per
+    /// data word, see [`Instructions::memory_accesses`]
+    pub fn model(fetch: u64, access: u64) -> Self {
+        let mut cycles = [fetch; 256];
+        for kind in Instructions::ALL {
+            cycles[kind as usize] =
+                fetch * kind.size() as u64 + access * kind.memory_accesses() as u64;
+        }
+        Self { cycles }
+    }
+
+    pub fn cost(&self, opcode: u8) -> u64 {
+        self.cycles[opcode as usize]
+    }
+
+    pub fn set(&mut self, kind: Instructions, cycles: u64) {
+        self.cycles[kind as usize] = cycles;
+    }
+
+    /// One `<name> <cycles>` per line, `#` starts a comment. `fetch` and
+    /// `access` set up [`Self::model`], then opcodes like `CALL_LIT` or
+    /// mnemonics like `mul` (all of its opcodes) override it in order.
+    pub fn parse(input: &str) -> Result<Self, CostError> {
+        let mut entries = Vec::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                [name, cycles] => match cycles.parse::<u64>() {
+                    Ok(cycles) => entries.push((i + 1, *name, cycles)),
+                    Err(_) => return Err(CostError::Syntax(i + 1)),
+                },
+                _ => return Err(CostError::Syntax(i + 1)),
+            }
+        }
+
+        let setting = |name: &str, default: u64| {
+            entries
+                .iter()
+                .rev()
+                .find(|x| x.1 == name)
+                .map_or(default, |x| x.2)
+        };
+        let mut table = Self::model(
+            setting("fetch", DEFAULT_FETCH),
+            setting("access", DEFAULT_ACCESS),
+        );
+        for (line, name, cycles) in entries {
+            if name == "fetch" || name == "access" {
+                continue;
+            }
+            let kinds: Vec<Instructions> = Instructions::ALL
+                .into_iter()
+                .filter(|x| format!("{:?}", x) == name || x.mnemonic() == name)
+                .collect();
+            if kinds.is_empty() {
+                return Err(CostError::UnknownInstruction(line, name.to_string()));
+            }
+            for kind in kinds {
+                table.set(kind, cycles);
+            }
+        }
+        Ok(table)
+    }
+}
+
+/// Read-only 32-bit big-endian view of the CPU cycle count. Reading the high
+/// word latches the low one, so two word reads see the same count.
+#[derive(Debug, Clone)]
+pub struct CycleCounter {
+    cycles: Rc<Cell<u64>>,
+    latch: Cell<u16>,
+}
+
+impl CycleCounter {
+    pub fn new(cycles: Rc<Cell<u64>>) -> Self {
+        Self {
+            cycles,
+            latch: Cell::new(0),
+        }
+    }
+}
+
+impl MemoryMappedDevice for CycleCounter {
+    fn get_u8(&self, addr: u16) -> u8 {
+        let word = self.get_u16(addr & !1);
+        word.to_be_bytes()[(addr & 1) as usize]
+    }
+
+    fn set_u8(&mut self, _addr: u16, _value: u8) {}
+
+    /// Leaves the latch alone
+    fn peek_u8(&self, addr: u16) -> u8 {
+        let word = match addr & !1 {
+            0 => (self.cycles.get() >> 16) as u16,
+            2 => self.latch.get(),
+            _ => 0,
+        };
+        word.to_be_bytes()[(addr & 1) as usize]
+    }
+
+    fn get_u16(&self, addr: u16) -> u16 {
+        let cycles = self.cycles.get() as u32;
+        match addr {
+            0 => {
+                self.latch.set(cycles as u16);
+                (cycles >> 16) as u16
+            }
+            2 => self.latch.get(),
+            _ => 0,
+        }
+    }
+
+    fn set_u16(&mut self, _addr: u16, _value: u16) {}
+
+    fn undo_state(&self) -> Option<Vec<u8>> {
+        Some(self.latch.get().to_be_bytes().to_vec())
+    }
+
+    fn undo(&mut self, state: &[u8]) {
+        if let [h, l] = state {
+            self.latch.set(u16::from_be_bytes([*h, *l]));
+        }
+    }
+}
+
+/// Keeps emulated cycles from running ahead of wall clock at `hz`
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    hz: u64,
+    start: Instant,
+    cycles: u64,
+}
+
+impl Throttle {
+    // sleeping for less is mostly syscall overhead
+    const SLACK: Duration = Duration::from_millis(1);
+
+    /// Counts from `cycles` the CPU is at now
+    pub fn new(hz: u64, cycles: u64) -> Self {
+        Self {
+            hz,
+            start: Instant::now(),
+            cycles,
+        }
+    }
+
+    /// Sleeps until the CPU at `cycles` is due
+    pub fn wait(&self, cycles: u64) {
+        let due =
+            Duration::from_secs_f64(cycles.saturating_sub(self.cycles) as f64 / self.hz as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed + Self::SLACK {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+#[test]
+fn timing_costs() {
+    let table = CostTable::default();
+    assert_eq!(table.cost(Instructions::INC_REG as u8), 2);
+    assert_eq!(table.cost(Instructions::MOV_REG_MEM as u8), 4 + 2);
+    assert_eq!(table.cost(Instructions::CALL_LIT as u8), 3 + 20);
+    assert_eq!(table.cost(0x00), 1);
+
+    let table = CostTable::parse(
+        "# slow memory
+access 4
+mul 10
+MUL_LIT_REG 12  # literal costs more
+fetch 0",
+    )
+    .unwrap();
+    assert_eq!(table.cost(Instructions::MOV_MEM_REG as u8), 4);
+    assert_eq!(table.cost(Instructions::MUL_REG_REG as u8), 10);
+    assert_eq!(table.cost(Instructions::MUL_LIT_REG as u8), 12);
+    assert_eq!(
+        CostTable::parse("fetch 1\ndiv 3"),
+        Err(CostError::UnknownInstruction(2, "div".into()))
+    );
+    assert_eq!(
+        CostTable::parse("mul").unwrap_err().to_string(),
+        "line 1: expected `<name> <cycles>`"
+    );
+
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x35, 0x02, // inc r1
+            0x13, 0x31, 0x00, 0x03, // mov &0x3100, r2
+            0x13, 0x31, 0x02, 0x04, // mov &0x3102, r3
+            0xFF,
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let counter = cpu.cycle_counter();
+    cpu.memory_mut()
+        .map(Region::new(counter, CYCLES_START, CYCLES_END, true));
+    cpu.run();
+    // counter shows cycles before the reading instruction
+    assert_eq!(cpu.register(Register::R2), 0);
+    assert_eq!(cpu.register(Register::R3), 2);
+    assert_eq!(cpu.cycles(), 2 + 6 + 6 + 1);
+}
+
+#[test]
+fn timing_throttle() {
+    let throttle = Throttle::new(1000, 100);
+    let start = Instant::now();
+    throttle.wait(120);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    // cycles already due do not sleep
+    let start = Instant::now();
+    throttle.wait(100);
+    assert!(start.elapsed() < Duration::from_millis(20));
+}
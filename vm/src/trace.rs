@@ -0,0 +1,242 @@
+use crate::{
+    cpu::Cpu,
+    instructions::{Instructions, Operand},
+    mapper::MemoryWrite,
+    registers::Register,
+};
+
+#[cfg(test)]
+use crate::{device::MemoryMappedDevice, memory::Memory};
+
+/// Starts binary trace file, entries follow back to back
+pub const TRACE_MAGIC: &[u8; 4] = b"16TR";
+
+const OPERAND_REG: u8 = 0;
+const OPERAND_LIT: u8 = 1;
+const OPERAND_ADDR: u8 = 2;
+
+/// One executed instruction and its side effects
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// Instructions executed before this one
+    pub index: u64,
+    pub ip: u16,
+    pub opcode: u8,
+    pub kind: Option<Instructions>,
+    /// Decoded operands, register operands come with their value before execution
+    pub operands: Vec<(Operand, u16)>,
+    /// Registers the instruction changed with new values, `ip` only when it
+    /// did not fall through to the next instruction
+    pub registers: Vec<(Register, u16)>,
+    pub writes: Vec<MemoryWrite>,
+}
+
+impl TraceEntry {
+    /// Executes one instruction on `cpu` and describes it, `true` once halted
+    pub fn step(cpu: &mut Cpu) -> (bool, Self) {
+        let index = cpu.executed();
+        let instruction = cpu.decode(cpu.register(Register::Ip));
+        let operands = instruction
+            .operands
+            .iter()
+            .map(|x| match x {
+                Operand::Reg(reg) => (*x, cpu.register(*reg)),
+                Operand::Lit(value) | Operand::Addr(value) => (*x, *value),
+            })
+            .collect();
+
+        let (halted, record) = cpu.step_recorded();
+        let next = instruction.address.wrapping_add(instruction.size);
+        let registers = Register::ALL
+            .iter()
+            .map(|reg| (*reg, cpu.register(*reg)))
+            .filter(|(reg, value)| record.registers[*reg as usize] != *value)
+            .filter(|(reg, value)| !(*reg == Register::Ip && *value == next))
+            .collect();
+
+        let entry = Self {
+            index,
+            ip: instruction.address,
+            opcode: instruction.opcode,
+            kind: instruction.kind,
+            operands,
+            registers,
+            writes: record.writes,
+        };
+        (halted, entry)
+    }
+
+    /// Appends big-endian encoding: index, ip, opcode, then counted lists of
+    /// operands (tag, register index for registers, value), registers
+    /// (index, value) and writes (addr, old, new)
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.index.to_be_bytes());
+        out.extend(self.ip.to_be_bytes());
+        out.push(self.opcode);
+
+        out.push(self.operands.len() as u8);
+        for (operand, value) in &self.operands {
+            match operand {
+                Operand::Reg(reg) => out.extend([OPERAND_REG, *reg as u8]),
+                Operand::Lit(_) => out.push(OPERAND_LIT),
+                Operand::Addr(_) => out.push(OPERAND_ADDR),
+            }
+            out.extend(value.to_be_bytes());
+        }
+
+        out.push(self.registers.len() as u8);
+        for (reg, value) in &self.registers {
+            out.push(*reg as u8);
+            out.extend(value.to_be_bytes());
+        }
+
+        out.extend((self.writes.len() as u16).to_be_bytes());
+        for write in &self.writes {
+            out.extend(write.addr.to_be_bytes());
+            out.extend([write.old, write.new]);
+        }
+    }
+
+    /// Entry at the start of `bytes` and its encoded size, `None` if malformed
+    pub fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let index = u64::from_be_bytes(reader.take()?);
+        let ip = reader.u16()?;
+        let opcode = reader.u8()?;
+
+        let mut operands = Vec::new();
+        for _ in 0..reader.u8()? {
+            let tag = reader.u8()?;
+            let reg = match tag {
+                OPERAND_REG => Some(Register::from_index(reader.u8()?)),
+                _ => None,
+            };
+            let value = reader.u16()?;
+            let operand = match (tag, reg) {
+                (OPERAND_REG, Some(reg)) => Operand::Reg(reg),
+                (OPERAND_LIT, _) => Operand::Lit(value),
+                (OPERAND_ADDR, _) => Operand::Addr(value),
+                _ => return None,
+            };
+            operands.push((operand, value));
+        }
+
+        let mut registers = Vec::new();
+        for _ in 0..reader.u8()? {
+            registers.push((Register::from_index(reader.u8()?), reader.u16()?));
+        }
+
+        let mut writes = Vec::new();
+        for _ in 0..reader.u16()? {
+            writes.push(MemoryWrite {
+                addr: reader.u16()?,
+                old: reader.u8()?,
+                new: reader.u8()?,
+            });
+        }
+
+        let entry = Self {
+            index,
+            ip,
+            opcode,
+            kind: Instructions::try_from(opcode).ok(),
+            operands,
+            registers,
+            writes,
+        };
+        Some((entry, reader.pos))
+    }
+}
+
+/// Big-endian cursor over encoded bytes, shared with snapshots
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let res = self.bytes.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(res)
+    }
+
+    pub(crate) fn slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        let res = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(res)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take::<1>()?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take()?))
+    }
+}
+
+#[test]
+fn trace_entries() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x10, 0x00, 0x05, 0x02, // mov $5, r1
+            0x12, 0x02, 0x01, 0x00, // mov r1, &0x0100
+            0x40, 0x02, 0x00, 0x00, // jne r1, &0x0000
+            0xFF,
+        ],
+    );
+    let mut cpu = Cpu::new(memory);
+    let mut entries = Vec::new();
+    for _ in 0..3 {
+        let (halted, entry) = TraceEntry::step(&mut cpu);
+        assert!(!halted);
+        entries.push(entry);
+    }
+
+    assert_eq!(entries[0].registers, vec![(Register::R1, 5)]);
+    assert_eq!(
+        entries[1].operands,
+        vec![
+            (Operand::Reg(Register::R1), 5),
+            (Operand::Addr(0x0100), 0x0100)
+        ]
+    );
+    assert_eq!(entries[1].registers, vec![]);
+    assert_eq!(
+        entries[1].writes,
+        vec![
+            MemoryWrite {
+                addr: 0x0100,
+                old: 0,
+                new: 0
+            },
+            MemoryWrite {
+                addr: 0x0101,
+                old: 0,
+                new: 5
+            },
+        ]
+    );
+    // taken jump shows up as ip change
+    assert_eq!(entries[2].index, 2);
+    assert_eq!(entries[2].kind, Some(Instructions::JNE_REG));
+    assert_eq!(entries[2].registers, vec![(Register::Ip, 0)]);
+    assert_eq!(cpu.memory().get_u16(0x0100), 5);
+
+    let mut bytes = Vec::new();
+    for entry in &entries {
+        entry.encode(&mut bytes);
+    }
+    let mut decoded = Vec::new();
+    let mut rest = bytes.as_slice();
+    while let Some((entry, size)) = TraceEntry::decode(rest) {
+        decoded.push(entry);
+        rest = &rest[size..];
+    }
+    assert_eq!(decoded, entries);
+    assert!(rest.is_empty());
+    assert_eq!(TraceEntry::decode(&bytes[..5]), None);
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::{
+    cpu::{Cpu, STACK_START},
+    device::MemoryMappedDevice,
+    registers::Register,
+};
+
+#[cfg(test)]
+use crate::memory::Memory;
+
+/// Bytes `pushState` writes above `fp`: frame size, return ip and r8..r1
+pub const FRAME_BYTES: u16 = 20;
+
+/// One function activation recovered from the stack, innermost first
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Current ip for the innermost frame, return address for the rest
+    pub ip: u16,
+    pub fp: u16,
+    /// r1..r8 as the call out of this frame saved them, `None` for innermost
+    pub registers: Option<[u16; 8]>,
+    /// Arguments in push order, `None` for outermost frame or when the
+    /// argument count word does not fit into the caller frame
+    pub args: Option<Vec<u16>>,
+}
+
+/// Why the `fp` chain could not be followed further
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnwindError {
+    /// Frame at `fp` would run past the end of memory
+    FrameOutOfMemory(u16),
+    /// Saved frame size at `fp` is smaller than `pushState` ever writes
+    FrameTooSmall(u16, u16),
+    /// Caller `fp` computed from the frame at `fp` is above the stack start
+    CallerOutOfStack(u16, u32),
+}
+
+impl fmt::Display for UnwindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FrameOutOfMemory(fp) => {
+                write!(f, "frame at {:#06x} runs past the end of memory", fp)
+            }
+            Self::FrameTooSmall(fp, size) => {
+                write!(
+                    f,
+                    "frame at {:#06x} has saved size {:#06x}, too small",
+                    fp, size
+                )
+            }
+            Self::CallerOutOfStack(fp, caller) => write!(
+                f,
+                "frame at {:#06x} points to caller frame {:#06x} above the stack",
+                fp, caller
+            ),
+        }
+    }
+}
+
+/// Frames that could be recovered, `error` is set when the chain is corrupted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backtrace {
+    pub frames: Vec<Frame>,
+    pub error: Option<UnwindError>,
+}
+
+/// Walks `fp` chain of `pushState` frames from the current one to [`STACK_START`]
+pub fn backtrace(cpu: &Cpu) -> Backtrace {
+    let memory = cpu.memory();
+    let mut frames = vec![Frame {
+        ip: cpu.register(Register::Ip),
+        fp: cpu.register(Register::Fp),
+        registers: None,
+        args: None,
+    }];
+
+    // caller fp only grows, so the walk always ends
+    let error = loop {
+        let fp = frames.last().unwrap().fp;
+        if fp == STACK_START {
+            break None;
+        }
+        if fp as u32 + FRAME_BYTES as u32 > STACK_START as u32 {
+            break Some(UnwindError::FrameOutOfMemory(fp));
+        }
+        let size = memory.peek_u16(fp.wrapping_add(2));
+        if size < FRAME_BYTES {
+            break Some(UnwindError::FrameTooSmall(fp, size));
+        }
+        let caller = fp as u32 + size as u32;
+        if caller > STACK_START as u32 {
+            break Some(UnwindError::CallerOutOfStack(fp, caller));
+        }
+
+        let word = |offset: u16| memory.peek_u16(fp.wrapping_add(offset));
+        let mut registers = [0; 8];
+        for (i, reg) in registers.iter_mut().enumerate() {
+            *reg = word(FRAME_BYTES - 2 * i as u16);
+        }
+        // count sits right above r1, arguments above it were pushed first
+        let count = word(FRAME_BYTES + 2);
+        let args = (FRAME_BYTES as u32 + 2 + 2 * count as u32 <= size as u32).then(|| {
+            (1..=count)
+                .rev()
+                .map(|i| word(FRAME_BYTES + 2 + 2 * i))
+                .collect()
+        });
+
+        frames.last_mut().unwrap().args = args;
+        frames.push(Frame {
+            ip: word(4),
+            fp: caller as u16,
+            registers: Some(registers),
+            args: None,
+        });
+    };
+    Backtrace { frames, error }
+}
+
+#[test]
+fn unwind_frames() {
+    let mut memory = Memory::default();
+    memory.load(
+        0,
+        &[
+            0x10, 0x00, 0x11, 0x02, // mov $0x11, r1
+            0x17, 0x00, 0xAA, // push $0xAA
+            0x17, 0x00, 0xBB, // push $0xBB
+            0x17, 0x00, 0x02, // push $2 (number of arguments)
+            0x5E, 0x00, 0x20, // call &0x20
+            0xFF, // 0x10
+        ],
+    );
+    memory.load(
+        0x20,
+        &[
+            0x10, 0x00, 0x22, 0x03, // mov $0x22, r2
+            0x5E, 0x00, 0x30, // call &0x30 without arguments
+        ],
+    );
+    memory.load(0x30, &[0xFF]);
+    let mut cpu = Cpu::new(memory);
+    for _ in 0..7 {
+        cpu.step();
+    }
+
+    let trace = backtrace(&cpu);
+    assert_eq!(trace.error, None);
+    let frames = trace.frames;
+    assert_eq!(
+        frames.iter().map(|x| x.ip).collect::<Vec<_>>(),
+        vec![0x30, 0x27, 0x10]
+    );
+    assert_eq!(frames[2].fp, STACK_START);
+    // inner call pushed no count, the word above r1 belongs to the caller
+    assert_eq!(frames[0].args, None);
+    assert_eq!(frames[1].args, Some(vec![0xAA, 0xBB]));
+    assert_eq!(frames[1].registers, Some([0x11, 0x22, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(frames[2].registers, Some([0x11, 0, 0, 0, 0, 0, 0, 0]));
+
+    // smashed frame size stops the walk with what was recovered so far
+    let fp = frames[1].fp;
+    cpu.memory_mut().set_u16(fp + 2, 4);
+    let trace = backtrace(&cpu);
+    assert_eq!(trace.frames.len(), 2);
+    assert_eq!(trace.error, Some(UnwindError::FrameTooSmall(fp, 4)));
+
+    cpu.memory_mut().set_u16(fp + 2, 0x4000);
+    let error = backtrace(&cpu).error.unwrap();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "frame at {:#06x} points to caller frame {:#06x} above the stack",
+            fp,
+            fp as u32 + 0x4000
+        )
+    );
+}